@@ -0,0 +1 @@
+alter table accounts drop column last_used_at
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- epoch sec of the last request which used the account
+alter table accounts add column last_used_at bigint;
\ No newline at end of file
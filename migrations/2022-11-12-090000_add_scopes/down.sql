@@ -0,0 +1 @@
+alter table accounts drop column scopes
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- scopes granted to the token on authorization
+alter table accounts add column scopes text[];
\ No newline at end of file
@@ -0,0 +1 @@
+alter table accounts drop column profile_image_url
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- avatar of the account, refreshed whenever its token is validated
+alter table accounts add column profile_image_url text;
\ No newline at end of file
@@ -0,0 +1 @@
+drop table timeline_tweets
\ No newline at end of file
@@ -0,0 +1,10 @@
+-- tweets fetched for each account's home timeline, kept for browsing the history without calling the API
+create table timeline_tweets (
+  twitter_id text not null,
+  tweet_id text not null,
+  -- the tweet object as fetched, before any filter ran
+  body jsonb not null,
+  -- epoch sec of the last time the tweet was fetched
+  fetched_at bigint not null,
+  primary key (twitter_id, tweet_id)
+);
\ No newline at end of file
@@ -0,0 +1 @@
+alter table accounts drop column token_state
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- state of the token as of the last time it was validated: valid or expired. null if it has not been checked yet
+alter table accounts add column token_state text;
\ No newline at end of file
@@ -0,0 +1 @@
+alter table accounts drop column username
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- username of the account, refreshed whenever its token is validated
+alter table accounts add column username text;
\ No newline at end of file
@@ -0,0 +1 @@
+drop table scheduled_tweets
\ No newline at end of file
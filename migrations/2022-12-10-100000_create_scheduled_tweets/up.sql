@@ -0,0 +1,13 @@
+-- tweets posted by the daemon at a later time with v0.tweet.create's scheduled_at
+create table scheduled_tweets (
+  id serial primary key,
+  account_id integer not null references accounts (id) on delete cascade,
+  text text not null,
+  reply_to_tweet_id text,
+  quote_tweet_id text,
+  reply_settings text,
+  -- epoch sec to post the tweet at
+  scheduled_at bigint not null,
+  -- why posting the tweet failed. Failed tweets are not posted again and stay until cancelled
+  error text
+);
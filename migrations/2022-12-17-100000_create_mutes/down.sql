@@ -0,0 +1 @@
+drop table mutes
\ No newline at end of file
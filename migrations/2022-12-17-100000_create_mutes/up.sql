@@ -0,0 +1,6 @@
+-- keywords muted with v0.mutes.add, loaded again when the daemon starts
+create table mutes (
+  keyword text primary key,
+  -- expiry in epoch sec. never expires if null
+  until bigint
+);
@@ -0,0 +1,33 @@
+-- bincho.text: helpers for matching on tweet text.
+local text = {}
+
+-- Returns true if `s` contains any of `words` (plain substring match).
+function text.contains_any(s, words)
+  if s == nil then
+    return false
+  end
+  for _, word in ipairs(words) do
+    if s:find(word, 1, true) then
+      return true
+    end
+  end
+  return false
+end
+
+-- Returns true if `s` matches the Lua pattern `pattern`.
+function text.matches(s, pattern)
+  if s == nil then
+    return false
+  end
+  return s:find(pattern) ~= nil
+end
+
+-- Returns `s` in lower case. Non-ASCII characters are kept as they are.
+function text.lower(s)
+  if s == nil then
+    return nil
+  end
+  return s:lower()
+end
+
+return text
@@ -0,0 +1,36 @@
+-- bincho.time: helpers for timestamps returned by the Twitter API.
+local time = {}
+
+local function days_from_civil(y, m, d)
+  y = m <= 2 and y - 1 or y
+  local era = (y >= 0 and y or y - 399) // 400
+  local yoe = y - era * 400
+  local mp = (m + 9) % 12
+  local doy = (153 * mp + 2) // 5 + d - 1
+  local doe = yoe * 365 + yoe // 4 - yoe // 100 + doy
+  return era * 146097 + doe - 719468
+end
+
+-- Parses an ISO 8601 timestamp in UTC such as `created_at` ("2022-10-27T15:41:35.000Z") into epoch seconds. Returns nil if it cannot be parsed.
+function time.parse_created_at(s)
+  if s == nil then
+    return nil
+  end
+  local y, mo, d, h, mi, sec = s:match("^(%d+)-(%d+)-(%d+)T(%d+):(%d+):(%d+)")
+  if y == nil then
+    return nil
+  end
+  local days = days_from_civil(tonumber(y), tonumber(mo), tonumber(d))
+  return days * 86400 + tonumber(h) * 3600 + tonumber(mi) * 60 + tonumber(sec)
+end
+
+-- Returns the age of the tweet in seconds relative to `now` (epoch seconds).
+function time.age(created_at, now)
+  local t = time.parse_created_at(created_at)
+  if t == nil then
+    return nil
+  end
+  return now - t
+end
+
+return time
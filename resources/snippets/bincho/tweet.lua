@@ -0,0 +1,31 @@
+-- bincho.tweet: helpers for inspecting tweet objects.
+local tweet = {}
+
+local function has_reference(post, kind)
+  if post.referenced_tweets == nil then
+    return false
+  end
+  for _, ref in ipairs(post.referenced_tweets) do
+    if ref.type == kind then
+      return true
+    end
+  end
+  return false
+end
+
+-- Returns true if the post is a retweet.
+function tweet.is_retweet(post)
+  return has_reference(post, "retweeted") or (post.text ~= nil and post.text:sub(1, 4) == "RT @")
+end
+
+-- Returns true if the post is a reply to another tweet.
+function tweet.is_reply(post)
+  return has_reference(post, "replied_to")
+end
+
+-- Returns true if the post quotes another tweet.
+function tweet.is_quote(post)
+  return has_reference(post, "quoted")
+end
+
+return tweet
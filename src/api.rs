@@ -1,20 +1,172 @@
 use crate::methods::HttpMethod;
+use crate::scheduler::{RetryPolicy, Scheduler};
 use crate::tweet::Tweet;
-use anyhow::anyhow;
+use crate::VERSION;
 use reqwest::header::CONTENT_TYPE;
-use reqwest::{Client, Response, StatusCode};
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
 use thiserror::Error;
-use tracing::debug;
+use tracing::{debug, warn};
 
 // TODO: use a crate dedicated for the twitter api?
 
-#[derive(Debug, Serialize, Deserialize)]
+// Used until configure_http is called
+const DEFAULT_HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+// Idle connections to the API kept for reuse
+const POOL_MAX_IDLE: usize = 8;
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+const API_BASE: &str = "https://api.twitter.com/2";
+
+// Shared by every ApiClient so that the connections are pooled
+static HTTP: OnceLock<Client> = OnceLock::new();
+
+/// Sets the timeout of the requests to the API, which covers connecting as well. It must be called before the first request, and only once.
+pub fn configure_http(timeout: Duration) -> Result<(), ApiClientError> {
+    if HTTP.set(build_http(timeout)?).is_err() {
+        warn!("the HTTP client for the API was configured already. ignoring the new timeout");
+    }
+    Ok(())
+}
+
+fn http() -> Client {
+    HTTP.get_or_init(|| {
+        // SAFETY: building only fails if the TLS backend cannot be initialized, in which case no request could be sent anyway
+        build_http(DEFAULT_HTTP_TIMEOUT).expect("could not build the HTTP client")
+    })
+    .clone()
+}
+
+fn build_http(timeout: Duration) -> reqwest::Result<Client> {
+    Client::builder()
+        .user_agent(format!("binchotan/{}", VERSION))
+        .connect_timeout(timeout)
+        .timeout(timeout)
+        .pool_max_idle_per_host(POOL_MAX_IDLE)
+        .pool_idle_timeout(POOL_IDLE_TIMEOUT)
+        .build()
+}
+
+/// Who can reply to a posted tweet. Everyone can if it is not set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ReplySettings {
+    MentionedUsers,
+    Following,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HomeTimelineResponseBody {
+    // The API omits `data` when there are no tweets
+    #[serde(default)]
     pub data: Vec<Tweet>,
     pub includes: Option<serde_json::Value>,
+    // `tweets/:id` answers without `meta`
+    #[serde(default)]
     pub meta: serde_json::Value,
+    // Set when expansions and fields were dropped because the access tier does not allow them
+    #[serde(skip)]
+    pub details_omitted: bool,
+}
+
+impl HomeTimelineResponseBody {
+    /// Returns the token for the next page, or None if this is the last page.
+    pub fn next_token(&self) -> Option<String> {
+        self.meta["next_token"].as_str().map(String::from)
+    }
+
+    /// Attaches the user object in `includes.users` to each tweet as `author`, looked up by `author_id`. Tweets whose author is not included (e.g. deleted or protected accounts) are left as they are.
+    fn resolve_authors(&mut self) {
+        let users = index_includes(&self.includes, "users", "id");
+
+        for tweet in &mut self.data {
+            let author = tweet
+                .field("author_id")
+                .as_str()
+                .and_then(|id| users.get(id))
+                .map(|user| (*user).clone());
+            if let Some(author) = author {
+                tweet.set_field("author", author);
+            }
+        }
+    }
+
+    /// Attaches the media objects in `includes.media` to each tweet as `media`, looked up by `attachments.media_keys`. Every tweet gets `media`, which is empty if it has no attachments.
+    fn resolve_media(&mut self) {
+        let media = index_includes(&self.includes, "media", "media_key");
+
+        for tweet in &mut self.data {
+            let attached: Vec<serde_json::Value> = tweet.field("attachments")["media_keys"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|key| media.get(key.as_str()?))
+                .map(|m| (*m).clone())
+                .collect();
+            tweet.set_field("media", attached.into());
+        }
+    }
+}
+
+/// Indexes the objects in `includes[key]` by their `id_field`.
+fn index_includes<'a>(
+    includes: &'a Option<serde_json::Value>,
+    key: &str,
+    id_field: &str,
+) -> HashMap<&'a str, &'a serde_json::Value> {
+    includes
+        .iter()
+        .flat_map(|includes| includes[key].as_array())
+        .flatten()
+        .filter_map(|object| Some((object[id_field].as_str()?, object)))
+        .collect()
+}
+
+/// Adds comma-separated values to a query parameter such as `expansions`, keeping the values the caller has already given. The caller may give them as a comma-separated string or as an array of strings.
+fn merge_param(params: &mut HashMap<String, serde_json::Value>, key: &str, values: &[&str]) {
+    let current = match params.get(key) {
+        Some(serde_json::Value::String(current)) => current.clone(),
+        Some(serde_json::Value::Array(current)) => current
+            .iter()
+            .filter_map(|v| v.as_str())
+            .collect::<Vec<_>>()
+            .join(","),
+        _ => String::new(),
+    };
+    let mut merged: Vec<&str> = current.split(',').filter(|v| !v.is_empty()).collect();
+    for value in values {
+        if !merged.contains(value) {
+            merged.push(value);
+        }
+    }
+    params.insert(key.to_owned(), merged.join(",").into());
+}
+
+// Maximum number of ids the `users` endpoint accepts at once
+const USER_LOOKUP_LIMIT: usize = 100;
+
+// Query parameters which request details beyond the default tweet fields
+const DETAIL_PARAMS: [&str; 6] = [
+    "expansions",
+    "tweet.fields",
+    "user.fields",
+    "media.fields",
+    "poll.fields",
+    "place.fields",
+];
+
+/// Tells whether a 403 response is caused by the access tier of the app (e.g. the Free tier) rather than by the request itself.
+fn is_access_tier_error(body: &str) -> bool {
+    let problem: serde_json::Value = match serde_json::from_str(body) {
+        Ok(problem) => problem,
+        Err(_) => return false,
+    };
+    problem["reason"] == "client-not-enrolled"
+        || problem["type"] == "https://api.twitter.com/2/problems/client-forbidden"
 }
 
 #[derive(Debug, Error)]
@@ -29,30 +181,91 @@ pub enum ApiClientError {
     RespParamNotFound(String, serde_json::Value),
     #[error("the API has given a non-successful status code ({0}): {1}")]
     RespStatus(u16, String),
+    #[error(
+        "tweet {0} could not be deleted. only tweets posted by the account itself can be deleted"
+    )]
+    NotOwnTweet(String),
+    #[error("tweet {0} was not found. it might have been deleted or be protected")]
+    TweetNotFound(String),
+    #[error("the rate limit of {endpoint} is exhausted until {reset} (epoch sec)")]
+    RateLimited { endpoint: String, reset: usize },
+    #[error("the request to the API timed out: {0}")]
+    Timeout(#[source] reqwest::Error),
+    #[error("could not connect to the API: {0}")]
+    Unreachable(#[source] reqwest::Error),
     #[error(transparent)]
-    Http(#[from] reqwest::Error),
+    Http(reqwest::Error),
+}
+
+impl From<reqwest::Error> for ApiClientError {
+    fn from(err: reqwest::Error) -> Self {
+        if err.is_timeout() {
+            Self::Timeout(err)
+        } else if err.is_connect() {
+            // the name could not be resolved, the connection was refused, or the TLS handshake failed
+            Self::Unreachable(err)
+        } else {
+            Self::Http(err)
+        }
+    }
+}
+
+impl ApiClientError {
+    /// Returns whether the API could not be reached at all, which is worth retrying once the network is back.
+    pub fn is_network(&self) -> bool {
+        matches!(self, Self::Timeout(_) | Self::Unreachable(_))
+    }
 }
 
 pub struct ApiClient {
     client: Client,
     pub user_id: String,
+    // Avatar of the authenticating user, fetched along with the id
+    pub profile_image_url: Option<String>,
+    // Username of the authenticating user, fetched along with the id
+    pub username: Option<String>,
     access_token: String,
+    scheduler: Arc<Scheduler>,
+    // Where the endpoints are, which tests point to a mock server
+    api_base: String,
 }
 
 impl ApiClient {
-    pub async fn new(access_token: String) -> Result<Self, ApiClientError> {
-        let client = Client::new();
-        let user_id = Self::id_for_token(&client, &access_token).await?;
+    pub async fn new(
+        access_token: String,
+        scheduler: Arc<Scheduler>,
+    ) -> Result<Self, ApiClientError> {
+        let client = http();
+        let (user_id, profile_image_url, username) =
+            Self::id_for_token(&client, &access_token).await?;
 
         Ok(Self {
             client,
             user_id,
+            profile_image_url,
+            username,
             access_token,
+            scheduler,
+            api_base: API_BASE.to_owned(),
         })
     }
 
+    /// Builds a client for the user which sends the requests to `api_base` instead of the API.
+    #[cfg(test)]
+    pub fn mock(user_id: &str, api_base: String, scheduler: Arc<Scheduler>) -> Self {
+        Self {
+            client: Client::new(),
+            user_id: user_id.to_owned(),
+            profile_image_url: None,
+            username: None,
+            access_token: "token".to_owned(),
+            scheduler,
+            api_base,
+        }
+    }
+
     pub async fn validate_token(access_token: &str) -> Result<bool, ApiClientError> {
-        let client = Client::new();
+        let client = http();
         match Self::id_for_token(&client, access_token).await {
             Ok(_id) => Ok(true),
             Err(ApiClientError::TokenExpired(_)) => Ok(false),
@@ -60,11 +273,15 @@ impl ApiClient {
         }
     }
 
-    async fn id_for_token(client: &Client, access_token: &str) -> Result<String, ApiClientError> {
+    /// Returns the id, the avatar URL and the username of the user who the token belongs to.
+    async fn id_for_token(
+        client: &Client,
+        access_token: &str,
+    ) -> Result<(String, Option<String>, Option<String>), ApiClientError> {
         let endpoint = "https://api.twitter.com/2/users/me";
-        tracing::warn!("access token: {}", access_token);
         let resp = client
             .get(endpoint)
+            .query(&[("user.fields", "profile_image_url")])
             .bearer_auth(access_token.to_owned())
             .send()
             .await?;
@@ -81,33 +298,363 @@ impl ApiClient {
         let id = user_data["data"]["id"]
             .as_str()
             .map(String::from)
-            .ok_or_else(|| ApiClientError::RespParamNotFound("id".into(), user_data))?;
-        Ok(id)
+            .ok_or_else(|| ApiClientError::RespParamNotFound("id".into(), user_data.clone()))?;
+        let profile_image_url = user_data["data"]["profile_image_url"]
+            .as_str()
+            .map(String::from);
+        let username = user_data["data"]["username"].as_str().map(String::from);
+        Ok((id, profile_image_url, username))
     }
 
-    /// Calls `users/:id/timelines/reverse_chronological` endpoint to fetch the home timeline of the user. Returns the response body, the remaining calls (`x-rate-limit-remaining`), and the end of the current rate-limiting time window in epoch seconds (`x-rate-limit-reset`), in this order.
+    /// Calls `users/:id/timelines/reverse_chronological` endpoint to fetch the home timeline of the user. Authors and media of the tweets are expanded and attached to each tweet as `author` and `media`, and `reply_settings` and `entities` are always requested (`entities.urls` and `entities.mentions` are filled in even if the tweet has none). Returns the response body, the remaining calls (`x-rate-limit-remaining`), and the end of the current rate-limiting time window in epoch seconds (`x-rate-limit-reset`), in this order.
     pub async fn timeline(
         &self,
         params: &mut HashMap<String, serde_json::Value>,
     ) -> Result<(HomeTimelineResponseBody, usize, usize), ApiClientError> {
-        let endpoint = format!(
-            "https://api.twitter.com/2/users/{}/timelines/reverse_chronological",
-            self.user_id
-        );
+        let endpoint = "users/:id/timelines/reverse_chronological";
+        self.tweets(endpoint, endpoint, params).await
+    }
 
-        let resp = self
-            .client
-            .get(endpoint)
-            .query(params)
-            .bearer_auth(self.access_token.to_owned())
-            .header(CONTENT_TYPE, "application/json")
-            .send()
+    /// Calls `tweets/:id/quote_tweets` endpoint to fetch tweets quoting the tweet. Authors are expanded in the same way as `timeline`.
+    pub async fn quote_tweets(
+        &self,
+        tweet_id: &str,
+        params: &mut HashMap<String, serde_json::Value>,
+    ) -> Result<(HomeTimelineResponseBody, usize, usize), ApiClientError> {
+        let path = format!("tweets/{}/quote_tweets", tweet_id);
+        self.tweets("tweets/:tweet_id/quote_tweets", &path, params)
+            .await
+    }
+
+    /// Calls `tweets/:id` endpoint to fetch a single tweet, expanded in the same way as `timeline`. Fails with `TweetNotFound` if the tweet does not exist (any more) or cannot be seen by the user.
+    pub async fn show_tweet(
+        &self,
+        tweet_id: &str,
+    ) -> Result<(Tweet, usize, usize), ApiClientError> {
+        let path = format!("tweets/{}", tweet_id);
+        let not_found = || ApiClientError::TweetNotFound(tweet_id.to_owned());
+        let (mut body, remaining, reset) = match self
+            .tweets("tweets/:tweet_id", &path, &mut HashMap::new())
+            .await
+        {
+            Err(ApiClientError::RespStatus(404, _)) => return Err(not_found()),
+            other => other?,
+        };
+        // a deleted tweet is answered with 200 and only `errors`
+        let tweet = body.data.pop().ok_or_else(not_found)?;
+        Ok((tweet, remaining, reset))
+    }
+
+    /// Calls `users/:id/liked_tweets` endpoint to fetch tweets the user has liked, most recent first. Requires the `like.read` scope. Authors are expanded in the same way as `timeline`.
+    pub async fn liked_tweets(
+        &self,
+        params: &mut HashMap<String, serde_json::Value>,
+    ) -> Result<(HomeTimelineResponseBody, usize, usize), ApiClientError> {
+        let endpoint = "users/:id/liked_tweets";
+        self.tweets(endpoint, endpoint, params).await
+    }
+
+    /// Calls `users/:id/mentions` endpoint to fetch tweets mentioning the user, most recent first. Authors are expanded in the same way as `timeline`.
+    pub async fn mentions(
+        &self,
+        params: &mut HashMap<String, serde_json::Value>,
+    ) -> Result<(HomeTimelineResponseBody, usize, usize), ApiClientError> {
+        let endpoint = "users/:id/mentions";
+        self.tweets(endpoint, endpoint, params).await
+    }
+
+    /// Calls `tweets/search/recent` endpoint to search tweets posted in the last 7 days. `query` is given in the params. Authors are expanded in the same way as `timeline`.
+    pub async fn search_recent(
+        &self,
+        params: &mut HashMap<String, serde_json::Value>,
+    ) -> Result<(HomeTimelineResponseBody, usize, usize), ApiClientError> {
+        let endpoint = "tweets/search/recent";
+        self.tweets(endpoint, endpoint, params).await
+    }
+
+    /// Posts a tweet, optionally as a reply or a quote, and limits who can reply to it if `reply_settings` is given. Requires the `tweet.write` scope. Returns the id and the text of the created tweet.
+    pub async fn create_tweet(
+        &self,
+        text: &str,
+        reply_to_tweet_id: Option<&str>,
+        quote_tweet_id: Option<&str>,
+        reply_settings: Option<ReplySettings>,
+    ) -> Result<((String, String), usize, usize), ApiClientError> {
+        let mut body = serde_json::json!({ "text": text });
+        if let Some(id) = reply_to_tweet_id {
+            body["reply"] = serde_json::json!({ "in_reply_to_tweet_id": id });
+        }
+        if let Some(id) = quote_tweet_id {
+            body["quote_tweet_id"] = id.into();
+        }
+        if let Some(settings) = reply_settings {
+            body["reply_settings"] = serde_json::json!(settings);
+        }
+
+        let (content, remaining, reset) = self
+            .send(&HttpMethod::Post, "tweets", "tweets", body.to_string())
             .await?;
+        let data = &content["data"];
+        match (data["id"].as_str(), data["text"].as_str()) {
+            (Some(id), Some(text)) => Ok(((id.to_owned(), text.to_owned()), remaining, reset)),
+            _ => Err(ApiClientError::RespParamNotFound("data".into(), content)),
+        }
+    }
 
-        let remaining = Self::get_header(&resp, "x-rate-limit-remaining")
-            .map_err(ApiClientError::RespHeader)?;
-        let reset =
-            Self::get_header(&resp, "x-rate-limit-reset").map_err(ApiClientError::RespHeader)?;
+    /// Deletes a tweet posted by the authenticating user. Requires the `tweet.write` scope. A tweet which no longer exists counts as deleted, so that deleting twice is not an error. Returns whether the tweet is deleted.
+    pub async fn delete_tweet(
+        &self,
+        tweet_id: &str,
+    ) -> Result<(bool, usize, usize), ApiClientError> {
+        let path = format!("tweets/{}", tweet_id);
+        let (status, json, remaining, reset) = self
+            .send_unchecked(
+                &HttpMethod::Delete,
+                "DELETE tweets/:tweet_id",
+                &path,
+                String::new(),
+            )
+            .await?;
+        let content: serde_json::Value = match status {
+            x if x.is_success() => {
+                serde_json::from_str(&json).map_err(ApiClientError::RespParse)?
+            }
+            StatusCode::NOT_FOUND => return Ok((true, remaining, reset)),
+            StatusCode::FORBIDDEN => return Err(ApiClientError::NotOwnTweet(tweet_id.to_owned())),
+            x => return Err(ApiClientError::RespStatus(x.as_u16(), json)),
+        };
+
+        let deleted = content["data"]["deleted"]
+            .as_bool()
+            .ok_or_else(|| ApiClientError::RespParamNotFound("deleted".into(), content))?;
+        Ok((deleted, remaining, reset))
+    }
+
+    /// Calls `users/:id/list_memberships` endpoint to fetch the lists the target user is a member of. Returns the response body as it is.
+    pub async fn list_memberships(
+        &self,
+        target_user_id: &str,
+        params: &HashMap<String, serde_json::Value>,
+    ) -> Result<(serde_json::Value, usize, usize), ApiClientError> {
+        let path = format!("users/{}/list_memberships", target_user_id);
+        self.get("users/:target/list_memberships", &path, params)
+            .await
+    }
+
+    /// Adds the user to (or removes the user from) the list, which the authenticating user owns. Returns whether the user is a member of the list afterwards.
+    pub async fn set_list_member(
+        &self,
+        list_id: &str,
+        member_id: &str,
+        is_member: bool,
+    ) -> Result<(bool, usize, usize), ApiClientError> {
+        let (content, remaining, reset) = if is_member {
+            let body = serde_json::json!({ "user_id": member_id }).to_string();
+            let path = format!("lists/{}/members", list_id);
+            self.send(&HttpMethod::Post, "lists/:list_id/members", &path, body)
+                .await?
+        } else {
+            let path = format!("lists/{}/members/{}", list_id, member_id);
+            let key = "lists/:list_id/members/:user_id";
+            self.send(&HttpMethod::Delete, key, &path, String::new())
+                .await?
+        };
+
+        let is_member = content["data"]["is_member"]
+            .as_bool()
+            .ok_or_else(|| ApiClientError::RespParamNotFound("is_member".into(), content))?;
+        Ok((is_member, remaining, reset))
+    }
+
+    /// Likes (or unlikes) the tweet as the authenticating user. Requires the `like.write` scope. Liking a tweet liked already succeeds as well. Returns whether the user likes the tweet afterwards.
+    pub async fn set_like(
+        &self,
+        tweet_id: &str,
+        like: bool,
+    ) -> Result<(bool, usize, usize), ApiClientError> {
+        let (content, remaining, reset) = if like {
+            let body = serde_json::json!({ "tweet_id": tweet_id }).to_string();
+            self.call(&HttpMethod::Post, "users/:id/likes", body)
+                .await?
+        } else {
+            let path = format!("users/:id/likes/{}", tweet_id);
+            let key = "users/:id/likes/:tweet_id";
+            self.send(&HttpMethod::Delete, key, &path, String::new())
+                .await?
+        };
+
+        let liked = content["data"]["liked"]
+            .as_bool()
+            .ok_or_else(|| ApiClientError::RespParamNotFound("liked".into(), content))?;
+        Ok((liked, remaining, reset))
+    }
+
+    /// Retweets (or undoes the retweet of) the tweet as the authenticating user. Requires the `tweet.write` scope. Retweeting a tweet retweeted already succeeds as well. Returns whether the user retweets the tweet afterwards.
+    pub async fn set_retweet(
+        &self,
+        tweet_id: &str,
+        retweet: bool,
+    ) -> Result<(bool, usize, usize), ApiClientError> {
+        let (content, remaining, reset) = if retweet {
+            let body = serde_json::json!({ "tweet_id": tweet_id }).to_string();
+            self.call(&HttpMethod::Post, "users/:id/retweets", body)
+                .await?
+        } else {
+            let path = format!("users/:id/retweets/{}", tweet_id);
+            let key = "users/:id/retweets/:tweet_id";
+            self.send(&HttpMethod::Delete, key, &path, String::new())
+                .await?
+        };
+
+        let retweeted = content["data"]["retweeted"]
+            .as_bool()
+            .ok_or_else(|| ApiClientError::RespParamNotFound("retweeted".into(), content))?;
+        Ok((retweeted, remaining, reset))
+    }
+
+    /// Calls `users` endpoint to look up the users by their ids, splitting them into requests of 100 ids each. Ids which could not be resolved (e.g. suspended or deleted users) are collected from the `errors` of the responses instead of failing the lookup. Returns the users and the errors, along with the rate limit of the last request.
+    pub async fn lookup_users(
+        &self,
+        ids: &[String],
+    ) -> Result<
+        (
+            (Vec<serde_json::Value>, Vec<serde_json::Value>),
+            usize,
+            usize,
+        ),
+        ApiClientError,
+    > {
+        let mut users = vec![];
+        let mut errors = vec![];
+        let (mut remaining, mut reset) = (0, 0);
+        for chunk in ids.chunks(USER_LOOKUP_LIMIT) {
+            let params = HashMap::from([
+                ("ids".to_owned(), chunk.join(",").into()),
+                (
+                    "user.fields".to_owned(),
+                    "username,name,profile_image_url".into(),
+                ),
+            ]);
+            let (content, chunk_remaining, chunk_reset) =
+                self.get("users", "users", &params).await?;
+            for (key, collected) in [("data", &mut users), ("errors", &mut errors)] {
+                if let Some(values) = content[key].as_array() {
+                    collected.extend(values.iter().cloned());
+                }
+            }
+            remaining = chunk_remaining;
+            reset = chunk_reset;
+        }
+
+        Ok(((users, errors), remaining, reset))
+    }
+
+    // Fetches a list of tweets with their authors attached. If the access tier forbids the details, retries without them.
+    async fn tweets(
+        &self,
+        endpoint_key: &str,
+        path: &str,
+        params: &mut HashMap<String, serde_json::Value>,
+    ) -> Result<(HomeTimelineResponseBody, usize, usize), ApiClientError> {
+        merge_param(
+            params,
+            "expansions",
+            &["author_id", "attachments.media_keys"],
+        );
+        merge_param(
+            params,
+            "user.fields",
+            &["username", "name", "profile_image_url", "verified"],
+        );
+        merge_param(
+            params,
+            "media.fields",
+            &["url", "preview_image_url", "type"],
+        );
+        // lets the frontend tell who can reply to each tweet, and render links and mentions
+        merge_param(params, "tweet.fields", &["reply_settings", "entities"]);
+
+        let mut details_omitted = false;
+        let (mut content, remaining, reset) = match self.get(endpoint_key, path, params).await {
+            Err(ApiClientError::RespStatus(403, body)) if is_access_tier_error(&body) => {
+                warn!(
+                    "the access tier does not allow details for {}. retrying without them: {}",
+                    endpoint_key, body
+                );
+                params.retain(|key, _| !DETAIL_PARAMS.contains(&key.as_str()));
+                details_omitted = true;
+                self.get(endpoint_key, path, params).await?
+            }
+            other => other?,
+        };
+        // `tweets/:id` answers a single tweet rather than an array
+        if content["data"].is_object() {
+            content["data"] = serde_json::Value::Array(vec![content["data"].take()]);
+        }
+        let mut body: HomeTimelineResponseBody =
+            serde_json::value::from_value(content).map_err(ApiClientError::RespParse)?;
+        body.resolve_authors();
+        body.resolve_media();
+        for tweet in &mut body.data {
+            tweet.fill_entities();
+        }
+        body.details_omitted = details_omitted;
+        Ok((body, remaining, reset))
+    }
+
+    /// Calls `users/:id` endpoint with the `pinned_tweet_id` expansion to fetch the tweet pinned by the target user. The user is attached to the tweet as `author`. Returns None if the user has not pinned any tweet.
+    pub async fn pinned_tweet(
+        &self,
+        target_user_id: &str,
+    ) -> Result<(Option<Tweet>, usize, usize), ApiClientError> {
+        let params = HashMap::from([
+            ("expansions".to_owned(), "pinned_tweet_id".into()),
+            (
+                "user.fields".to_owned(),
+                "username,name,profile_image_url,verified".into(),
+            ),
+            (
+                "tweet.fields".to_owned(),
+                "created_at,author_id,public_metrics,entities,reply_settings".into(),
+            ),
+        ]);
+        let path = format!("users/{}", target_user_id);
+        let (content, remaining, reset) = self.get("users/:target", &path, &params).await?;
+
+        let tweet = content["includes"]["tweets"]
+            .as_array()
+            .and_then(|tweets| tweets.first())
+            .cloned()
+            .map(|t| serde_json::value::from_value::<Tweet>(t).map_err(ApiClientError::RespParse))
+            .transpose()?
+            .map(|mut tweet| {
+                tweet.set_field("author", content["data"].clone());
+                tweet.fill_entities();
+                tweet
+            });
+        Ok((tweet, remaining, reset))
+    }
+
+    /// Sends a GET request to `{api_base}/{path}` with the query parameters. `:id` in the path is replaced with the id of the authenticating user. The rate limit is tracked under `endpoint_key`, since it is shared among every path of the endpoint. Returns the response body, the remaining calls, and the reset time of the rate limit.
+    async fn get(
+        &self,
+        endpoint_key: &str,
+        path: &str,
+        params: &HashMap<String, serde_json::Value>,
+    ) -> Result<(serde_json::Value, usize, usize), ApiClientError> {
+        let path = path.replace(":id", &self.user_id);
+        let endpoint = format!("{}/{}", self.api_base, path);
+        let acquire = || self.acquire(endpoint_key);
+        let resp = send_with_retry(self.scheduler.retry_policy(), acquire, || {
+            self.client
+                .get(&endpoint)
+                .query(params)
+                .bearer_auth(self.access_token.to_owned())
+                .header(CONTENT_TYPE, "application/json")
+        })
+        .await?;
+
+        let (remaining, reset) = self.rate_limit(&resp, endpoint_key)?;
 
         let status = resp.status();
         let json = resp.text().await?;
@@ -116,9 +663,7 @@ impl ApiClient {
                 let content: serde_json::Value =
                     serde_json::from_str(&json).map_err(ApiClientError::RespParse)?;
                 debug!("{:?}", content);
-                let body: HomeTimelineResponseBody =
-                    serde_json::value::from_value(content).map_err(ApiClientError::RespParse)?;
-                Ok((body, remaining, reset))
+                Ok((content, remaining, reset))
             }
             x => Err(ApiClientError::RespStatus(x.as_u16(), json)),
         }
@@ -131,24 +676,20 @@ impl ApiClient {
         endpoint_path: &str,
         body: String,
     ) -> Result<(serde_json::Value, usize, usize), ApiClientError> {
-        let path = endpoint_path.replace(":id", &self.user_id);
-        let endpoint = format!("https://api.twitter.com/2/{}", path);
-        let resp = self
-            .client
-            .request(reqwest::Method::from(*method), endpoint)
-            .body(body)
-            .bearer_auth(self.access_token.to_owned())
-            .header(CONTENT_TYPE, "application/json")
-            .send()
-            .await?;
-        let status = resp.status();
-
-        let remaining = Self::get_header(&resp, "x-rate-limit-remaining")
-            .map_err(ApiClientError::RespHeader)?;
-        let reset =
-            Self::get_header(&resp, "x-rate-limit-reset").map_err(ApiClientError::RespHeader)?;
-        let json = resp.text().await?;
+        self.send(method, endpoint_path, endpoint_path, body).await
+    }
 
+    /// Sends a request with the body to `{api_base}/{path}`. `:id` in the path is replaced with the id of the authenticating user. The rate limit is tracked under `endpoint_key` (e.g. `tweets/:tweet_id`), so that requests to the same endpoint share it whatever ids are in the path.
+    async fn send(
+        &self,
+        method: &HttpMethod,
+        endpoint_key: &str,
+        path: &str,
+        body: String,
+    ) -> Result<(serde_json::Value, usize, usize), ApiClientError> {
+        let (status, json, remaining, reset) = self
+            .send_unchecked(method, endpoint_key, path, body)
+            .await?;
         match status {
             x if x.is_success() => {
                 let val: serde_json::Value =
@@ -160,10 +701,597 @@ impl ApiClient {
         }
     }
 
-    fn get_header(resp: &Response, key: &str) -> Result<usize, anyhow::Error> {
-        let value = resp.headers().get(key).ok_or(anyhow!("no header"))?;
-        let st = value.to_str()?;
-        let num = st.parse::<usize>()?;
-        Ok(num)
+    /// Same as `send`, but returns the status and the raw body whatever the status is, so that the caller can read the rate limit of a failed request too.
+    async fn send_unchecked(
+        &self,
+        method: &HttpMethod,
+        endpoint_key: &str,
+        path: &str,
+        body: String,
+    ) -> Result<(StatusCode, String, usize, usize), ApiClientError> {
+        let path = path.replace(":id", &self.user_id);
+        let endpoint = format!("{}/{}", self.api_base, path);
+        let acquire = || self.acquire(endpoint_key);
+        let resp = send_with_retry(self.scheduler.retry_policy(), acquire, || {
+            self.client
+                .request(reqwest::Method::from(*method), &endpoint)
+                .body(body.clone())
+                .bearer_auth(self.access_token.to_owned())
+                .header(CONTENT_TYPE, "application/json")
+        })
+        .await?;
+        let status = resp.status();
+
+        let (remaining, reset) = self.rate_limit(&resp, endpoint_key)?;
+        let json = resp.text().await?;
+
+        Ok((status, json, remaining, reset))
+    }
+
+    /// Waits for the turn of a request to the endpoint, or fails if its rate limit is exhausted and the scheduler does not wait for the reset.
+    async fn acquire(&self, endpoint: &str) -> Result<(), ApiClientError> {
+        self.scheduler
+            .acquire(&self.user_id, endpoint)
+            .await
+            .map_err(|limit| ApiClientError::RateLimited {
+                endpoint: limit.endpoint,
+                reset: limit.reset,
+            })
+    }
+
+    /// Reads the rate limit (remaining calls and reset time) from the response headers and tells it to the scheduler. Some endpoints do not send the headers, in which case both are 0.
+    fn rate_limit(
+        &self,
+        resp: &Response,
+        endpoint: &str,
+    ) -> Result<(usize, usize), ApiClientError> {
+        let remaining =
+            Self::get_header(resp, "x-rate-limit-remaining").map_err(ApiClientError::RespHeader)?;
+        let reset =
+            Self::get_header(resp, "x-rate-limit-reset").map_err(ApiClientError::RespHeader)?;
+        match (remaining, reset) {
+            (Some(remaining), Some(reset)) => {
+                self.scheduler
+                    .observe(&self.user_id, endpoint, remaining, reset);
+                Ok((remaining, reset))
+            }
+            _ => {
+                debug!("{} did not send rate limit headers", endpoint);
+                Ok((0, 0))
+            }
+        }
+    }
+
+    // Returns None if the header is absent
+    fn get_header(resp: &Response, key: &str) -> Result<Option<usize>, anyhow::Error> {
+        match resp.headers().get(key) {
+            Some(value) => Ok(Some(value.to_str()?.parse::<usize>()?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Endpoints (as the keys their rate limits are tracked under) which the methods of ApiClient call.
+pub const TRACKED_ENDPOINTS: &[&str] = &[
+    "users/:id/timelines/reverse_chronological",
+    "users/:id/liked_tweets",
+    "users/:id/mentions",
+    "users/:id/likes",
+    "users/:id/likes/:tweet_id",
+    "users/:id/retweets",
+    "users/:id/retweets/:tweet_id",
+    "users/:target",
+    "users/:target/list_memberships",
+    "lists/:list_id/members",
+    "lists/:list_id/members/:user_id",
+    "users",
+    "tweets",
+    "tweets/:tweet_id",
+    // deleting has a rate limit of its own, apart from fetching a tweet
+    "DELETE tweets/:tweet_id",
+    "tweets/:tweet_id/quote_tweets",
+    "tweets/search/recent",
+];
+
+/// Sends the request built by `build`, and sends it again while the API answers 429 (Too Many Requests) as far as the policy allows. Once the retries or the total wait run out, the last response is returned as it is. `acquire` runs before every attempt, so that retries also wait for their turn in the scheduler.
+async fn send_with_retry<F: Future<Output = Result<(), ApiClientError>>>(
+    policy: RetryPolicy,
+    acquire: impl Fn() -> F,
+    build: impl Fn() -> RequestBuilder,
+) -> Result<Response, ApiClientError> {
+    let mut waited = Duration::ZERO;
+    let mut attempt = 0;
+    loop {
+        acquire().await?;
+        let resp = build().send().await?;
+        if resp.status() != StatusCode::TOO_MANY_REQUESTS || attempt >= policy.max_retries {
+            return Ok(resp);
+        }
+
+        let reset = ApiClient::get_header(&resp, "x-rate-limit-reset")
+            .ok()
+            .flatten();
+        let delay = policy.delay(attempt, reset);
+        if waited + delay > policy.max_wait {
+            warn!(
+                "rate limited by {}, but waiting {:?} more would exceed the limit of {:?}. giving up",
+                resp.url(),
+                delay,
+                policy.max_wait
+            );
+            return Ok(resp);
+        }
+
+        attempt += 1;
+        warn!(
+            "rate limited by {}. retrying in {:?} ({}/{})",
+            resp.url(),
+            delay,
+            attempt,
+            policy.max_retries
+        );
+        tokio::time::sleep(delay).await;
+        waited += delay;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_keeps_given_values() {
+        let mut params = HashMap::from([(
+            "expansions".to_owned(),
+            serde_json::Value::from("referenced_tweets.id,author_id"),
+        )]);
+        merge_param(
+            &mut params,
+            "expansions",
+            &["author_id", "attachments.media_keys"],
+        );
+        merge_param(&mut params, "user.fields", &["username"]);
+
+        assert_eq!(
+            params["expansions"],
+            "referenced_tweets.id,author_id,attachments.media_keys"
+        );
+        assert_eq!(params["user.fields"], "username");
+
+        params.insert(
+            "tweet.fields".to_owned(),
+            serde_json::json!(["created_at", "public_metrics", "lang"]),
+        );
+        merge_param(&mut params, "tweet.fields", &["reply_settings", "entities"]);
+        assert_eq!(
+            params["tweet.fields"],
+            "created_at,public_metrics,lang,reply_settings,entities"
+        );
+    }
+
+    #[test]
+    fn keep_requested_fields() -> Result<(), Box<dyn std::error::Error>> {
+        let body: HomeTimelineResponseBody = serde_json::from_str(
+            r#"{
+                "data": [{
+                    "id": "1",
+                    "text": "hi",
+                    "lang": "en",
+                    "created_at": "2022-12-01T00:00:00.000Z",
+                    "public_metrics": { "like_count": 3, "retweet_count": 1 }
+                }],
+                "meta": {}
+            }"#,
+        )?;
+        let tweet = serde_json::to_value(&body.data[0])?;
+        assert_eq!(tweet["lang"], "en");
+        assert_eq!(tweet["created_at"], "2022-12-01T00:00:00.000Z");
+        assert_eq!(tweet["public_metrics"]["like_count"], 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_authors_from_includes() -> Result<(), Box<dyn std::error::Error>> {
+        let mut body: HomeTimelineResponseBody = serde_json::from_str(
+            r#"{
+                "data": [
+                    { "id": "1", "text": "hi", "author_id": "10" },
+                    { "id": "2", "text": "hidden", "author_id": "20" }
+                ],
+                "includes": { "users": [ { "id": "10", "username": "alice", "name": "Alice" } ] },
+                "meta": {}
+            }"#,
+        )?;
+        body.resolve_authors();
+
+        assert_eq!(body.data[0].field("author")["username"], "alice");
+        assert!(body.data[1].field("author").is_null());
+
+        Ok(())
+    }
+
+    #[test]
+    fn empty_timeline() -> Result<(), Box<dyn std::error::Error>> {
+        // what the API returns for a since_id with no newer tweets
+        let body: HomeTimelineResponseBody =
+            serde_json::from_str(r#"{ "meta": { "result_count": 0 } }"#)?;
+        assert!(body.data.is_empty());
+        assert_eq!(body.next_token(), None);
+        assert_eq!(serde_json::to_value(&body)?["data"], serde_json::json!([]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_media_from_includes() -> Result<(), Box<dyn std::error::Error>> {
+        let mut body: HomeTimelineResponseBody = serde_json::from_str(
+            r#"{
+                "data": [
+                    { "id": "1", "text": "look", "attachments": { "media_keys": ["3_1", "7_2"] } },
+                    { "id": "2", "text": "plain" }
+                ],
+                "includes": { "media": [
+                    { "media_key": "3_1", "type": "photo", "url": "https://pbs.twimg.com/media/a.jpg" },
+                    { "media_key": "7_2", "type": "video", "preview_image_url": "https://pbs.twimg.com/b.jpg" }
+                ] },
+                "meta": {}
+            }"#,
+        )?;
+        body.resolve_media();
+
+        assert_eq!(body.data[0].field("media")[0]["type"], "photo");
+        assert_eq!(body.data[0].field("media")[1]["type"], "video");
+        assert_eq!(body.data[1].field("media"), &serde_json::json!([]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn detect_access_tier_errors() {
+        let forbidden = r#"{
+            "client_id": "123",
+            "detail": "When authenticating requests to the Twitter API v2 endpoints, you must use keys and tokens from a Twitter developer App that is attached to a Project.",
+            "reason": "client-not-enrolled",
+            "title": "Client Forbidden",
+            "type": "https://api.twitter.com/2/problems/client-forbidden"
+        }"#;
+        assert!(is_access_tier_error(forbidden));
+
+        let other = r#"{ "title": "Forbidden", "type": "about:blank", "status": 403 }"#;
+        assert!(!is_access_tier_error(other));
+        assert!(!is_access_tier_error("not json"));
+    }
+
+    // Serves the statuses in order, one per request, and returns the address
+    fn mock_server(statuses: Vec<u16>) -> std::net::SocketAddr {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr();
+        std::thread::spawn(move || {
+            for status in statuses {
+                let req = server.recv().unwrap();
+                let resp = tiny_http::Response::from_string(format!("{{\"status\":{}}}", status))
+                    .with_status_code(status);
+                req.respond(resp).unwrap();
+            }
+        });
+        addr
+    }
+
+    // Answers the (status, body) pairs in order, one per request, with rate limit headers. Sends the method, the URL and the body of each request to the receiver.
+    fn mock_api(
+        responses: Vec<(u16, &'static str)>,
+    ) -> (String, std::sync::mpsc::Receiver<(String, String, String)>) {
+        mock_api_with_limit(responses, 41, 1700000000)
+    }
+
+    // Like mock_api, with the given rate limit in every response
+    fn mock_api_with_limit(
+        responses: Vec<(u16, &'static str)>,
+        remaining: usize,
+        reset: u64,
+    ) -> (String, std::sync::mpsc::Receiver<(String, String, String)>) {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr();
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            for (status, body) in responses {
+                let mut req = server.recv().unwrap();
+                let mut content = String::new();
+                req.as_reader().read_to_string(&mut content).unwrap();
+                let _ = tx.send((req.method().to_string(), req.url().to_owned(), content));
+                let header = |key: &str, value: &str| {
+                    tiny_http::Header::from_bytes(key.as_bytes(), value.as_bytes()).unwrap()
+                };
+                let resp = tiny_http::Response::from_string(body)
+                    .with_status_code(status)
+                    .with_header(header("x-rate-limit-remaining", &remaining.to_string()))
+                    .with_header(header("x-rate-limit-reset", &reset.to_string()));
+                req.respond(resp).unwrap();
+            }
+        });
+        (format!("http://{}", addr), rx)
+    }
+
+    #[tokio::test]
+    async fn show_a_single_tweet() -> Result<(), Box<dyn std::error::Error>> {
+        let (base, requests) = mock_api(vec![
+            (
+                200,
+                r#"{
+                    "data": { "id": "20", "text": "hello", "author_id": "7" },
+                    "includes": { "users": [{ "id": "7", "username": "jack" }] }
+                }"#,
+            ),
+            (
+                200,
+                r#"{ "errors": [{ "resource_id": "21", "type": "https://api.twitter.com/2/problems/resource-not-found" }] }"#,
+            ),
+            (404, r#"{ "title": "Not Found Error" }"#),
+        ]);
+        let client = ApiClient::mock("1", base, Arc::new(Scheduler::new(None)));
+
+        let (tweet, remaining, _) = client.show_tweet("20").await?;
+        assert_eq!(tweet.field("author")["username"], "jack");
+        assert_eq!(tweet.field("entities")["urls"], serde_json::json!([]));
+        assert_eq!(remaining, 41);
+        let (_, url, _) = requests.recv()?;
+        assert!(url.starts_with("/tweets/20?"));
+        assert!(url.contains("expansions=author_id"));
+
+        for id in ["21", "22"] {
+            assert!(matches!(
+                client.show_tweet(id).await,
+                Err(ApiClientError::TweetNotFound(missing)) if missing == id
+            ));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn report_the_rate_limit_of_a_missing_tweet() -> Result<(), Box<dyn std::error::Error>> {
+        let (base, _requests) = mock_api(vec![(404, r#"{ "title": "Not Found Error" }"#)]);
+        let client = ApiClient::mock("1", base, Arc::new(Scheduler::new(None)));
+
+        let (deleted, remaining, reset) = client.delete_tweet("20").await?;
+        assert!(deleted);
+        assert_eq!((remaining, reset), (41, 1700000000));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fetch_mentions() -> Result<(), Box<dyn std::error::Error>> {
+        let (base, requests) = mock_api(vec![(
+            200,
+            r#"{
+                "data": [{ "id": "40", "text": "@me hi", "author_id": "7" }],
+                "includes": { "users": [{ "id": "7", "username": "jack" }] },
+                "meta": { "result_count": 1, "next_token": "page2" }
+            }"#,
+        )]);
+        let client = ApiClient::mock("1", base, Arc::new(Scheduler::new(None)));
+
+        let mut params = HashMap::new();
+        params.insert("max_results".to_owned(), 5.into());
+        let (body, remaining, _) = client.mentions(&mut params).await?;
+        assert_eq!(body.data.len(), 1);
+        assert_eq!(body.data[0].field("author")["username"], "jack");
+        assert_eq!(body.next_token(), Some("page2".to_owned()));
+        assert_eq!(remaining, 41);
+        let (_, url, _) = requests.recv()?;
+        assert!(url.starts_with("/users/1/mentions?"));
+        assert!(url.contains("max_results=5"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn post_with_reply_settings() -> Result<(), Box<dyn std::error::Error>> {
+        let created = r#"{ "data": { "id": "30", "text": "hello" } }"#;
+        let (base, requests) = mock_api(vec![(201, created), (201, created)]);
+        let client = ApiClient::mock("1", base, Arc::new(Scheduler::new(None)));
+
+        let (tweet, _, _) = client
+            .create_tweet("hello", None, None, Some(ReplySettings::MentionedUsers))
+            .await?;
+        assert_eq!(tweet, ("30".to_owned(), "hello".to_owned()));
+        client.create_tweet("hello", None, None, None).await?;
+
+        let bodies: Vec<serde_json::Value> = requests
+            .try_iter()
+            .map(|(_, _, body)| serde_json::from_str(&body))
+            .collect::<Result<_, _>>()?;
+        assert_eq!(
+            bodies,
+            vec![
+                serde_json::json!({ "text": "hello", "reply_settings": "mentionedUsers" }),
+                serde_json::json!({ "text": "hello" }),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn share_the_rate_limit_of_an_endpoint() -> Result<(), Box<dyn std::error::Error>> {
+        let deleted = r#"{ "data": { "deleted": true } }"#;
+        let (base, requests) = mock_api(vec![(200, deleted), (200, deleted)]);
+        let scheduler = Arc::new(Scheduler::new(None));
+        let client = ApiClient::mock("1", base, scheduler.clone());
+
+        client.delete_tweet("20").await?;
+        client.delete_tweet("21").await?;
+        let paths: Vec<String> = requests.try_iter().map(|(_, url, _)| url).collect();
+        assert_eq!(paths, vec!["/tweets/20", "/tweets/21"]);
+
+        let limits = scheduler.rate_limits("1", TRACKED_ENDPOINTS);
+        assert_eq!(limits.len(), TRACKED_ENDPOINTS.len());
+        assert_eq!(limits["DELETE tweets/:tweet_id"].unwrap().remaining, 41);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn a_broken_account_does_not_hold_up_others() -> Result<(), Box<dyn std::error::Error>> {
+        let reset = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs()
+            + 3600;
+        let (broken_base, _) =
+            mock_api_with_limit(vec![(401, r#"{ "title": "Unauthorized" }"#)], 0, reset);
+        let deleted = r#"{ "data": { "deleted": true } }"#;
+        let (healthy_base, _) = mock_api(vec![(200, deleted), (200, deleted)]);
+        let scheduler = Arc::new(Scheduler::new(None));
+        let broken = ApiClient::mock("1", broken_base, scheduler.clone());
+        let healthy = ApiClient::mock("2", healthy_base, scheduler.clone());
+
+        // the broken account fails, and then waits an hour for the rate limit of the endpoint
+        assert!(broken.delete_tweet("20").await.is_err());
+        let waiting = tokio::spawn(async move { broken.delete_tweet("21").await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(scheduler.pending().len(), 1);
+
+        // the other account calls the same endpoint right away
+        let started = std::time::Instant::now();
+        healthy.delete_tweet("30").await?;
+        healthy.delete_tweet("31").await?;
+        assert!(started.elapsed() < Duration::from_secs(1));
+        let limits = scheduler.rate_limits("2", TRACKED_ENDPOINTS);
+        assert_eq!(limits["DELETE tweets/:tweet_id"].unwrap().remaining, 41);
+        assert!(!waiting.is_finished());
+        waiting.abort();
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn retry_on_too_many_requests() -> Result<(), Box<dyn std::error::Error>> {
+        let addr = mock_server(vec![429, 200]);
+        let client = Client::new();
+        let policy = RetryPolicy {
+            max_retries: 2,
+            max_wait: Duration::from_secs(10),
+        };
+        let acquired = std::sync::atomic::AtomicUsize::new(0);
+        let resp = send_with_retry(
+            policy,
+            || async {
+                acquired.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                Ok(())
+            },
+            || client.get(format!("http://{}/", addr)),
+        )
+        .await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.text().await?, r#"{"status":200}"#);
+        // the retry waited for its turn again
+        assert_eq!(acquired.into_inner(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn identify_and_time_out() -> Result<(), Box<dyn std::error::Error>> {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr();
+        std::thread::spawn(move || {
+            for req in server.incoming_requests() {
+                if req.url() == "/hang" {
+                    // never answered in time
+                    std::thread::sleep(Duration::from_secs(2));
+                    continue;
+                }
+                let agent = req
+                    .headers()
+                    .iter()
+                    .find(|h| h.field.equiv("User-Agent"))
+                    .map(|h| h.value.to_string())
+                    .unwrap_or_default();
+                req.respond(tiny_http::Response::from_string(agent))
+                    .unwrap();
+            }
+        });
+        let client = build_http(Duration::from_millis(200))?;
+
+        let agent = client.get(format!("http://{}/", addr)).send().await?;
+        assert_eq!(agent.text().await?, format!("binchotan/{}", VERSION));
+
+        let err = client
+            .get(format!("http://{}/hang", addr))
+            .send()
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            ApiClientError::from(err),
+            ApiClientError::Timeout(_)
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn report_unreachable_api() -> Result<(), Box<dyn std::error::Error>> {
+        // nobody listens on the port once the listener is dropped
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        let err = Client::new()
+            .get(format!("http://{}/", addr))
+            .send()
+            .await
+            .unwrap_err();
+        let err = ApiClientError::from(err);
+        assert!(matches!(err, ApiClientError::Unreachable(_)));
+        assert!(err.is_network());
+
+        let resp = crate::connection::ResponseError::from(crate::error::AppError::from(err));
+        assert_eq!(resp.code, -32004);
+        assert_eq!(resp.data, Some(serde_json::json!({ "retryable": true })));
+
+        Ok(())
+    }
+
+    #[test]
+    fn report_missing_tweet() {
+        let err = crate::error::AppError::from(ApiClientError::TweetNotFound("20".to_owned()));
+        let resp = crate::connection::ResponseError::from(err);
+        assert_eq!(resp.code, -32001);
+        assert_eq!(
+            resp.data,
+            Some(serde_json::json!({ "tweet_id": "20", "retryable": false }))
+        );
+    }
+
+    #[test]
+    fn report_whether_to_retry() {
+        let retryable = |err: ApiClientError| {
+            crate::connection::ResponseError::from(crate::error::AppError::from(err))
+                .data
+                .unwrap()["retryable"]
+                .clone()
+        };
+        let limited = ApiClientError::RateLimited {
+            endpoint: "tweets".to_owned(),
+            reset: 1700000000,
+        };
+        assert_eq!(retryable(limited), true);
+        let failed =
+            ApiClientError::RespStatus(400, r#"{ "title": "Invalid Request" }"#.to_owned());
+        assert_eq!(retryable(failed), false);
+    }
+
+    #[tokio::test]
+    async fn no_retry_by_default() -> Result<(), Box<dyn std::error::Error>> {
+        let addr = mock_server(vec![429]);
+        let client = Client::new();
+        let resp = send_with_retry(
+            RetryPolicy::default(),
+            || async { Ok(()) },
+            || client.get(format!("http://{}/", addr)),
+        )
+        .await?;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        Ok(())
     }
 }
@@ -1,22 +1,71 @@
 use anyhow::Context;
 use oauth2::{
-    basic::BasicClient, reqwest::async_http_client, AuthUrl, AuthorizationCode, ClientId,
-    ClientSecret, CsrfToken, PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, RefreshToken, Scope,
-    TokenResponse, TokenUrl,
+    basic::{BasicClient, BasicErrorResponseType},
+    reqwest::async_http_client,
+    AccessToken, AuthUrl, AuthorizationCode, ClientId, ClientSecret, CsrfToken, PkceCodeChallenge,
+    PkceCodeVerifier, RedirectUrl, RefreshToken, RequestTokenError, RevocationUrl, Scope,
+    StandardRevocableToken, TokenResponse, TokenUrl,
+};
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+    sync::Arc,
+    time::{Duration, Instant},
 };
-use std::{borrow::Cow, collections::HashSet};
 use thiserror::Error;
 use tokio::{
-    sync::mpsc::{self, error::TryRecvError},
+    sync::{
+        mpsc::{self, error::TryRecvError},
+        oneshot,
+    },
     task::JoinHandle,
 };
-use tracing::info;
+use tracing::{debug, info, warn};
 use url::Url;
 
+use crate::{
+    api::{ApiClient, ApiClientError},
+    cache::{Credential, CredentialState},
+    error::AppError,
+    scheduler::Scheduler,
+};
+
+/// Scopes Twitter's OAuth 2.0 accepts. See https://developer.twitter.com/en/docs/authentication/oauth-2-0/authorization-code
+pub const KNOWN_SCOPES: &[&str] = &[
+    "tweet.read",
+    "tweet.write",
+    "tweet.moderate.write",
+    "users.read",
+    "follows.read",
+    "follows.write",
+    "offline.access",
+    "space.read",
+    "mute.read",
+    "mute.write",
+    "like.read",
+    "like.write",
+    "list.read",
+    "list.write",
+    "block.read",
+    "block.write",
+    "bookmark.read",
+    "bookmark.write",
+    "dm.read",
+    "dm.write",
+];
+
+// The bind is retried for about 3 seconds in total
+const BIND_ATTEMPTS: u32 = 6;
+const BIND_INITIAL_DELAY: Duration = Duration::from_millis(100);
+// An authorization flow is abandoned if its callback does not arrive in this long, unless configured otherwise
+const DEFAULT_CALLBACK_TIMEOUT: Duration = Duration::from_secs(600);
+
 #[derive(Debug, Error)]
 pub enum AuthError {
-    #[error("could not start the redirect server. The port might be already occupied: {0}")]
-    ServerLaunch(Box<dyn std::error::Error + Send + Sync + 'static>),
+    #[error("could not start the redirect server on {0}. The port might be already occupied by another process (change redirect_host in the config): {1}")]
+    ServerLaunch(String, Box<dyn std::error::Error + Send + Sync + 'static>),
+    #[error("redirect_host must be in the form of host:port, got {0:?}")]
+    InvalidRedirectHost(String),
     #[error("could not receive a request: {0}")]
     ServerListen(std::io::Error),
     #[error("no authorization code was returned")]
@@ -25,8 +74,16 @@ pub enum AuthError {
     NoState,
     #[error("invalid state: {0}")]
     InvalidState(String),
+    #[error("the authorization was denied: {0}")]
+    Denied(String),
+    #[error("the authorization was not completed. see the log of the redirect server")]
+    Aborted,
     #[error("failed to exchange authorization code for access token: {0:?}")]
     Exchange(#[source] anyhow::Error),
+    #[error("the refresh token was rejected: {0}")]
+    InvalidGrant(String),
+    #[error(transparent)]
+    Api(#[from] ApiClientError),
     #[error(transparent)]
     Parse(#[from] url::ParseError),
     #[error(transparent)]
@@ -35,11 +92,19 @@ pub enum AuthError {
     Other(#[from] anyhow::Error),
 }
 
+/// An account authorized by `Auth::add_account`.
+pub struct NewAccount {
+    pub client: ApiClient,
+    pub credential: Credential,
+    pub scopes: Vec<String>,
+}
+
 pub struct Auth {
     client_id: String,
     client_secret: String,
     redirect_host: String,
     pub scopes: HashSet<String>,
+    callback_timeout: Duration,
     _handle: JoinHandle<()>,
     tx: mpsc::Sender<RedirectServerRequest>,
 }
@@ -52,6 +117,13 @@ impl Auth {
         scopes: HashSet<String>,
     ) -> Self {
         let client = create_client(client_id.clone(), client_secret.clone());
+        match redirect_url(&redirect_host) {
+            Ok(url) => info!(
+                "using {} as the redirect URL. It must match the callback URL registered in the Twitter app",
+                url.as_str()
+            ),
+            Err(err) => warn!("{}", err),
+        }
         let (tx, rx) = mpsc::channel(10);
         let handle = start_server(redirect_host.clone(), client, rx);
 
@@ -60,37 +132,42 @@ impl Auth {
             client_secret,
             redirect_host,
             scopes,
+            callback_timeout: DEFAULT_CALLBACK_TIMEOUT,
             // the server will stop when Auth is dropped
             _handle: handle,
             tx,
         }
     }
 
+    /// Sets how long the redirect server waits for the callback of a flow. The flow is aborted once it passes.
+    pub fn with_callback_timeout(mut self, timeout: Duration) -> Self {
+        self.callback_timeout = timeout;
+        self
+    }
+
     pub async fn start_auth(
         &self,
-        callback: impl FnOnce(String, String) + Send + 'static,
+        callback: impl FnOnce(String, String, Vec<String>) + Send + 'static,
     ) -> Result<String, AuthError> {
         let client = create_client(self.client_id.clone(), self.client_secret.clone())
-            .set_redirect_uri(RedirectUrl::new(format!("http://{}", self.redirect_host))?);
+            .set_redirect_uri(redirect_url(&self.redirect_host)?);
 
         let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
-        let scopes = self.scopes.clone();
+        let scopes: Vec<String> = self.scopes.iter().cloned().collect();
         let (auth_url, state) = client
             .authorize_url(CsrfToken::new_random)
-            .add_scopes(scopes.into_iter().map(Scope::new))
+            .add_scopes(scopes.iter().cloned().map(Scope::new))
             .set_pkce_challenge(pkce_challenge)
             .url();
 
         // save them for later verification
-        info!(
-            "sent to redirect server: state = {}, pkce_verifier = {}",
-            state.secret(),
-            pkce_verifier.secret()
-        );
+        info!("sent to redirect server: state = {}", state.secret());
         self.tx
             .send(RedirectServerRequest {
                 state,
                 pkce_verifier,
+                scopes,
+                expires_at: Instant::now() + self.callback_timeout,
                 callback: Box::new(callback),
             })
             .await
@@ -101,6 +178,37 @@ impl Auth {
         Ok(auth_url.into())
     }
 
+    /// Runs the whole authorization flow for a new account and waits for it to complete. `open` receives the URL the user has to visit. The user id is fetched with the new tokens, so that the account can be told apart from ones already added.
+    /// Each flow is matched by its own `state`, so flows running at the same time do not get mixed up.
+    pub async fn add_account(
+        &self,
+        scheduler: Arc<Scheduler>,
+        open: impl FnOnce(&str),
+    ) -> Result<NewAccount, AppError> {
+        let (tx, rx) = oneshot::channel();
+        let auth_url = self
+            .start_auth(move |acc, refr, scopes| {
+                let _ = tx.send((acc, refr, scopes));
+            })
+            .await?;
+        open(&auth_url);
+
+        // the callback is dropped without being called if the flow fails
+        let (access_token, refresh_token, scopes) = rx.await.map_err(|_| AuthError::Aborted)?;
+        let client = ApiClient::new(access_token.clone(), scheduler).await?;
+        info!("authorized account {}", client.user_id);
+
+        Ok(NewAccount {
+            client,
+            credential: Credential {
+                access_token,
+                refresh_token,
+                state: CredentialState::Valid,
+            },
+            scopes,
+        })
+    }
+
     /// Refresh tokens to obtain a fresh access token using the refresh token received in advance.
     pub async fn refresh_tokens(
         &self,
@@ -109,14 +217,27 @@ impl Auth {
         let refresh_token = RefreshToken::new(refresh_token);
         let scopes = self.scopes.clone();
         let client = create_client(self.client_id.clone(), self.client_secret.clone())
-            .set_redirect_uri(RedirectUrl::new("http://127.0.0.1:31337".to_owned())?);
+            .set_redirect_uri(redirect_url(&self.redirect_host)?);
 
         let result = client
             .exchange_refresh_token(&refresh_token)
             .add_scopes(scopes.into_iter().map(Scope::new))
             .request_async(async_http_client)
             .await
-            .context("failed to exchange refresh token for access token")?;
+            .map_err(|err| match err {
+                // only this means the token is no longer usable. Other errors may go away if retried
+                RequestTokenError::ServerResponse(resp)
+                    if *resp.error() == BasicErrorResponseType::InvalidGrant =>
+                {
+                    AuthError::InvalidGrant(resp.to_string())
+                }
+                RequestTokenError::Request(oauth2::reqwest::Error::Reqwest(err)) => {
+                    AuthError::Api(err.into())
+                }
+                other => anyhow::Error::new(other)
+                    .context("failed to exchange refresh token for access token")
+                    .into(),
+            })?;
         let access_token = result.access_token().secret().to_owned();
         let new_refresh_token = match result.refresh_token() {
             Some(x) => x.secret(),
@@ -124,10 +245,49 @@ impl Auth {
         }
         .to_owned();
 
-        info!("Tokens refreshed: {}, {}", access_token, new_refresh_token);
+        info!("Tokens refreshed");
 
         Ok((access_token, new_refresh_token))
     }
+
+    /// Revokes the refresh token and the access token, so that they cannot be used even if they leak later.
+    pub async fn revoke_tokens(
+        &self,
+        access_token: String,
+        refresh_token: String,
+    ) -> Result<(), AuthError> {
+        let client = create_client(self.client_id.clone(), self.client_secret.clone())
+            .set_revocation_uri(RevocationUrl::new(
+                "https://api.twitter.com/2/oauth2/revoke".to_owned(),
+            )?);
+
+        // revoking only the access token would leave the refresh token usable
+        let tokens = [
+            StandardRevocableToken::RefreshToken(RefreshToken::new(refresh_token)),
+            StandardRevocableToken::AccessToken(AccessToken::new(access_token)),
+        ];
+        for token in tokens {
+            client
+                .revoke_token(token)
+                .context("the revocation endpoint is not set")?
+                .request_async(async_http_client)
+                .await
+                .context("failed to revoke the token")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Builds the redirect URL from `redirect_host`, which must consist of a host and a port.
+pub fn redirect_url(redirect_host: &str) -> Result<RedirectUrl, AuthError> {
+    let invalid = || AuthError::InvalidRedirectHost(redirect_host.to_owned());
+    let url = Url::parse(&format!("http://{}", redirect_host)).map_err(|_| invalid())?;
+    // reject anything other than host:port, e.g. a path or a missing port
+    if url.port().is_none() || url.path() != "/" || url.query().is_some() {
+        return Err(invalid());
+    }
+    Ok(RedirectUrl::new(format!("http://{}", redirect_host))?)
 }
 
 fn create_client(client_id: String, client_secret: String) -> BasicClient {
@@ -151,11 +311,16 @@ fn start_server(
     })
 }
 
-// Represents (state, pkce_verifier, callback)
+// Called with the access token, the refresh token and the granted scopes
+type AuthCallback = Box<dyn FnOnce(String, String, Vec<String>) + Send + 'static>;
+
+// Represents (state, pkce_verifier, requested scopes, when the flow is abandoned, callback)
 pub(crate) struct RedirectServerRequest {
     state: CsrfToken,
     pkce_verifier: PkceCodeVerifier,
-    callback: Box<dyn FnOnce(String, String) + Send + 'static>,
+    scopes: Vec<String>,
+    expires_at: Instant,
+    callback: AuthCallback,
 }
 
 struct RedirectServer {
@@ -181,8 +346,12 @@ impl RedirectServer {
 
     async fn start(&mut self) -> Result<(), AuthError> {
         // TODO: use async http server implementation (e.g. tide)
-        let server =
-            tiny_http::Server::http(self.redirect_host.clone()).map_err(AuthError::ServerLaunch)?;
+        redirect_url(&self.redirect_host)?;
+        let server = Self::bind(&self.redirect_host).await?;
+        self.serve(server).await
+    }
+
+    async fn serve(&mut self, server: tiny_http::Server) -> Result<(), AuthError> {
         loop {
             if let Some(req) = server.try_recv().map_err(AuthError::ServerListen)? {
                 match self.handle_request(req).await {
@@ -193,6 +362,19 @@ impl RedirectServer {
                 }
             }
 
+            // dropping the callback aborts the flow waiting for it
+            let now = Instant::now();
+            self.states.retain(|s| {
+                let alive = s.expires_at > now;
+                if !alive {
+                    warn!(
+                        "the callback for state {} did not arrive in time. aborting the authorization",
+                        s.state.secret()
+                    );
+                }
+                alive
+            });
+
             match self.rx.try_recv() {
                 Ok(req) => self.states.push(req),
                 Err(TryRecvError::Empty) => {}
@@ -207,75 +389,106 @@ impl RedirectServer {
         Ok(())
     }
 
+    /// Binds the redirect server, retrying with an exponential backoff since the port may still be held by a previous attempt (e.g. in TIME_WAIT).
+    async fn bind(redirect_host: &str) -> Result<tiny_http::Server, AuthError> {
+        let mut delay = BIND_INITIAL_DELAY;
+        let mut attempt = 1;
+        loop {
+            match tiny_http::Server::http(redirect_host) {
+                Ok(server) => return Ok(server),
+                Err(err) if attempt < BIND_ATTEMPTS => {
+                    warn!(
+                        "could not bind the redirect server to {} ({}). retrying in {:?}...",
+                        redirect_host, err, delay
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
+                Err(err) => return Err(AuthError::ServerLaunch(redirect_host.to_owned(), err)),
+            }
+        }
+    }
+
+    /// Handles a request to the redirect URL. Browsers also request a favicon or prefetch the URL, so requests without `code` or `error` are answered with 404 and otherwise ignored.
     async fn handle_request(&mut self, req: tiny_http::Request) -> Result<(), AuthError> {
-        let pairs = Url::parse(&format!("http://{}/{}", self.redirect_host, req.url()))?;
-        let code = pairs
-            .query_pairs()
-            .find_map(|(k, v)| match k {
-                Cow::Borrowed("code") => Some(AuthorizationCode::new(v.to_string())),
-                _ => None,
-            })
-            .ok_or(AuthError::NoAuthorizationCode)?;
-        let state = pairs
-            .query_pairs()
-            .find_map(|(k, v)| match k {
-                Cow::Borrowed("state") => Some(CsrfToken::new(v.to_string())),
-                _ => None,
-            })
-            .ok_or(AuthError::NoState)?;
+        let url = Url::parse(&format!("http://{}/{}", self.redirect_host, req.url()))?;
+        let param = |name: &str| {
+            url.query_pairs()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.into_owned())
+        };
+        if param("code").is_none() && param("error").is_none() {
+            debug!("ignoring a request to {} on the redirect server", req.url());
+            req.respond(tiny_http::Response::from_string("Not Found").with_status_code(404))?;
+            return Ok(());
+        }
 
-        let (acc, refr, callback) = self.generate_tokens(code, state).await?;
+        let result = self
+            .handle_callback(param("code"), param("state"), param("error"))
+            .await;
+        let resp = match &result {
+            Ok(_) => tiny_http::Response::from_string(
+                "Authentication succeeded! Now you can safely close this page and go back to your frontend.",
+            ),
+            Err(err) => tiny_http::Response::from_string(format!(
+                "Authentication failed: {}. Please start over from your frontend.",
+                err
+            ))
+            .with_status_code(400),
+        };
+        req.respond(resp)?;
 
-        info!("got tokens : {},  {}", acc, refr);
-        callback(acc, refr);
+        result
+    }
 
-        // return 200 OK
-        let resp = tiny_http::Response::from_string(
-        "Authentication succeeded! Now you can safely close this page and go back to your frontend.",);
-        req.respond(resp)?;
+    async fn handle_callback(
+        &mut self,
+        code: Option<String>,
+        state: Option<String>,
+        error: Option<String>,
+    ) -> Result<(), AuthError> {
+        let state = CsrfToken::new(state.ok_or(AuthError::NoState)?);
+        if let Some(error) = error {
+            // the user denied the authorization. the flow is aborted as its callback is dropped
+            self.take_state(&state)?;
+            return Err(AuthError::Denied(error));
+        }
+        let code = AuthorizationCode::new(code.ok_or(AuthError::NoAuthorizationCode)?);
+
+        let (acc, refr, scopes, callback) = self.generate_tokens(code, state).await?;
+
+        info!("got tokens");
+        callback(acc, refr, scopes);
 
         Ok(())
     }
 
-    /// Ask the authorization server to exchange the authorization code for access/refresh token.
-    async fn generate_tokens(
-        &mut self,
-        code: AuthorizationCode,
-        state: CsrfToken,
-    ) -> Result<
-        (
-            String,
-            String,
-            Box<dyn FnOnce(String, String) + Send + 'static>,
-        ),
-        AuthError,
-    > {
-        // look for the same state
+    /// Removes the flow the state was issued for. Each state is accepted only once, so a replayed callback is rejected.
+    fn take_state(&mut self, state: &CsrfToken) -> Result<RedirectServerRequest, AuthError> {
         let idx = self
             .states
             .iter()
-            .enumerate()
-            .find(|(_i, s)| *(**s).state.secret() == *state.secret())
-            .map(|(i, _s)| i)
+            .position(|s| *s.state.secret() == *state.secret())
             .ok_or_else(|| AuthError::InvalidState(state.secret().into()))?;
+        Ok(self.states.swap_remove(idx))
+    }
 
+    /// Ask the authorization server to exchange the authorization code for access/refresh token.
+    async fn generate_tokens(
+        &mut self,
+        code: AuthorizationCode,
+        state: CsrfToken,
+    ) -> Result<(String, String, Vec<String>, AuthCallback), AuthError> {
         let RedirectServerRequest {
             state,
             pkce_verifier,
+            scopes,
             callback,
-        } = self.states.swap_remove(idx);
-
-        // なんかを忘れている・・・・code か pkce_verifierが誤り
-        info!(
-            "retrieved: state = {}, pkce_verifier = {}, code = {}\n",
-            state.secret(),
-            pkce_verifier.secret(),
-            code.secret(),
-        );
-        info!(
-            "pkce_challenge should be: {}",
-            PkceCodeChallenge::from_code_verifier_sha256(&pkce_verifier).as_str()
-        );
+            ..
+        } = self.take_state(&state)?;
+
+        info!("retrieved: state = {}", state.secret());
 
         let req = self
             .client
@@ -283,11 +496,7 @@ impl RedirectServer {
             .set_pkce_verifier(pkce_verifier)
             // It seems Twitter requires redirect_uri again on Authorization Code Request.
             // see also: https://www.oauth.com/oauth2-servers/access-tokens/authorization-code-request/
-            .set_redirect_uri(Cow::Owned(RedirectUrl::new(
-                // TODO: remove hard-coded redirect url
-                "http://127.0.0.1:31337".to_owned(),
-            )?));
-        info!("request: {:?}", req);
+            .set_redirect_uri(Cow::Owned(redirect_url(&self.redirect_host)?));
         let result = req.request_async(async_http_client).await.map_err(|err| {
             tracing::error!("{:?}", err);
             AuthError::Exchange(err.into())
@@ -298,7 +507,104 @@ impl RedirectServer {
             None => "",
         }
         .to_owned();
+        // The token response may omit the scope if it is identical to the requested one (RFC 6749, Section 5.1)
+        let granted_scopes = match result.scopes() {
+            Some(granted) => granted.iter().map(|s| s.to_string()).collect(),
+            None => scopes,
+        };
+
+        Ok((access_token, refresh_token, granted_scopes, callback))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_redirect_host() {
+        assert_eq!(
+            redirect_url("127.0.0.1:31337").unwrap().as_str(),
+            "http://127.0.0.1:31337"
+        );
+        assert_eq!(
+            redirect_url("localhost:8080").unwrap().as_str(),
+            "http://localhost:8080"
+        );
+        assert!(redirect_url("127.0.0.1").is_err());
+        assert!(redirect_url("127.0.0.1:31337/callback").is_err());
+        assert!(redirect_url("127.0.0.1:port").is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn each_flow_has_its_own_state() -> Result<(), AuthError> {
+        let auth = Auth::new(
+            "id".to_owned(),
+            "secret".to_owned(),
+            "127.0.0.1:0".to_owned(),
+            HashSet::from(["tweet.read".to_owned()]),
+        );
+        let state = |url: String| {
+            Url::parse(&url)
+                .unwrap()
+                .query_pairs()
+                .find(|(k, _)| k == "state")
+                .map(|(_, v)| v.into_owned())
+                .unwrap()
+        };
 
-        Ok((access_token, refresh_token, callback))
+        let first = state(auth.start_auth(|_, _, _| {}).await?);
+        let second = state(auth.start_auth(|_, _, _| {}).await?);
+        assert_ne!(first, second);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn wait_for_the_real_callback() -> Result<(), Box<dyn std::error::Error>> {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let host = server.server_addr().to_string();
+        let (tx, rx) = mpsc::channel(10);
+        let flow = |state: &str, timeout: Duration| {
+            let (done, aborted) = oneshot::channel::<()>();
+            let req = RedirectServerRequest {
+                state: CsrfToken::new(state.to_owned()),
+                pkce_verifier: PkceCodeVerifier::new("verifier".to_owned()),
+                scopes: vec![],
+                expires_at: Instant::now() + timeout,
+                callback: Box::new(move |_, _, _| {
+                    let _ = done.send(());
+                }),
+            };
+            (req, aborted)
+        };
+        let (slow, slow_aborted) = flow("slow", Duration::from_millis(300));
+        let (denied, denied_aborted) = flow("denied", Duration::from_secs(60));
+        assert!(tx.send(slow).await.is_ok());
+        assert!(tx.send(denied).await.is_ok());
+        let mut redirect = RedirectServer::new(
+            create_client("id".into(), "secret".into()),
+            host.clone(),
+            rx,
+        );
+        tokio::spawn(async move { redirect.serve(server).await });
+
+        let status = |path: &'static str| {
+            let url = format!("http://{}{}", host, path);
+            async move { reqwest::get(url).await.map(|resp| resp.status().as_u16()) }
+        };
+        // requests other than the callback do not end the flow
+        assert_eq!(status("/favicon.ico").await?, 404);
+        assert_eq!(status("/?state=slow").await?, 404);
+        assert_eq!(status("/?code=c&state=unknown").await?, 400);
+
+        assert_eq!(status("/?error=access_denied&state=denied").await?, 400);
+        assert!(denied_aborted.await.is_err());
+        // a flow whose callback never arrives is aborted, and its state cannot be used afterwards
+        assert!(slow_aborted.await.is_err());
+        assert_eq!(status("/?code=c&state=slow").await?, 400);
+
+        drop(tx);
+        Ok(())
     }
 }
@@ -1,21 +1,47 @@
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN},
+    pbkdf2,
+    rand::{SecureRandom, SystemRandom},
+};
 use serde::{Deserialize, Serialize};
 use std::{
     collections::{HashMap, HashSet},
     fs::File,
     io::{Read, Write},
+    num::NonZeroU32,
     path::{Path, PathBuf},
 };
 use thiserror::Error;
-use tracing::warn;
+use tracing::{info, warn};
 
 use crate::error::AppError;
 
+/// Version of the cache format written by this binary. Bump it and add a step to `migrate` when the format changes.
+pub const CACHE_VERSION: u32 = 2;
+
+// Rounds of PBKDF2-HMAC-SHA256 deriving the key from the passphrase
+const KDF_ITERATIONS: u32 = 100_000;
+
 #[derive(Deserialize, Serialize, Default)]
 pub struct Cache {
+    // Files written before versioning have no version, which is treated as 0
+    #[serde(default)]
+    pub version: u32,
+    // Present if the tokens are encrypted. They are in plaintext otherwise.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<Encryption>,
     pub accounts: HashMap<String, Credential>,
     pub scopes: HashSet<String>,
 }
 
+/// How the tokens in the cache are encrypted. Each token is sealed with ChaCha20-Poly1305 under a key derived from the passphrase, and stored as base64 of the nonce followed by the ciphertext.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Encryption {
+    // base64 of the salt for the key derivation
+    salt: String,
+    iterations: u32,
+}
+
 #[derive(Deserialize, Serialize, Clone)]
 pub struct Credential {
     pub access_token: String,
@@ -24,7 +50,8 @@ pub struct Credential {
     pub state: CredentialState,
 }
 
-#[derive(Default, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum CredentialState {
     #[default]
     Cached,
@@ -32,40 +59,106 @@ pub enum CredentialState {
     Valid,
 }
 
+impl CredentialState {
+    /// The name stored in the `token_state` column. None for Cached, which is not stored.
+    pub fn column(self) -> Option<&'static str> {
+        match self {
+            CredentialState::Cached => None,
+            CredentialState::Expired => Some("expired"),
+            CredentialState::Valid => Some("valid"),
+        }
+    }
+
+    pub fn from_column(column: Option<&str>) -> Self {
+        match column {
+            Some("expired") => CredentialState::Expired,
+            Some("valid") => CredentialState::Valid,
+            _ => CredentialState::Cached,
+        }
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum CacheManagerError {
     #[error(transparent)]
     Io(#[from] std::io::Error),
+    #[error("could not serialize the cache: {0}")]
+    Serialize(serde_json::Error),
+    #[error("the cache is in version {0} of the format, but this binary only supports up to {CACHE_VERSION}. update binchotan")]
+    UnsupportedVersion(u32),
+    #[error("the tokens in the cache are encrypted. set the passphrase to cache_key in the config (or BINCHOTAN_CACHE_KEY)")]
+    MissingKey,
+    #[error("could not decrypt the tokens in the cache. cache_key (or BINCHOTAN_CACHE_KEY) may be wrong")]
+    WrongKey,
+    #[error("could not encrypt the tokens")]
+    Encrypt,
 }
 
 /// キャッシュの読み書きを行います。トークンなどの情報は有効であるとは限らないので、別途検証する必要があります。
 pub struct CacheManager {
     cache_path: PathBuf,
+    // The tokens are encrypted with this passphrase if set
+    passphrase: Option<String>,
 }
 
 impl CacheManager {
     pub fn new<P: AsRef<Path>>(path: P) -> Self {
         Self {
             cache_path: path.as_ref().to_owned(),
+            passphrase: None,
         }
     }
 
+    /// Encrypts the tokens with the passphrase on save. Caches written without one can still be loaded, and are encrypted on the next save.
+    pub fn with_passphrase(mut self, passphrase: Option<String>) -> Self {
+        self.passphrase = passphrase;
+        self
+    }
+
     pub fn load(&self) -> Result<Option<Cache>, CacheManagerError> {
         let mut file = match File::open(&self.cache_path) {
             Ok(file) => file,
             Err(x) if x.kind() == std::io::ErrorKind::NotFound => return Ok(None),
             Err(x) => return Err(x.into()),
         };
+        warn_if_exposed(&file, &self.cache_path)?;
         let mut s = String::new();
         file.read_to_string(&mut s)?;
 
-        match serde_json::from_str::<Cache>(&s) {
-            Ok(content) => Ok(Some(content)),
+        let content = serde_json::from_str::<serde_json::Value>(&s)
+            .map(|value| (value["version"].as_u64().unwrap_or(0) as u32, value));
+        let (version, value) = match content {
+            Ok(content) => content,
             Err(err) => {
                 warn!("the cache file is corrupt. ignoring it: {}", err);
-                Ok(None)
+                return Ok(None);
+            }
+        };
+        if version > CACHE_VERSION {
+            return Err(CacheManagerError::UnsupportedVersion(version));
+        }
+
+        let mut content = match serde_json::from_value::<Cache>(migrate(value, version)) {
+            Ok(content) => content,
+            Err(err) => {
+                warn!("the cache file is corrupt. ignoring it: {}", err);
+                return Ok(None);
+            }
+        };
+
+        if let Some(encryption) = &content.encryption {
+            let passphrase = self
+                .passphrase
+                .as_ref()
+                .ok_or(CacheManagerError::MissingKey)?;
+            let cipher = Cipher::derive(passphrase, encryption)?;
+            for cred in content.accounts.values_mut() {
+                cred.access_token = cipher.decrypt(&cred.access_token)?;
+                cred.refresh_token = cipher.decrypt(&cred.refresh_token)?;
             }
         }
+
+        Ok(Some(content))
     }
 
     pub fn save(
@@ -73,20 +166,297 @@ impl CacheManager {
         scopes: HashSet<String>,
         credentials: HashMap<String, Credential>,
     ) -> Result<(), AppError> {
-        let content = Cache {
+        let mut content = Cache {
+            version: CACHE_VERSION,
+            encryption: None,
             scopes,
             accounts: credentials.into_iter().collect(),
         };
-        let mut file = File::create(&self.cache_path)?;
-        file.write_all(serde_json::to_string(&content).unwrap().as_bytes())?;
+        if let Some(passphrase) = &self.passphrase {
+            let encryption = Encryption::generate()?;
+            let cipher = Cipher::derive(passphrase, &encryption)?;
+            for cred in content.accounts.values_mut() {
+                cred.access_token = cipher.encrypt(&cred.access_token)?;
+                cred.refresh_token = cipher.encrypt(&cred.refresh_token)?;
+            }
+            content.encryption = Some(encryption);
+        }
+        let json = serde_json::to_string(&content).map_err(CacheManagerError::Serialize)?;
+
+        if let Some(dir) = self.cache_path.parent() {
+            std::fs::create_dir_all(dir).map_err(CacheManagerError::Io)?;
+        }
+        // write to a temporary file and then rename it over the cache, so that the cache is never left half-written
+        let tmp_path = self.tmp_path();
+        let result = create_private(&tmp_path).and_then(|mut file| {
+            file.write_all(json.as_bytes())?;
+            file.sync_all()
+        });
+        if let Err(err) = result.and_then(|_| std::fs::rename(&tmp_path, &self.cache_path)) {
+            let _ = std::fs::remove_file(&tmp_path);
+            return Err(CacheManagerError::Io(err).into());
+        }
         Ok(())
     }
+
+    // A sibling of the cache, so that the rename stays within the same filesystem
+    fn tmp_path(&self) -> PathBuf {
+        let name = self
+            .cache_path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        self.cache_path.with_file_name(format!(".{}.tmp", name))
+    }
+}
+
+/// Upgrades a cache in an older format to the current one, step by step.
+fn migrate(mut value: serde_json::Value, version: u32) -> serde_json::Value {
+    if version < 1 {
+        // 0 -> 1: only the version was added
+        info!("migrating the cache from version 0 to 1");
+    }
+    if version < 2 {
+        // 1 -> 2: `encryption` was added. files without it are in plaintext
+        info!("migrating the cache from version 1 to 2");
+    }
+
+    value["version"] = CACHE_VERSION.into();
+    value
+}
+
+impl Encryption {
+    // Uses a fresh salt on each save
+    fn generate() -> Result<Self, CacheManagerError> {
+        let mut salt = [0u8; 16];
+        SystemRandom::new()
+            .fill(&mut salt)
+            .map_err(|_| CacheManagerError::Encrypt)?;
+        Ok(Self {
+            salt: base64::encode(salt),
+            iterations: KDF_ITERATIONS,
+        })
+    }
+}
+
+struct Cipher {
+    key: LessSafeKey,
+}
+
+impl Cipher {
+    fn derive(passphrase: &str, encryption: &Encryption) -> Result<Self, CacheManagerError> {
+        let salt = base64::decode(&encryption.salt).map_err(|_| CacheManagerError::WrongKey)?;
+        let iterations =
+            NonZeroU32::new(encryption.iterations).ok_or(CacheManagerError::WrongKey)?;
+        let mut key = [0u8; 32];
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            iterations,
+            &salt,
+            passphrase.as_bytes(),
+            &mut key,
+        );
+        // SAFETY: the key has the length ChaCha20-Poly1305 requires
+        let key = UnboundKey::new(&CHACHA20_POLY1305, &key).unwrap();
+        Ok(Self {
+            key: LessSafeKey::new(key),
+        })
+    }
+
+    fn encrypt(&self, plaintext: &str) -> Result<String, CacheManagerError> {
+        let mut nonce = [0u8; NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| CacheManagerError::Encrypt)?;
+        let mut sealed = plaintext.as_bytes().to_vec();
+        self.key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::empty(),
+                &mut sealed,
+            )
+            .map_err(|_| CacheManagerError::Encrypt)?;
+
+        Ok(base64::encode([nonce.as_slice(), &sealed].concat()))
+    }
+
+    fn decrypt(&self, encrypted: &str) -> Result<String, CacheManagerError> {
+        let mut sealed = base64::decode(encrypted).map_err(|_| CacheManagerError::WrongKey)?;
+        if sealed.len() < NONCE_LEN {
+            return Err(CacheManagerError::WrongKey);
+        }
+        let mut ciphertext = sealed.split_off(NONCE_LEN);
+        // SAFETY: the nonce has just been split off with the right length
+        let nonce = Nonce::try_assume_unique_for_key(&sealed).unwrap();
+        let plaintext = self
+            .key
+            .open_in_place(nonce, Aad::empty(), &mut ciphertext)
+            .map_err(|_| CacheManagerError::WrongKey)?;
+
+        String::from_utf8(plaintext.to_vec()).map_err(|_| CacheManagerError::WrongKey)
+    }
+}
+
+/// Creates (or truncates) the file so that only the owner can read and write it, since it holds tokens.
+#[cfg(unix)]
+fn create_private(path: &Path) -> std::io::Result<File> {
+    use std::fs::{OpenOptions, Permissions};
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    // the mode only applies to new files
+    file.set_permissions(Permissions::from_mode(0o600))?;
+    Ok(file)
+}
+
+#[cfg(not(unix))]
+fn create_private(path: &Path) -> std::io::Result<File> {
+    File::create(path)
+}
+
+#[cfg(unix)]
+fn warn_if_exposed(file: &File, path: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = file.metadata()?.permissions().mode();
+    if mode & 0o077 != 0 {
+        warn!(
+            "the cache {} is readable by other users (mode {:o}). run `chmod 600` on it",
+            path.display(),
+            mode & 0o777
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn warn_if_exposed(_file: &File, _path: &Path) -> std::io::Result<()> {
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[cfg(unix)]
+    #[test]
+    fn save_owner_only() -> Result<(), Box<dyn std::error::Error>> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path: PathBuf = "/tmp/binchotan_private_cache.json".into();
+        let cm = CacheManager::new(&path);
+        cm.save(HashSet::new(), HashMap::new())?;
+        let mode = std::fs::metadata(&path)?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        std::fs::remove_file(&path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn store_credential_state() {
+        for state in [CredentialState::Valid, CredentialState::Expired] {
+            assert_eq!(CredentialState::from_column(state.column()), state);
+        }
+        assert_eq!(CredentialState::Cached.column(), None);
+        assert_eq!(
+            CredentialState::from_column(Some("unknown")),
+            CredentialState::Cached
+        );
+    }
+
+    #[test]
+    fn migrate_unversioned_cache() -> Result<(), Box<dyn std::error::Error>> {
+        let path: PathBuf = "/tmp/binchotan_v0_cache.json".into();
+        let mut f = File::create(&path)?;
+        f.write_all(
+            br#"{ "scopes": ["tweet.read"], "accounts": { "123": { "access_token": "foo", "refresh_token": "bar" } } }"#,
+        )?;
+        f.flush()?;
+        let cache = CacheManager::new(&path).load()?.unwrap();
+        assert_eq!(cache.version, CACHE_VERSION);
+        assert_eq!(cache.accounts["123"].access_token, "foo");
+
+        std::fs::remove_file(&path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn reject_newer_cache() -> Result<(), Box<dyn std::error::Error>> {
+        let path: PathBuf = "/tmp/binchotan_future_cache.json".into();
+        let mut f = File::create(&path)?;
+        f.write_all(br#"{ "version": 999, "scopes": [], "accounts": {} }"#)?;
+        f.flush()?;
+        let result = CacheManager::new(&path).load();
+        assert!(matches!(
+            result,
+            Err(CacheManagerError::UnsupportedVersion(999))
+        ));
+
+        std::fs::remove_file(&path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn failed_save_keeps_cache() -> Result<(), Box<dyn std::error::Error>> {
+        let path: PathBuf = "/tmp/binchotan_atomic_cache.json".into();
+        let cm = CacheManager::new(&path);
+        let scopes = HashSet::from(["tweet.read".to_owned()]);
+        cm.save(scopes.clone(), HashMap::new())?;
+
+        // a directory in the way of the temporary file makes the write fail
+        let tmp_path = cm.tmp_path();
+        std::fs::create_dir_all(&tmp_path)?;
+        assert!(cm.save(HashSet::new(), HashMap::new()).is_err());
+        assert_eq!(cm.load()?.unwrap().scopes, scopes);
+
+        std::fs::remove_dir(&tmp_path)?;
+        std::fs::remove_file(&path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn encrypt_tokens() -> Result<(), Box<dyn std::error::Error>> {
+        let path: PathBuf = "/tmp/binchotan_encrypted_cache.json".into();
+        let cm = CacheManager::new(&path).with_passphrase(Some("correct horse".to_owned()));
+        let cred = Credential {
+            access_token: "access".to_owned(),
+            refresh_token: "refresh".to_owned(),
+            state: CredentialState::Cached,
+        };
+        cm.save(HashSet::new(), HashMap::from([("123".to_owned(), cred)]))?;
+
+        let raw = std::fs::read_to_string(&path)?;
+        assert!(!raw.contains(r#""access""#) && !raw.contains(r#""refresh""#));
+        let cache = cm.load()?.unwrap();
+        assert_eq!(cache.accounts["123"].access_token, "access");
+        assert_eq!(cache.accounts["123"].refresh_token, "refresh");
+
+        assert!(matches!(
+            CacheManager::new(&path).load(),
+            Err(CacheManagerError::MissingKey)
+        ));
+        assert!(matches!(
+            CacheManager::new(&path)
+                .with_passphrase(Some("wrong".to_owned()))
+                .load(),
+            Err(CacheManagerError::WrongKey)
+        ));
+
+        std::fs::remove_file(&path)?;
+
+        Ok(())
+    }
+
     #[test]
     fn ignore_nonexistent_cache() -> Result<(), Box<dyn std::error::Error>> {
         let path: PathBuf = "/tmp/binchotan_fake_cache.json".into();
@@ -1,29 +1,607 @@
+use anyhow::anyhow;
+use config::ConfigError;
 use serde::Deserialize;
 use std::collections::HashSet;
-use std::path::PathBuf;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
 
-use crate::error::AppError;
+use crate::{
+    auth::{self, KNOWN_SCOPES},
+    connection::METHOD_SCOPES,
+    error::AppError,
+    filter::FilterErrorPolicy,
+    mute::Mute,
+};
 
 #[derive(Deserialize)]
 pub struct Config {
     pub twitter_client_id: String,
     pub twitter_client_secret: String,
     pub redirect_host: String,
+    // Path of the Unix socket, or `tcp://host:port` to listen on TCP.
     pub socket_path: String,
+    // Allow a TCP socket_path on an address other than loopback. Requests carry session keys in plaintext.
+    #[serde(default)]
+    pub allow_remote: bool,
+    // Defaults to $XDG_CACHE_HOME/binchotan/cache.json. Falls back to $XDG_DATA_HOME/binchotan/cache.json and then to a temporary directory if the directory is not writable.
+    #[serde(default = "Config::default_cache_path")]
     pub cache_path: String,
-    pub filter_dir: PathBuf,
+    // Passphrase to encrypt the tokens in the cache with. They are stored in plaintext if not set.
+    #[serde(default)]
+    pub cache_key: Option<String>,
+    // Directories containing filters, searched in order. A filter in an earlier directory shadows one with the same directory name in a later one.
+    #[serde(default)]
+    pub filter_dirs: Vec<PathBuf>,
+    // Deprecated. A single filter directory, searched after filter_dirs.
+    #[serde(default)]
+    pub filter_dir: Option<PathBuf>,
     pub scopes: HashSet<String>,
     pub database_url: String,
+    #[serde(default)]
+    pub strict_jsonrpc: bool,
+    // Upper bound of tweets fetched by a single v0.export.timeline request.
+    #[serde(default = "Config::default_export_max_count")]
+    pub export_max_count: usize,
+    // Global ceiling of requests per second sent to the Twitter API, shared by all accounts. Unlimited if not set.
+    #[serde(default)]
+    pub max_requests_per_second: Option<f64>,
+    // Filter profile active on startup. Every filter runs if not set.
+    #[serde(default)]
+    pub active_filter_profile: Option<String>,
+    // Append each request and its response to this JSONL file for debugging. Tokens are redacted.
+    #[serde(default)]
+    pub record_path: Option<PathBuf>,
+    // Tweets by these authors (user ids or usernames) are returned as they are, skipping every filter.
+    #[serde(default)]
+    pub filter_allowlist_authors: HashSet<String>,
+    // Pretty-print JSON-RPC responses by default. Each request can override this with `"pretty": true/false`.
+    #[serde(default)]
+    pub pretty_responses: bool,
+    // Interval in seconds of the heartbeat sent on idle streaming connections. If not set, heartbeats are only sent on connections with subscriptions, at SUBSCRIPTION_HEARTBEAT_INTERVAL.
+    #[serde(default)]
+    pub heartbeat_interval_secs: Option<u64>,
+    // Keywords muted on startup, optionally until an epoch sec. They can be changed at runtime with v0.mutes.*.
+    #[serde(default)]
+    pub mute_keywords: Vec<Mute>,
+    // Whether a tweet a filter fails on goes through the rest of the filters (skip_filter) or is dropped (drop_tweet).
+    #[serde(default)]
+    pub filter_error_policy: FilterErrorPolicy,
+    // Milliseconds a filter may run on a single tweet before it is aborted as failed.
+    #[serde(default = "Config::default_filter_timeout_ms")]
+    pub filter_timeout_ms: u64,
+    // Hosts filters can request with `http.get`/`http.post`. Filters cannot make requests if empty.
+    #[serde(default)]
+    pub filter_http_allowed_hosts: Vec<String>,
+    // Seconds a request from a filter may take.
+    #[serde(default = "Config::default_filter_http_timeout_secs")]
+    pub filter_http_timeout_secs: u64,
+    // Times a request rejected with 429 (Too Many Requests) is retried. Disabled (0) by default.
+    #[serde(default)]
+    pub max_retries: u32,
+    // Upper bound in seconds of the total wait for the retries of a request.
+    #[serde(default = "Config::default_max_retry_wait_secs")]
+    pub max_retry_wait_secs: u64,
+    // Whether a request for an endpoint whose rate limit is exhausted waits until the reset. It fails at once with the reset time if false.
+    #[serde(default = "Config::default_wait_for_rate_limit_reset")]
+    pub wait_for_rate_limit_reset: bool,
+    // Seconds a fetched timeline is reused for the same request. 0 disables the cache.
+    #[serde(default)]
+    pub timeline_cache_ttl_secs: u64,
+    // Users kept by v0.user.lookup, the least recently used being evicted first. 0 disables the cache.
+    #[serde(default = "Config::default_user_cache_size")]
+    pub user_cache_size: usize,
+    // Seconds a looked-up user is reused.
+    #[serde(default = "Config::default_user_cache_ttl_secs")]
+    pub user_cache_ttl_secs: u64,
+    // Seconds a request to the Twitter API may take, including connecting.
+    #[serde(default = "Config::default_http_timeout_secs")]
+    pub http_timeout_secs: u64,
+    // The longest request (a line of JSON-RPC) a client may send, in bytes.
+    #[serde(default = "Config::default_max_request_bytes")]
+    pub max_request_bytes: usize,
+    // Fetched tweets are deleted from the database once they have not been fetched again for this many days. Kept forever if not set.
+    #[serde(default)]
+    pub tweet_retention_days: Option<u64>,
+    // Interval in seconds of the log line summarizing the requests served so far. Not logged if not set.
+    #[serde(default)]
+    pub metrics_log_interval_secs: Option<u64>,
+    // Seconds the requests being handled may take to finish after SIGINT or SIGTERM.
+    #[serde(default = "Config::default_shutdown_grace_secs")]
+    pub shutdown_grace_secs: u64,
+    // Seconds the redirect server waits for Twitter to redirect the browser back after an authorization starts.
+    #[serde(default = "Config::default_auth_callback_timeout_secs")]
+    pub auth_callback_timeout_secs: u64,
 }
 
+// Where the config was read from before it moved to $XDG_CONFIG_HOME
+const LEGACY_CONFIG_PATH: &str = "config.toml";
+
 impl Config {
-    pub fn new() -> Result<Self, AppError> {
-        let config: Config = config::Config::builder()
-            .add_source(config::File::with_name("config.toml"))
+    /// Reads the config from `path` (given with `--config`), `BINCHOTAN_CONFIG` or `$XDG_CONFIG_HOME/binchotan/config.toml`, in this order.
+    pub fn new(path: Option<PathBuf>) -> Result<Self, AppError> {
+        let path = match path.or_else(|| explicit_config_path(env)) {
+            Some(path) => path,
+            None => {
+                let path = default_config_path(env);
+                if !path.is_file() && Path::new(LEGACY_CONFIG_PATH).is_file() {
+                    warn!(
+                        "reading ./{} in the working directory. move it to {} so that it is found from anywhere",
+                        LEGACY_CONFIG_PATH,
+                        path.display()
+                    );
+                    PathBuf::from(LEGACY_CONFIG_PATH)
+                } else {
+                    path
+                }
+            }
+        };
+        info!("using the config at {}", path.display());
+
+        let mut config: Config = config::Config::builder()
+            .add_source(config::File::from(path))
             .add_source(config::Environment::with_prefix("BINCHOTAN"))
             .build()?
             .try_deserialize()?;
+        config.resolve_filter_dirs();
+        config.validate()?;
+        config.resolve_cache_path()?;
 
         Ok(config)
     }
+
+    /// Checks the configuration before the daemon starts, so that problems show up before the user authenticates. The error names the offending field.
+    fn validate(&self) -> Result<(), AppError> {
+        let invalid = |field: &str, reason: String| -> AppError {
+            ConfigError::Message(format!("invalid `{}`: {}", field, reason)).into()
+        };
+
+        if self.twitter_client_id.trim().is_empty() {
+            return Err(invalid(
+                "twitter_client_id",
+                "must not be empty. get one from the Twitter Developer Portal".to_owned(),
+            ));
+        }
+        if self.twitter_client_secret.trim().is_empty() {
+            return Err(invalid(
+                "twitter_client_secret",
+                "must not be empty. get one from the Twitter Developer Portal".to_owned(),
+            ));
+        }
+        auth::redirect_url(&self.redirect_host)
+            .map_err(|err| invalid("redirect_host", err.to_string()))?;
+
+        if self.socket_path.trim().is_empty() {
+            return Err(invalid("socket_path", "must not be empty".to_owned()));
+        }
+        // a TCP address is checked when it is bound
+        if !self.socket_path.starts_with("tcp://") {
+            let socket_path = Path::new(&self.socket_path);
+            let socket_dir = match socket_path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            if !socket_dir.is_dir() {
+                return Err(invalid(
+                    "socket_path",
+                    format!("the directory {} does not exist", socket_dir.display()),
+                ));
+            }
+            if !is_writable(socket_path) {
+                return Err(invalid(
+                    "socket_path",
+                    format!("the directory {} is not writable", socket_dir.display()),
+                ));
+            }
+        }
+
+        if self.filter_dirs.is_empty() {
+            return Err(invalid(
+                "filter_dirs",
+                "at least one directory is required".to_owned(),
+            ));
+        }
+        if !self.filter_dirs.iter().any(|dir| dir.is_dir()) {
+            let dirs: Vec<String> = self
+                .filter_dirs
+                .iter()
+                .map(|dir| dir.display().to_string())
+                .collect();
+            return Err(invalid(
+                "filter_dirs",
+                format!("none of {} is a directory", dirs.join(", ")),
+            ));
+        }
+        for dir in self.filter_dirs.iter().filter(|dir| !dir.is_dir()) {
+            warn!("the filter directory {} does not exist", dir.display());
+        }
+        if self.scopes.is_empty() {
+            return Err(invalid(
+                "scopes",
+                "at least one scope is required, e.g. tweet.read".to_owned(),
+            ));
+        }
+        if self.tweet_retention_days == Some(0) {
+            return Err(invalid(
+                "tweet_retention_days",
+                "must be at least 1. remove it to keep the tweets forever".to_owned(),
+            ));
+        }
+        if self.http_timeout_secs == 0 {
+            return Err(invalid(
+                "http_timeout_secs",
+                "must be at least 1".to_owned(),
+            ));
+        }
+        if self.auth_callback_timeout_secs == 0 {
+            return Err(invalid(
+                "auth_callback_timeout_secs",
+                "must be at least 1".to_owned(),
+            ));
+        }
+
+        let mut scopes: Vec<&String> = self.scopes.iter().collect();
+        scopes.sort();
+        if let Some(scope) = scopes
+            .into_iter()
+            .find(|scope| !KNOWN_SCOPES.contains(&scope.as_str()))
+        {
+            return Err(invalid(
+                "scopes",
+                format!(
+                    "unknown scope {:?}. did you mean {:?}?",
+                    scope,
+                    closest_scope(scope)
+                ),
+            ));
+        }
+        self.warn_missing_scopes();
+
+        if !["postgres://", "postgresql://"]
+            .iter()
+            .any(|scheme| self.database_url.starts_with(scheme))
+        {
+            return Err(invalid(
+                "database_url",
+                "only PostgreSQL (postgres://...) is supported".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    // Methods are refused for accounts which have not granted their scopes, so point them out before anyone authorizes
+    fn warn_missing_scopes(&self) {
+        let mut missing: Vec<(&str, Vec<&str>)> = vec![];
+        for (method, scope) in METHOD_SCOPES {
+            if self.scopes.contains(*scope) {
+                continue;
+            }
+            match missing.iter_mut().find(|(s, _)| s == scope) {
+                Some((_, methods)) => methods.push(method),
+                None => missing.push((scope, vec![method])),
+            }
+        }
+        for (scope, methods) in missing {
+            warn!(
+                "{} cannot be used without the `{}` scope. add it to `scopes` if you need them",
+                methods.join(", "),
+                scope
+            );
+        }
+    }
+
+    /// Appends the deprecated `filter_dir` to `filter_dirs`.
+    fn resolve_filter_dirs(&mut self) {
+        if let Some(dir) = self.filter_dir.take() {
+            if !self.filter_dirs.is_empty() {
+                warn!(
+                    "filter_dir is deprecated. add {} to filter_dirs instead",
+                    dir.display()
+                );
+            }
+            if !self.filter_dirs.contains(&dir) {
+                self.filter_dirs.push(dir);
+            }
+        }
+    }
+
+    /// Picks a writable location for the cache, falling back from `cache_path` if needed.
+    fn resolve_cache_path(&mut self) -> Result<(), AppError> {
+        let configured = PathBuf::from(&self.cache_path);
+        let mut candidates = vec![configured.clone()];
+        if let Some(data_home) = std::env::var_os("XDG_DATA_HOME") {
+            candidates.push(PathBuf::from(data_home).join("binchotan/cache.json"));
+        }
+        candidates.push(std::env::temp_dir().join("binchotan/cache.json"));
+
+        let cache_path = first_writable(&candidates).ok_or_else(|| {
+            anyhow!(
+                "none of the cache locations is writable: {}",
+                candidates
+                    .iter()
+                    .map(|p| p.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        })?;
+        if cache_path != configured {
+            warn!(
+                "{} is not writable. the cache is stored in {} instead",
+                configured.display(),
+                cache_path.display()
+            );
+            // carry over the accounts saved so far
+            if configured.is_file() && !cache_path.exists() {
+                std::fs::copy(&configured, &cache_path)?;
+            }
+        }
+        info!("using the cache at {}", cache_path.display());
+        self.cache_path = cache_path.to_string_lossy().into_owned();
+
+        Ok(())
+    }
+
+    fn default_cache_path() -> String {
+        xdg_dir("XDG_CACHE_HOME", ".cache", env)
+            .map(|dir| dir.join("binchotan/cache.json"))
+            .unwrap_or_else(|| PathBuf::from("cache.json"))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn default_export_max_count() -> usize {
+        1000
+    }
+
+    fn default_filter_timeout_ms() -> u64 {
+        1000
+    }
+
+    fn default_filter_http_timeout_secs() -> u64 {
+        5
+    }
+
+    fn default_max_retry_wait_secs() -> u64 {
+        60
+    }
+
+    fn default_wait_for_rate_limit_reset() -> bool {
+        true
+    }
+
+    fn default_user_cache_size() -> usize {
+        1000
+    }
+
+    fn default_user_cache_ttl_secs() -> u64 {
+        3600
+    }
+
+    fn default_http_timeout_secs() -> u64 {
+        30
+    }
+
+    fn default_max_request_bytes() -> usize {
+        4 * 1024 * 1024
+    }
+
+    fn default_shutdown_grace_secs() -> u64 {
+        10
+    }
+
+    fn default_auth_callback_timeout_secs() -> u64 {
+        600
+    }
+}
+
+fn env(key: &str) -> Option<OsString> {
+    std::env::var_os(key).filter(|value| !value.is_empty())
+}
+
+/// Returns the config path given with `BINCHOTAN_CONFIG`, or `BINCHOTAN_CONFIG_FILE` as it used to be documented.
+fn explicit_config_path(env: impl Fn(&str) -> Option<OsString>) -> Option<PathBuf> {
+    env("BINCHOTAN_CONFIG")
+        .or_else(|| env("BINCHOTAN_CONFIG_FILE"))
+        .map(PathBuf::from)
+}
+
+fn default_config_path(env: impl Fn(&str) -> Option<OsString>) -> PathBuf {
+    xdg_dir("XDG_CONFIG_HOME", ".config", env)
+        .map(|dir| dir.join("binchotan/config.toml"))
+        .unwrap_or_else(|| PathBuf::from(LEGACY_CONFIG_PATH))
+}
+
+/// Resolves an XDG base directory, which falls back to `fallback` under the home directory if the variable is not set.
+fn xdg_dir(var: &str, fallback: &str, env: impl Fn(&str) -> Option<OsString>) -> Option<PathBuf> {
+    env(var)
+        .map(PathBuf::from)
+        // relative paths are invalid per the spec
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| env("HOME").map(|home| PathBuf::from(home).join(fallback)))
+}
+
+/// Returns the first path whose directory can be created and written to.
+fn first_writable(candidates: &[PathBuf]) -> Option<PathBuf> {
+    candidates.iter().find(|path| is_writable(path)).cloned()
+}
+
+fn is_writable(path: &Path) -> bool {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    if std::fs::create_dir_all(dir).is_err() {
+        return false;
+    }
+
+    let probe = dir.join(format!(".binchotan-write-test-{}", std::process::id()));
+    match std::fs::File::create(&probe) {
+        Ok(_) => std::fs::remove_file(&probe).is_ok(),
+        Err(_) => false,
+    }
+}
+
+// The known scope with the smallest edit distance
+fn closest_scope(scope: &str) -> &'static str {
+    // SAFETY: KNOWN_SCOPES is not empty
+    KNOWN_SCOPES
+        .iter()
+        .min_by_key(|known| edit_distance(scope, known))
+        .unwrap()
+}
+
+// Levenshtein distance
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = usize::from(ca != *cb);
+            cur.push((prev[j] + cost).min(prev[j + 1] + 1).min(cur[j] + 1));
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn valid_config(dir: &Path) -> Config {
+        toml::from_str(&format!(
+            r#"
+                twitter_client_id = "id"
+                twitter_client_secret = "secret"
+                redirect_host = "127.0.0.1:31337"
+                socket_path = "{dir}/binchotan.sock"
+                cache_path = "{dir}/cache.json"
+                filter_dirs = [ "{dir}" ]
+                scopes = [ "tweet.read" ]
+                database_url = "postgres://localhost/binchotan"
+            "#,
+            dir = dir.display()
+        ))
+        .unwrap()
+    }
+
+    fn assert_invalid(field: &str, break_config: impl FnOnce(&mut Config)) {
+        let mut config = valid_config(&std::env::temp_dir());
+        break_config(&mut config);
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains(&format!("`{}`", field)), "{}", err);
+    }
+
+    #[test]
+    fn name_invalid_fields() {
+        assert!(valid_config(&std::env::temp_dir()).validate().is_ok());
+
+        assert_invalid("twitter_client_id", |c| c.twitter_client_id = " ".into());
+        assert_invalid("twitter_client_secret", |c| {
+            c.twitter_client_secret = "".into()
+        });
+        assert_invalid("redirect_host", |c| c.redirect_host = "127.0.0.1".into());
+        assert_invalid("socket_path", |c| c.socket_path = "".into());
+        assert_invalid("socket_path", |c| {
+            c.socket_path = "/nonexistent/binchotan.sock".into()
+        });
+        assert_invalid("filter_dirs", |c| c.filter_dirs.clear());
+        assert_invalid("filter_dirs", |c| {
+            c.filter_dirs = vec!["/nonexistent".into()]
+        });
+        let mut config = valid_config(&std::env::temp_dir());
+        config.filter_dirs.push("/nonexistent".into());
+        assert!(config.validate().is_ok());
+        assert_invalid("scopes", |c| c.scopes.clear());
+        assert_invalid("scopes", |c| {
+            c.scopes.insert("tweet.reed".into());
+        });
+        assert_invalid("http_timeout_secs", |c| c.http_timeout_secs = 0);
+        assert_invalid("auth_callback_timeout_secs", |c| {
+            c.auth_callback_timeout_secs = 0
+        });
+        assert_invalid("tweet_retention_days", |c| c.tweet_retention_days = Some(0));
+        assert_invalid("database_url", |c| {
+            c.database_url = "sqlite://binchotan.db".into()
+        });
+    }
+
+    #[test]
+    fn accept_deprecated_filter_dir() {
+        let mut config = valid_config(&std::env::temp_dir());
+        config.filter_dirs = vec!["/personal".into()];
+        config.filter_dir = Some("/shared".into());
+        config.resolve_filter_dirs();
+        assert_eq!(
+            config.filter_dirs,
+            vec![PathBuf::from("/personal"), PathBuf::from("/shared")]
+        );
+        assert!(config.filter_dir.is_none());
+    }
+
+    #[test]
+    fn suggest_closest_scope() {
+        assert_eq!(closest_scope("tweet.reed"), "tweet.read");
+        assert_eq!(closest_scope("offline_access"), "offline.access");
+        assert_eq!(closest_scope("likes.write"), "like.write");
+
+        let mut config = valid_config(&std::env::temp_dir());
+        config.scopes.insert("tweet.reed".into());
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains(r#"did you mean "tweet.read"?"#), "{}", err);
+    }
+
+    #[test]
+    fn resolve_config_path() {
+        let vars = |pairs: &'static [(&'static str, &'static str)]| {
+            move |key: &str| {
+                pairs
+                    .iter()
+                    .find(|(k, _)| *k == key)
+                    .map(|(_, v)| OsString::from(v))
+            }
+        };
+
+        let env = vars(&[
+            ("BINCHOTAN_CONFIG", "/etc/binchotan.toml"),
+            ("BINCHOTAN_CONFIG_FILE", "/old.toml"),
+        ]);
+        assert_eq!(
+            explicit_config_path(env),
+            Some("/etc/binchotan.toml".into())
+        );
+        let env = vars(&[("BINCHOTAN_CONFIG_FILE", "/old.toml")]);
+        assert_eq!(explicit_config_path(env), Some("/old.toml".into()));
+        assert_eq!(explicit_config_path(vars(&[])), None);
+
+        let env = vars(&[("XDG_CONFIG_HOME", "/xdg"), ("HOME", "/home/me")]);
+        assert_eq!(
+            default_config_path(env),
+            PathBuf::from("/xdg/binchotan/config.toml")
+        );
+        let env = vars(&[("XDG_CONFIG_HOME", "relative"), ("HOME", "/home/me")]);
+        assert_eq!(
+            default_config_path(env),
+            PathBuf::from("/home/me/.config/binchotan/config.toml")
+        );
+        assert_eq!(
+            default_config_path(vars(&[])),
+            PathBuf::from(LEGACY_CONFIG_PATH)
+        );
+
+        let env = vars(&[("HOME", "/home/me")]);
+        assert_eq!(
+            xdg_dir("XDG_CACHE_HOME", ".cache", env),
+            Some("/home/me/.cache".into())
+        );
+    }
+
+    #[test]
+    fn fall_back_to_writable_location() {
+        let fallback = std::env::temp_dir().join("binchotan_fallback/cache.json");
+        let candidates = vec!["/proc/binchotan/cache.json".into(), fallback.clone()];
+        assert_eq!(first_writable(&candidates), Some(fallback));
+    }
 }
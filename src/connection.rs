@@ -1,22 +1,65 @@
 use crate::{
-    api::HomeTimelineResponseBody,
-    credential::CredentialStore,
+    api::{ApiClientError, HomeTimelineResponseBody, ReplySettings, TRACKED_ENDPOINTS},
+    clock,
+    credential::{AccountHealth, AccountSummary, CredentialStore, CredentialStoreError},
     error::AppError,
-    filter::{Filter, FilterError},
+    export::{self, ExportFormat},
+    filter::{
+        Filter, FilterContext, FilterError, FilterErrorPolicy, FilterHttp, FilterVerdict,
+        InlineFilter,
+    },
     methods::HttpMethod,
+    metrics::{Metrics, MetricsSnapshot},
+    mute::Mute,
+    scheduler::{ExhaustedLimit, PendingRequest, RateLimitStatus},
+    snippet::{self, SnippetInfo},
+    store::{MuteStore, ScheduledTweet, ScheduledTweetStore, TweetStore},
+    subscription::{Subscription, Subscriptions, MAX_SUBSCRIPTIONS, MIN_POLL_INTERVAL},
+    timeline_cache::{CachedTimeline, TimelineCache},
+    tweet::Tweet,
+    user_cache::UserCache,
     VERSION,
 };
+use anyhow::Context;
+use futures_util::future::join_all;
 use serde::{Deserialize, Serialize};
 use std::{
     cell::RefCell,
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
+    future::Future,
     path::PathBuf,
+    sync::Mutex,
+    time::Duration,
 };
 use thiserror::Error;
-use tracing::{info, warn};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+    time::Instant,
+};
+use tracing::{error, info, warn};
 
 pub const JSONRPC_VERSION: &str = "2.0";
 
+/// Longest search query the recent search endpoint accepts, in characters.
+const SEARCH_QUERY_MAX_LEN: usize = 512;
+
+/// Notification written to idle streaming connections so that clients and proxies know the connection is alive.
+const HEARTBEAT: &str = r#"{"jsonrpc":"2.0","method":"v0.heartbeat"}"#;
+
+/// Method of the notifications carrying the new tweets of a subscription.
+const SUBSCRIPTION_NOTIFICATION: &str = "v0.subscription";
+
+// Number of stored tweets v0.history reads unless the request says otherwise, and the most it reads at once
+const HISTORY_DEFAULT_LIMIT: usize = 100;
+const HISTORY_MAX_LIMIT: usize = 1000;
+
+// How often a subscription polls the timeline unless the request says otherwise
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Top-level members a JSON-RPC request object is allowed to have. `pretty` is our extension to pretty-print the response.
+const REQUEST_MEMBERS: [&str; 5] = ["jsonrpc", "method", "params", "id", "pretty"];
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct Request {
     pub jsonrpc: String,
@@ -25,21 +68,102 @@ pub struct Request {
     pub id: String,
 }
 
-// TODO: 'params' field should be able to be omitted (as JSON-RPC spec says) but
-// serde complains about that
-#[derive(Debug, Clone, Deserialize)]
-#[serde(tag = "method", content = "params")]
-pub enum Method {
-    #[serde(rename = "v0.plain")]
-    Plain(PlainParams),
-    #[serde(rename = "v0.home_timeline")]
-    HomeTimeline(HomeTimelineParams),
-    #[serde(rename = "v0.status")]
-    Status(#[serde(default)] EmptyParams),
-    #[serde(rename = "v0.account.list")]
-    AccountList(AccountListParams),
-    #[serde(rename = "v0.account.add")]
-    AccountAdd(AccountAddParams),
+/// Declares `Method` along with `METHOD_NAMES` and `Method::name`, so that the name of each method is written only once.
+macro_rules! methods {
+    ($($name:literal => $variant:ident($(#[$attr:meta])* $params:ty),)*) => {
+        // TODO: 'params' field should be able to be omitted (as JSON-RPC spec says) but
+        // serde complains about that
+        #[derive(Debug, Clone, Deserialize)]
+        #[serde(tag = "method", content = "params")]
+        pub enum Method {
+            $(
+                #[serde(rename = $name)]
+                $variant($(#[$attr])* $params),
+            )*
+        }
+
+        /// Names of the methods `Method` is deserialized from, which tell an unknown method apart from invalid params.
+        pub const METHOD_NAMES: &[&str] = &[$($name),*];
+
+        impl Method {
+            /// The name of the method as it is called, e.g. `v0.home_timeline`.
+            fn name(&self) -> &'static str {
+                match self {
+                    $(Method::$variant(_) => $name,)*
+                }
+            }
+        }
+    };
+}
+
+methods! {
+    "v0.plain" => Plain(PlainParams),
+    "v0.home_timeline" => HomeTimeline(HomeTimelineParams),
+    "v0.status" => Status(#[serde(default)] EmptyParams),
+    "v0.system.stats" => SystemStats(#[serde(default)] EmptyParams),
+    "v0.queue.status" => QueueStatus(#[serde(default)] EmptyParams),
+    "v0.metrics" => Metrics(#[serde(default)] EmptyParams),
+    "v0.healthcheck" => Healthcheck(HealthcheckParams),
+    "v0.ratelimit" => RateLimit(RateLimitParams),
+    "v0.account.list" => AccountList(AccountListParams),
+    "v0.account.add" => AccountAdd(AccountAddParams),
+    "v0.account.prune" => AccountPrune(AccountPruneParams),
+    "v0.account.remove" => AccountRemove(AccountRemoveParams),
+    "v0.account.scopes" => AccountScopes(AccountScopesParams),
+    "v0.filter.snippets" => FilterSnippets(#[serde(default)] EmptyParams),
+    "v0.filter.profile" => FilterProfile(FilterProfileParams),
+    "v0.export.timeline" => ExportTimeline(ExportTimelineParams),
+    "v0.user.pinned" => PinnedTweet(PinnedTweetParams),
+    "v0.tweet.create" => TweetCreate(TweetCreateParams),
+    "v0.tweet.delete" => TweetDelete(TweetDeleteParams),
+    "v0.scheduled.list" => ScheduledList(ScheduledListParams),
+    "v0.scheduled.cancel" => ScheduledCancel(ScheduledCancelParams),
+    "v0.tweet.show" => TweetShow(TweetShowParams),
+    "v0.tweet.quotes" => QuoteTweets(QuoteTweetsParams),
+    "v0.likes.list" => MyLikes(MyLikesParams),
+    "v0.mutes.list" => MutesList(#[serde(default)] EmptyParams),
+    "v0.mutes.add" => MutesAdd(Mute),
+    "v0.mutes.remove" => MutesRemove(MutesRemoveParams),
+    "v0.lists.memberships" => ListMemberships(ListMembershipsParams),
+    "v0.lists.add_member" => ListAddMember(ListMemberParams),
+    "v0.lists.remove_member" => ListRemoveMember(ListMemberParams),
+    "v0.user.lookup" => UserLookup(UserLookupParams),
+    "v0.search" => Search(SearchParams),
+    "v0.mentions" => Mentions(MentionsParams),
+    "v0.like" => Like(LikeParams),
+    "v0.unlike" => Unlike(LikeParams),
+    "v0.retweet" => Retweet(RetweetParams),
+    "v0.unretweet" => Unretweet(RetweetParams),
+    "v0.history" => History(HistoryParams),
+    "v0.subscribe" => Subscribe(SubscribeParams),
+    "v0.unsubscribe" => Unsubscribe(UnsubscribeParams),
+}
+
+// Methods which fail unless the account has granted the scope
+pub const METHOD_SCOPES: &[(&str, &str)] = &[
+    ("v0.tweet.create", "tweet.write"),
+    ("v0.tweet.delete", "tweet.write"),
+    ("v0.likes.list", "like.read"),
+    ("v0.like", "like.write"),
+    ("v0.unlike", "like.write"),
+    ("v0.retweet", "tweet.write"),
+    ("v0.unretweet", "tweet.write"),
+];
+
+impl Method {
+    /// Marks the params of a method which is rejected before dispatching as validated.
+    fn discard(&self) {
+        match self {
+            Method::Status(params)
+            | Method::SystemStats(params)
+            | Method::QueueStatus(params)
+            | Method::Metrics(params)
+            | Method::FilterSnippets(params) => {
+                params.validate();
+            }
+            _ => {}
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -56,6 +180,214 @@ pub struct HomeTimelineParams {
     session_key: String,
     #[serde(default)]
     api_params: HashMap<String, serde_json::Value>,
+    // Timezone and locale of the user, passed to filters. Defaults to UTC/en.
+    #[serde(default)]
+    context: FilterContext,
+    // `meta.next_token` of the previous page. The same as `pagination_token` in api_params
+    pagination_token: Option<String>,
+    // Replaces the installed filters for this request only, e.g. to preview filters being written.
+    filters: Option<Vec<InlineFilter>>,
+    // Runs only these installed filters (directory names or names in binchotan.toml) in the given order, regardless of the active profile. An empty list runs no filters.
+    use_filters: Option<Vec<String>>,
+    // Also return the tweets the filters dropped, to review false positives.
+    #[serde(default)]
+    include_dropped: bool,
+    // Returns every tweet along with the filter which would have dropped it, instead of the filtered timeline.
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ExportTimelineParams {
+    session_key: String,
+    // Number of tweets to fetch. Capped by `export_max_count` in the config.
+    count: usize,
+    #[serde(default)]
+    format: ExportFormat,
+    // Export tweets as they are, without running filters.
+    #[serde(default)]
+    skip_filters: bool,
+    #[serde(default)]
+    context: FilterContext,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PinnedTweetParams {
+    session_key: String,
+    // Id of the user whose pinned tweet is fetched
+    target_user_id: String,
+    #[serde(default)]
+    context: FilterContext,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TweetShowParams {
+    session_key: String,
+    tweet_id: String,
+    #[serde(default)]
+    context: FilterContext,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TweetCreateParams {
+    session_key: String,
+    text: String,
+    reply_to_tweet_id: Option<String>,
+    quote_tweet_id: Option<String>,
+    // Who can reply: "mentionedUsers" or "following". Everyone if not set.
+    reply_settings: Option<ReplySettings>,
+    // Epoch sec to post the tweet at instead of now. The tweet is queued, and posted by the daemon when it is due.
+    scheduled_at: Option<i64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScheduledListParams {
+    session_key: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScheduledCancelParams {
+    session_key: String,
+    scheduled_id: i32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TweetDeleteParams {
+    session_key: String,
+    // Tweet of the account to delete
+    tweet_id: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct QuoteTweetsParams {
+    session_key: String,
+    tweet_id: String,
+    // `meta.next_token` of the previous page
+    next_token: Option<String>,
+    #[serde(default)]
+    context: FilterContext,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MyLikesParams {
+    session_key: String,
+    // 10 to 100. The API default (100) is used if omitted
+    max_results: Option<usize>,
+    // `meta.next_token` of the previous page
+    next_token: Option<String>,
+    #[serde(default)]
+    context: FilterContext,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MutesRemoveParams {
+    keyword: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ListMembershipsParams {
+    session_key: String,
+    // User whose memberships are fetched. Defaults to the authenticating user
+    member_id: Option<String>,
+    // `meta.next_token` of the previous page
+    next_token: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ListMemberParams {
+    session_key: String,
+    // The list must be owned by the authenticating user
+    list_id: String,
+    member_id: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LikeParams {
+    session_key: String,
+    tweet_id: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RetweetParams {
+    session_key: String,
+    tweet_id: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HistoryParams {
+    session_key: String,
+    // `next_before_id` of the previous page. The newest stored tweets are read if omitted
+    before_id: Option<String>,
+    // 1 to 1000. Defaults to 100
+    limit: Option<usize>,
+    #[serde(default)]
+    context: FilterContext,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SubscribeParams {
+    session_key: String,
+    // Seconds between polls of the timeline. At least 15. Defaults to 60.
+    poll_interval_secs: Option<u64>,
+    // Only tweets newer than this are pushed. If not set, the tweets on the timeline when subscribing are not pushed.
+    since_id: Option<String>,
+    #[serde(default)]
+    context: FilterContext,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UnsubscribeParams {
+    subscription: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SearchParams {
+    session_key: String,
+    // Search query in the syntax of the Twitter API, up to 512 characters
+    query: String,
+    // 10 to 100. The API default (10) is used if omitted
+    max_results: Option<usize>,
+    // `meta.next_token` of the previous page
+    next_token: Option<String>,
+    // Passed to the API as they are, e.g. `tweet.fields`
+    #[serde(default)]
+    api_params: HashMap<String, serde_json::Value>,
+    #[serde(default)]
+    context: FilterContext,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MentionsParams {
+    session_key: String,
+    // 5 to 100. The API default (10) is used if omitted
+    max_results: Option<usize>,
+    // `meta.next_token` of the previous page
+    pagination_token: Option<String>,
+    #[serde(default)]
+    context: FilterContext,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UserLookupParams {
+    session_key: String,
+    // Any number of user ids. They are looked up 100 at a time
+    target_ids: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FilterProfileParams {
+    // Profile to activate. null deactivates profiles so that every filter runs.
+    profile: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HealthcheckParams {
+    session_key: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RateLimitParams {
+    session_key: String,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -63,6 +395,22 @@ pub struct AccountListParams {
     session_key: String,
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct AccountScopesParams {
+    session_key: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AccountPruneParams {
+    session_key: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AccountRemoveParams {
+    // Session of the account to remove
+    session_key: String,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct AccountAddParams {
     session_key: Option<String>,
@@ -102,6 +450,16 @@ pub struct Response {
     pub id: String,
 }
 
+impl Response {
+    pub fn new(content: ResponseContent, id: String) -> Self {
+        Self {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            content,
+            id,
+        }
+    }
+}
+
 #[derive(Debug, Serialize)]
 pub enum ResponseContent {
     #[serde(rename = "result")]
@@ -111,17 +469,178 @@ pub enum ResponseContent {
     },
     #[serde(rename = "result")]
     HomeTimeline {
+        meta: ResponseTimelineMeta,
+        body: HomeTimelineResponseBody,
+        // Tweets removed by the filters. Only present if `include_dropped` is set.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        dropped: Option<Vec<DroppedTweet>>,
+    },
+    #[serde(rename = "result")]
+    HomeTimelineDryRun {
+        meta: ResponseTimelineMeta,
+        // Every fetched tweet in order, unmodified by the filters.
+        dry_run: Vec<DryRunTweet>,
+        includes: Option<serde_json::Value>,
+    },
+    #[serde(rename = "result")]
+    TweetCreate {
+        meta: ResponsePlainMeta,
+        // Id of the created tweet.
+        id: String,
+        // Text of the created tweet, which Twitter may have altered (e.g. shortened URLs).
+        text: String,
+    },
+    #[serde(rename = "result")]
+    TweetSchedule {
+        // Id of the queued tweet, which v0.scheduled.cancel takes.
+        scheduled_id: i32,
+        scheduled_at: i64,
+    },
+    #[serde(rename = "result")]
+    ScheduledList {
+        // Tweets of the account waiting to be posted (or failed to), the earliest first.
+        scheduled: Vec<ScheduledTweet>,
+    },
+    #[serde(rename = "result")]
+    ScheduledCancel {
+        scheduled_id: i32,
+        // False if there was no such tweet, e.g. it has been posted already.
+        canceled: bool,
+    },
+    #[serde(rename = "result")]
+    TweetDelete {
+        meta: ResponsePlainMeta,
+        // Whether the tweet is gone. Also true if it had already been deleted.
+        deleted: bool,
+    },
+    #[serde(rename = "result")]
+    QuoteTweets {
+        meta: ResponseTimelineMeta,
+        // `meta.next_token` in the body points to the next page.
+        body: HomeTimelineResponseBody,
+    },
+    #[serde(rename = "result")]
+    MyLikes {
+        meta: ResponseTimelineMeta,
+        // `meta.next_token` in the body points to the next page.
+        body: HomeTimelineResponseBody,
+    },
+    #[serde(rename = "result")]
+    Mutes {
+        // Mutes in effect after the request. Expired ones are removed.
+        mutes: Vec<Mute>,
+    },
+    #[serde(rename = "result")]
+    ListMemberships {
+        meta: ResponsePlainMeta,
+        // Response from the Twitter API as it is. `meta.next_token` points to the next page.
+        body: serde_json::Value,
+    },
+    #[serde(rename = "result")]
+    ListMember {
+        meta: ResponsePlainMeta,
+        list_id: String,
+        member_id: String,
+        // Whether the user is a member of the list after the request.
+        is_member: bool,
+    },
+    #[serde(rename = "result")]
+    Like {
+        meta: ResponsePlainMeta,
+        tweet_id: String,
+        // Whether the account likes the tweet after the request.
+        liked: bool,
+    },
+    #[serde(rename = "result")]
+    History {
+        // Stored tweets which passed the filters, newest first.
+        data: Vec<Tweet>,
+        // Pass this as `before_id` to read older tweets. null if no older tweets are stored.
+        next_before_id: Option<String>,
+        // Filters which failed on some of the tweets.
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        filter_errors: Vec<FilterFailure>,
+    },
+    #[serde(rename = "result")]
+    Subscribe {
+        // Id of the subscription, which the notifications carry and v0.unsubscribe takes.
+        subscription: String,
+        poll_interval_secs: u64,
+    },
+    #[serde(rename = "result")]
+    Unsubscribe { subscription: String },
+    #[serde(rename = "result")]
+    Retweet {
         meta: ResponsePlainMeta,
+        tweet_id: String,
+        // Whether the account retweets the tweet after the request.
+        retweeted: bool,
+    },
+    #[serde(rename = "result")]
+    Search {
+        meta: ResponseTimelineMeta,
+        // `meta.next_token` in the body points to the next page.
+        body: HomeTimelineResponseBody,
+    },
+    #[serde(rename = "result")]
+    Mentions {
+        meta: ResponseTimelineMeta,
+        // `meta.next_token` in the body points to the next page.
         body: HomeTimelineResponseBody,
     },
     #[serde(rename = "result")]
-    Status { version: String },
+    UserLookup {
+        meta: ResponsePlainMeta,
+        // User objects with id, username, name and profile_image_url
+        users: Vec<serde_json::Value>,
+        // `errors` of the API, one for each id which could not be resolved (e.g. suspended users)
+        errors: Vec<serde_json::Value>,
+    },
+    #[serde(rename = "result")]
+    Status {
+        version: String,
+        // Seconds since the daemon started.
+        uptime_secs: u64,
+        // null if the database could not be reached
+        account_count: Option<i64>,
+        // Accounts whose tokens were valid (or expired) the last time they were used. Accounts not used since the start are in neither.
+        valid_accounts: usize,
+        expired_accounts: usize,
+    },
+    #[serde(rename = "result")]
+    SystemStats {
+        // Number of API requests waiting for the scheduler.
+        queue_depth: usize,
+        // Global ceiling of API requests per second. null if unlimited.
+        max_requests_per_second: Option<f64>,
+    },
+    #[serde(rename = "result")]
+    QueueStatus {
+        // API requests waiting for the scheduler, oldest first.
+        pending_requests: Vec<PendingRequest>,
+        // Rate limits exhausted per account and endpoint, the earliest reset first.
+        exhausted_limits: Vec<ExhaustedLimit>,
+        // Scheduled tweets of every account waiting to be posted. null if the database could not be reached.
+        scheduled_tweets: Option<i64>,
+    },
+    #[serde(rename = "result")]
+    RateLimit {
+        // Endpoint -> the rate limit reported by its last response. null if the account has not called it yet.
+        limits: BTreeMap<String, Option<RateLimitStatus>>,
+    },
+    #[serde(rename = "result")]
+    Healthcheck {
+        // Reports for the owner account and accounts it owns.
+        accounts: Vec<AccountHealth>,
+    },
     #[serde(rename = "result")]
     AccountList {
         // Account id which the user used for authorization.
         owner: String,
         // Session keys for the owner account and accounts it owns.
         session_keys: HashMap<String, String>,
+        // The same accounts sorted by the time they were last used, most recent first.
+        accounts: Vec<AccountSummary>,
     },
     #[serde(rename = "result")]
     AccountAdd {
@@ -131,16 +650,154 @@ pub enum ResponseContent {
         // once the user has authenciated on Twitter and the redirect server receives an access token.
         session_key: String,
     },
+    #[serde(rename = "result")]
+    AccountScopes {
+        twitter_id: String,
+        // Scopes granted to the token. null if they were not recorded when the account was added.
+        scopes: Option<Vec<String>>,
+    },
+    #[serde(rename = "result")]
+    AccountPrune {
+        // Twitter ids of the accounts removed because their tokens could not be refreshed.
+        removed: Vec<String>,
+    },
+    #[serde(rename = "result")]
+    AccountRemove {
+        twitter_id: String,
+        removed: bool,
+        // Why the tokens could not be revoked. The account is removed anyway.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        revocation_error: Option<String>,
+    },
+    #[serde(rename = "result")]
+    ExportTimeline {
+        format: ExportFormat,
+        // Number of exported tweets.
+        count: usize,
+        // Exported tweets serialized in `format`. Omitted when the tweets were streamed (NDJSON).
+        #[serde(skip_serializing_if = "Option::is_none")]
+        document: Option<String>,
+    },
+    #[serde(rename = "result")]
+    PinnedTweet {
+        meta: ResponsePlainMeta,
+        // The pinned tweet after the filters ran. null if the user has no pinned tweet or a filter dropped it.
+        tweet: Option<Tweet>,
+    },
+    #[serde(rename = "result")]
+    TweetShow {
+        meta: ResponsePlainMeta,
+        // The tweet after the filters ran (the first one, if a filter expanded it into several). null if a filter dropped it.
+        tweet: Option<Tweet>,
+        // The filter which dropped the tweet, e.g. `no_ads` or `mute:keyword`. null unless the tweet was dropped.
+        dropped_by: Option<String>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        filter_errors: Vec<FilterFailure>,
+    },
+    #[serde(rename = "result")]
+    FilterProfile {
+        // The profile which is active now.
+        profile: Option<String>,
+    },
+    #[serde(rename = "result")]
+    FilterSnippets {
+        // Helper modules which filters can load with `require`.
+        snippets: Vec<SnippetInfo>,
+    },
+    // Requests counted so far. The v0.metrics request itself is counted after its response is built, so the snapshot does not include it.
+    #[serde(rename = "result")]
+    Metrics(MetricsSnapshot),
     #[serde(rename = "error")]
     Error(ResponseError),
 }
 
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum BatchResponse {
+    Responses(Vec<Response>),
+    // The batch itself was invalid (e.g. empty)
+    Invalid(Box<Response>),
+}
+
 #[derive(Debug, Serialize)]
 pub struct ResponsePlainMeta {
     pub api_calls_remaining: usize,
     pub api_calls_reset: usize, // in epoch sec
 }
 
+#[derive(Debug, Serialize)]
+pub struct ResponseTimelineMeta {
+    pub api_calls_remaining: usize,
+    pub api_calls_reset: usize, // in epoch sec
+    // Number of tweets by allowlisted authors, which skipped the filters.
+    pub filters_bypassed: usize,
+    // Present when some details of the tweets were left out, e.g. expansions not allowed by the access tier.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+    // Pass this as `pagination_token` (or `next_token`) to get the next page. null on the last page.
+    pub next_token: Option<String>,
+    // Filters which failed on some of the tweets. The rest of the timeline is returned anyway.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub filter_errors: Vec<FilterFailure>,
+    // Whether the tweets came from the timeline cache instead of the API. Absent if the cache is disabled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_hit: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FilterFailure {
+    pub filter: String,
+    // The first error the filter raised
+    pub error: String,
+    // Number of tweets the filter failed on
+    pub count: usize,
+}
+
+/// A JSON-RPC notification (without an id) pushed by the backend.
+#[derive(Debug, Serialize)]
+pub struct Notification<T> {
+    pub jsonrpc: String,
+    pub method: &'static str,
+    pub params: T,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SubscriptionUpdate {
+    pub subscription: String,
+    #[serde(flatten)]
+    pub content: SubscriptionContent,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum SubscriptionContent {
+    // New tweets which passed the filters, newest first
+    Tweets {
+        meta: ResponseTimelineMeta,
+        body: HomeTimelineResponseBody,
+    },
+    // A poll failed. `ended` is true if the subscription was ended because of it, e.g. the account was removed.
+    Error {
+        error: ResponseError,
+        ended: bool,
+    },
+}
+
+#[derive(Debug, Serialize)]
+pub struct DryRunTweet {
+    // Name of the first filter which would have dropped the tweet, or null if it passed
+    pub dropped_by: Option<String>,
+    pub tweet: Tweet,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DroppedTweet {
+    // Name of the filter which returned null for the tweet (or failed on it, under the drop_tweet policy)
+    pub filter: String,
+    // The tweet as fetched (or as a filter expanded it into), before any filter modified it
+    pub tweet: Tweet,
+}
+
 #[derive(Debug, Serialize)]
 pub struct ResponseError {
     pub code: isize,
@@ -171,9 +828,12 @@ impl From<RpcError> for isize {
 }
 
 enum RpcServerError {
+    #[allow(dead_code)]
     Api,
     ApiStatus,
     Lua,
+    RateLimited,
+    Network,
     Other,
 }
 
@@ -183,6 +843,8 @@ impl From<RpcServerError> for isize {
             RpcServerError::Api => -32000,
             RpcServerError::ApiStatus => -32001,
             RpcServerError::Lua => -32002,
+            RpcServerError::RateLimited => -32003,
+            RpcServerError::Network => -32004,
             RpcServerError::Other => -32099,
         }
     }
@@ -196,13 +858,32 @@ impl From<AppError> for ResponseError {
             AppError::Listener(_) => unreachable!(),
             AppError::CacheManager(_) => RpcError::Server(RpcServerError::Other),
             AppError::CredentialStore(_) => RpcError::Server(RpcServerError::Other),
+            AppError::TweetStore(_) => RpcError::Server(RpcServerError::Other),
             AppError::Auth(_) => RpcError::Server(RpcServerError::Other),
-            AppError::ApiClient(_) => RpcError::Server(RpcServerError::Other),
+            AppError::ApiClient(ApiClientError::RateLimited { .. }) => {
+                RpcError::Server(RpcServerError::RateLimited)
+            }
+            AppError::ApiClient(ref e) if e.is_network() => {
+                RpcError::Server(RpcServerError::Network)
+            }
+            AppError::ApiClient(ApiClientError::TweetNotFound(_)) => {
+                RpcError::Server(RpcServerError::ApiStatus)
+            }
+            AppError::ApiClient(_) => RpcError::Server(RpcServerError::Other),
             AppError::Handler(ref e) => match e {
                 HandlerError::ParamsParse(_) => RpcError::Parse,
                 HandlerError::Version => RpcError::InvalidRequest,
+                HandlerError::UnknownMember(_) => RpcError::InvalidRequest,
+                HandlerError::EmptyBatch => RpcError::InvalidRequest,
+                HandlerError::InvalidRequest(_) => RpcError::InvalidRequest,
+                HandlerError::MethodNotFound(_) => RpcError::MethodNotFound,
+                HandlerError::SubscriptionInBatch => RpcError::InvalidRequest,
+                HandlerError::TooManySubscriptions(_) => RpcError::Server(RpcServerError::Other),
+                HandlerError::Parse(_) => RpcError::Parse,
                 HandlerError::UnknownAccount(_) => RpcError::InvalidParams,
                 HandlerError::ParamsMismatch(_) => RpcError::InvalidParams,
+                HandlerError::MissingScope(_) => RpcError::Server(RpcServerError::Other),
+                HandlerError::TooLarge(_) => RpcError::Internal,
             },
             AppError::Filter(ref e) => match e {
                 FilterError::PathNotDir(_) => RpcError::Server(RpcServerError::Other),
@@ -220,50 +901,336 @@ impl From<AppError> for ResponseError {
         ResponseError {
             code,
             message: err.to_string(),
-            data: None,
+            data: error_data(&err),
         }
     }
 }
 
+/// Machine-readable details of the error, so that clients need not parse the message.
+fn error_data(err: &AppError) -> Option<serde_json::Value> {
+    let data = match err {
+        // tell the client when it can try again
+        AppError::ApiClient(ApiClientError::RateLimited { endpoint, reset }) => {
+            serde_json::json!({ "endpoint": endpoint, "reset": reset, "retryable": true })
+        }
+        // the frontend can show that it is offline and retry, rather than give up
+        AppError::ApiClient(e) if e.is_network() => serde_json::json!({ "retryable": true }),
+        AppError::ApiClient(ApiClientError::RespStatus(status, body)) => {
+            // Twitter answers errors in JSON, but keep the body as it is otherwise
+            let body = serde_json::from_str(body)
+                .unwrap_or_else(|_| serde_json::Value::String(body.clone()));
+            serde_json::json!({ "status": status, "body": body, "retryable": false })
+        }
+        // sending the same request again fails in the same way
+        AppError::ApiClient(
+            ApiClientError::NotOwnTweet(tweet_id) | ApiClientError::TweetNotFound(tweet_id),
+        ) => {
+            serde_json::json!({ "tweet_id": tweet_id, "retryable": false })
+        }
+        AppError::CredentialStore(CredentialStoreError::UnknownAccount(session_key))
+        | AppError::Handler(HandlerError::UnknownAccount(session_key)) => {
+            serde_json::json!({ "session_key": session_key })
+        }
+        AppError::CredentialStore(CredentialStoreError::Revoked(twitter_id)) => {
+            serde_json::json!({ "twitter_id": twitter_id })
+        }
+        // `handle` adds the method
+        AppError::Handler(HandlerError::ParamsMismatch(id)) => serde_json::json!({ "id": id }),
+        AppError::Handler(HandlerError::MissingScope(scope)) => {
+            serde_json::json!({ "scope": scope })
+        }
+        AppError::Handler(HandlerError::UnknownMember(member)) => {
+            serde_json::json!({ "member": member })
+        }
+        AppError::Handler(HandlerError::TooLarge(max)) => {
+            serde_json::json!({ "max_request_bytes": max })
+        }
+        AppError::Handler(HandlerError::TooManySubscriptions(max)) => {
+            serde_json::json!({ "max_subscriptions": max })
+        }
+        AppError::Handler(HandlerError::MethodNotFound(method)) => {
+            serde_json::json!({ "method": method })
+        }
+        _ => return None,
+    };
+
+    Some(data)
+}
+
 #[derive(Debug, Error)]
 pub enum HandlerError {
     #[error("could not parse the parameters in the JSON-RPC request: {0}")]
     ParamsParse(serde_json::Error),
     #[error("incompatible JSON-RPC version. use 2.0 instead")]
     Version,
+    #[error("unknown member `{0}` in the JSON-RPC request")]
+    UnknownMember(String),
+    #[error("the batch has no requests")]
+    EmptyBatch,
+    #[error("invalid JSON-RPC request: {0}")]
+    InvalidRequest(serde_json::Error),
+    #[error("unknown method `{0}`")]
+    MethodNotFound(String),
+    #[error(
+        "subscriptions can only be made by a request of its own on a connection, not in a batch"
+    )]
+    SubscriptionInBatch,
+    #[error("a connection can have up to {0} subscriptions. end one with v0.unsubscribe first")]
+    TooManySubscriptions(usize),
+    #[error("could not parse the payload as JSON: {0}")]
+    Parse(serde_json::Error),
     #[error("unregistered user id: {0}")]
     UnknownAccount(String),
     #[error("wrong parameters in request (id = {0})")]
     ParamsMismatch(String),
+    #[error("the request is longer than max_request_bytes ({0} bytes)")]
+    TooLarge(usize),
+    #[error("the account has not granted the `{0}` scope. add it to `scopes` in your config and authorize the account again")]
+    MissingScope(String),
 }
 
 pub struct Handler {
     pub store: CredentialStore,
-    pub filter_path: PathBuf,
+    // Fetched timelines are saved here
+    pub tweet_store: Box<dyn TweetStore>,
+    // Tweets queued by v0.tweet.create with scheduled_at
+    pub scheduled_tweets: ScheduledTweetStore,
+    // Searched in order. See Filter::load
+    pub filter_dirs: Vec<PathBuf>,
     pub scopes: HashSet<String>,
+    // Reject requests with unknown top-level members instead of ignoring them.
+    pub strict_jsonrpc: bool,
+    pub export_max_count: usize,
+    // Only filters belonging to this profile (and ones without profiles) run.
+    pub active_filter_profile: Mutex<Option<String>>,
+    // Tweets by these authors (ids or usernames) skip every filter.
+    pub filter_allowlist_authors: HashSet<String>,
+    // Pretty-print responses unless the request says `"pretty": false`.
+    pub pretty_responses: bool,
+    // Send a heartbeat on streaming connections which have been idle this long. Disabled if None, except on connections with subscriptions (see SUBSCRIPTION_HEARTBEAT_INTERVAL).
+    pub heartbeat_interval: Option<Duration>,
+    // Tweets containing these keywords are dropped before the filters run.
+    pub mutes: Mutex<Vec<Mute>>,
+    // Mutes added and removed at runtime are saved here
+    pub mute_store: MuteStore,
+    pub filter_error_policy: FilterErrorPolicy,
+    // A filter is aborted once it runs this long on a single tweet.
+    pub filter_timeout: Duration,
+    // Client for `http.get`/`http.post` in filters
+    pub filter_http: FilterHttp,
+    // Timelines fetched within the TTL, which repeated requests are answered from
+    pub timeline_cache: TimelineCache,
+    // Requests served so far, for v0.metrics
+    pub metrics: Metrics,
+    // Users resolved by v0.user.lookup recently
+    pub user_cache: UserCache,
+    pub started_at: std::time::Instant,
 }
 
 impl Handler {
-    pub async fn handle(&mut self, req: Request) -> Response {
+    pub async fn handle(&self, req: Request) -> Response {
+        let method = req.method.name();
+        let resp = self.handle_uncounted(req).await;
+        self.count(Some(method), &resp);
+        resp
+    }
+
+    // Counts the request in the metrics by the response to it
+    fn count(&self, method: Option<&'static str>, resp: &Response) {
+        let error_code = match &resp.content {
+            ResponseContent::Error(err) => Some(err.code),
+            _ => None,
+        };
+        self.metrics.record(method, error_code);
+    }
+
+    async fn handle_uncounted(&self, req: Request) -> Response {
         let id = req.id.clone();
+        let method = req.method.name();
         match self.handle_inner(req).await {
             Ok(resp) => resp,
-            Err(err) => {
-                warn!("something bad happened: {:?}", err);
-                let resp_err: ResponseError = err.into();
-                Response {
-                    jsonrpc: JSONRPC_VERSION.to_string(),
-                    content: ResponseContent::Error(resp_err),
-                    id,
+            Err(err) => Self::method_error_response(id, method, err),
+        }
+    }
+
+    // Like error_response, but tells which method the parameters did not match
+    fn method_error_response(id: String, method: &'static str, err: AppError) -> Response {
+        let mismatch = matches!(err, AppError::Handler(HandlerError::ParamsMismatch(_)));
+        let mut resp = Self::error_response(id, err);
+        if let ResponseContent::Error(ResponseError {
+            data: Some(serde_json::Value::Object(data)),
+            ..
+        }) = &mut resp.content
+        {
+            if mismatch {
+                data.insert("method".to_owned(), method.into());
+            }
+        }
+        resp
+    }
+
+    /// In strict mode, returns an error response if the raw request has a top-level member other than `jsonrpc`, `method`, `params` and `id`.
+    pub fn check_members(&self, value: &serde_json::Value) -> Option<Response> {
+        if !self.strict_jsonrpc {
+            return None;
+        }
+
+        let member = value
+            .as_object()?
+            .keys()
+            .find(|k| !REQUEST_MEMBERS.contains(&k.as_str()))?;
+        let id = value["id"].as_str().unwrap_or_default().to_owned();
+        Some(Self::error_response(
+            id,
+            HandlerError::UnknownMember(member.clone()).into(),
+        ))
+    }
+
+    /// Handles a batch of requests concurrently. The responses are in the same order as the requests regardless of which finishes first, and a broken request only turns its own response into an error. An empty batch is answered with a single error response, not an array.
+    pub async fn handle_batch(&self, values: &[serde_json::Value]) -> BatchResponse {
+        if values.is_empty() {
+            return BatchResponse::Invalid(Box::new(Self::error_response(
+                String::new(),
+                HandlerError::EmptyBatch.into(),
+            )));
+        }
+
+        // the requests run concurrently, and join_all keeps the responses in the order of the requests
+        let resps = join_all(values.iter().map(|value| async move {
+            match self.parse_request(value) {
+                Ok(req) => self.handle(req).await,
+                Err(resp) => *resp,
+            }
+        }))
+        .await;
+
+        BatchResponse::Responses(resps)
+    }
+
+    /// Turns a raw request into a `Request`, or into the error response to send back if it is not a valid one.
+    pub fn parse_request(&self, value: &serde_json::Value) -> Result<Request, Box<Response>> {
+        if let Some(resp) = self.check_members(value) {
+            return Err(Box::new(resp));
+        }
+
+        serde_json::from_value::<Request>(value.clone()).map_err(|err| {
+            let id = value["id"].as_str().unwrap_or_default().to_owned();
+            let method = value["method"].as_str().map(|method| {
+                METHOD_NAMES
+                    .iter()
+                    .find(|name| **name == method)
+                    .ok_or(method)
+            });
+            let envelope_valid = value["jsonrpc"].is_string() && value["id"].is_string();
+            let resp = match method {
+                Some(Err(method)) => {
+                    Self::error_response(id, HandlerError::MethodNotFound(method.to_owned()).into())
                 }
+                // the method is known and the rest of the request is well-formed, so the params are what is invalid
+                Some(Ok(method)) if envelope_valid => Self::method_error_response(
+                    id.clone(),
+                    method,
+                    HandlerError::ParamsMismatch(id).into(),
+                ),
+                _ => Self::error_response(id, HandlerError::InvalidRequest(err).into()),
+            };
+            self.count(None, &resp);
+            Box::new(resp)
+        })
+    }
+
+    /// Returns the response to a payload which is not even JSON. It has no id since none could be read.
+    pub fn parse_error(err: serde_json::Error) -> Response {
+        Self::error_response(String::new(), HandlerError::Parse(err).into())
+    }
+
+    /// Returns the response to a request longer than `max_request_bytes`. It has no id since the request was not read.
+    pub fn too_large(max_request_bytes: usize) -> Response {
+        Self::error_response(
+            String::new(),
+            HandlerError::TooLarge(max_request_bytes).into(),
+        )
+    }
+
+    /// Serializes the response (or the responses to a batch) for the raw request, pretty-printed if the request has `"pretty": true` or pretty_responses is enabled.
+    pub fn render<T: Serialize>(&self, req: &serde_json::Value, resp: &T) -> String {
+        let pretty = req["pretty"].as_bool().unwrap_or(self.pretty_responses);
+        // SAFETY: Response is serde::Serialize so it should always be able to be serialized
+        if pretty {
+            let mut buf = Vec::new();
+            resp.serialize(&mut serde_json::Serializer::with_formatter(
+                &mut buf,
+                SpacedFormatter,
+            ))
+            .unwrap();
+            // SAFETY: serde_json only writes UTF-8
+            String::from_utf8(buf).unwrap()
+        } else {
+            serde_json::to_string(resp).unwrap()
+        }
+    }
+
+    fn error_response(id: String, err: AppError) -> Response {
+        warn!("something bad happened: {:?}", err);
+        let resp_err: ResponseError = err.into();
+        Response::new(ResponseContent::Error(resp_err), id)
+    }
+
+    /// Handles the request like `handle`, except that a v0.export.timeline request in the NDJSON format writes each tweet to `out` as a line while it is being exported. The returned response terminates the stream.
+    pub async fn handle_streaming(
+        &self,
+        req: Request,
+        out: &mut (dyn AsyncWrite + Send + Unpin),
+        subscriptions: &mut Subscriptions,
+    ) -> Response {
+        match req.method {
+            Method::ExportTimeline(params)
+                if params.format == ExportFormat::Ndjson && req.jsonrpc == JSONRPC_VERSION =>
+            {
+                info!("received a streaming request: {:?}", params);
+                let id = req.id;
+                let resp = match self.stream_export_timeline(id.clone(), params, out).await {
+                    Ok(resp) => resp,
+                    Err(err) => Self::error_response(id, err),
+                };
+                self.count(Some("v0.export.timeline"), &resp);
+                resp
             }
+            Method::Subscribe(params) if req.jsonrpc == JSONRPC_VERSION => {
+                let id = req.id;
+                let resp = match self
+                    .handle_subscribe(id.clone(), params, subscriptions)
+                    .await
+                {
+                    Ok(resp) => resp,
+                    Err(err) => Self::method_error_response(id, "v0.subscribe", err),
+                };
+                self.count(Some("v0.subscribe"), &resp);
+                resp
+            }
+            Method::Unsubscribe(params) if req.jsonrpc == JSONRPC_VERSION => {
+                let id = req.id;
+                let UnsubscribeParams { subscription } = params;
+                let resp = if subscriptions.remove(&subscription) {
+                    info!("ended the subscription {}", subscription);
+                    Response::new(ResponseContent::Unsubscribe { subscription }, id)
+                } else {
+                    let err = HandlerError::ParamsMismatch(id.clone()).into();
+                    Self::method_error_response(id, "v0.unsubscribe", err)
+                };
+                self.count(Some("v0.unsubscribe"), &resp);
+                resp
+            }
+            _ => self.handle(req).await,
         }
     }
 
-    async fn handle_inner(&mut self, req: Request) -> Result<Response, AppError> {
+    async fn handle_inner(&self, req: Request) -> Result<Response, AppError> {
         info!("received a request: {:?}", req);
 
         if req.jsonrpc.as_str() != JSONRPC_VERSION {
+            // the request never reaches its handler, so empty params have to be settled here
+            req.method.discard();
             return Err(HandlerError::Version.into());
         }
 
@@ -271,8 +1238,47 @@ impl Handler {
             Method::Plain(params) => self.handle_plain(req.id, params).await?,
             Method::HomeTimeline(params) => self.handle_timeline(req.id, params).await?,
             Method::Status(params) => self.handle_status(req.id, params).await?,
+            Method::SystemStats(params) => self.handle_system_stats(req.id, params).await?,
+            Method::QueueStatus(params) => self.handle_queue_status(req.id, params).await?,
+            Method::Metrics(params) => self.handle_metrics(req.id, params)?,
+            Method::Healthcheck(params) => self.handle_healthcheck(req.id, params).await?,
+            Method::RateLimit(params) => self.handle_rate_limit(req.id, params).await?,
             Method::AccountList(params) => self.handle_account_list(req.id, params).await?,
             Method::AccountAdd(params) => self.handle_account_add(req.id, params).await?,
+            Method::AccountPrune(params) => self.handle_account_prune(req.id, params).await?,
+            Method::AccountRemove(params) => self.handle_account_remove(req.id, params).await?,
+            Method::AccountScopes(params) => self.handle_account_scopes(req.id, params).await?,
+            Method::FilterSnippets(params) => self.handle_filter_snippets(req.id, params).await?,
+            Method::FilterProfile(params) => self.handle_filter_profile(req.id, params).await?,
+            Method::ExportTimeline(params) => self.handle_export_timeline(req.id, params).await?,
+            Method::PinnedTweet(params) => self.handle_pinned_tweet(req.id, params).await?,
+            Method::TweetShow(params) => self.handle_tweet_show(req.id, params).await?,
+            Method::TweetCreate(params) => self.handle_tweet_create(req.id, params).await?,
+            Method::TweetDelete(params) => self.handle_tweet_delete(req.id, params).await?,
+            Method::ScheduledList(params) => self.handle_scheduled_list(req.id, params).await?,
+            Method::ScheduledCancel(params) => self.handle_scheduled_cancel(req.id, params).await?,
+            Method::QuoteTweets(params) => self.handle_quote_tweets(req.id, params).await?,
+            Method::MyLikes(params) => self.handle_my_likes(req.id, params).await?,
+            Method::MutesList(params) => self.handle_mutes_list(req.id, params).await?,
+            Method::MutesAdd(params) => self.handle_mutes_add(req.id, params).await?,
+            Method::MutesRemove(params) => self.handle_mutes_remove(req.id, params).await?,
+            Method::ListMemberships(params) => self.handle_list_memberships(req.id, params).await?,
+            Method::ListAddMember(params) => self.handle_list_member(req.id, params, true).await?,
+            Method::ListRemoveMember(params) => {
+                self.handle_list_member(req.id, params, false).await?
+            }
+            Method::UserLookup(params) => self.handle_user_lookup(req.id, params).await?,
+            Method::Search(params) => self.handle_search(req.id, params).await?,
+            Method::Mentions(params) => self.handle_mentions(req.id, params).await?,
+            Method::Like(params) => self.handle_like(req.id, params, true).await?,
+            Method::Unlike(params) => self.handle_like(req.id, params, false).await?,
+            Method::Retweet(params) => self.handle_retweet(req.id, params, true).await?,
+            Method::Unretweet(params) => self.handle_retweet(req.id, params, false).await?,
+            Method::History(params) => self.handle_history(req.id, params).await?,
+            // they belong to a connection, so handle_streaming takes them
+            Method::Subscribe(_) | Method::Unsubscribe(_) => {
+                return Err(HandlerError::SubscriptionInBatch.into())
+            }
         };
 
         Ok(resp)
@@ -298,11 +1304,7 @@ impl Handler {
             },
             body: resp,
         };
-        Ok(Response {
-            jsonrpc: JSONRPC_VERSION.to_string(),
-            content,
-            id,
-        })
+        Ok(Response::new(content, id))
     }
 
     async fn handle_timeline(
@@ -313,53 +1315,146 @@ impl Handler {
         let HomeTimelineParams {
             session_key,
             mut api_params,
+            context,
+            pagination_token,
+            filters,
+            use_filters,
+            include_dropped,
+            dry_run,
         } = params;
+        if let Some(token) = pagination_token {
+            api_params.insert("pagination_token".to_owned(), token.into());
+        }
+        // these are passed through to the API, so anything other than a tweet id is rejected here
+        let bounds_valid = ["since_id", "until_id"].iter().all(|key| {
+            api_params
+                .get(*key)
+                .is_none_or(|value| value.as_str().is_some_and(is_tweet_id))
+        });
+        if !bounds_valid {
+            return Err(HandlerError::ParamsMismatch(id).into());
+        }
+        // inline filters are compiled before the API is called so that mistakes in them fail fast
+        let filters = match (filters, use_filters) {
+            (Some(_), Some(_)) => return Err(HandlerError::ParamsMismatch(id).into()),
+            (Some(filters), None) => filters
+                .into_iter()
+                .enumerate()
+                .map(|(i, filter)| Filter::inline(i, filter))
+                .collect::<Result<Vec<_>, _>>()?,
+            (None, Some(names)) => match self.select_filters(&names)? {
+                Some(filters) => filters,
+                None => return Err(HandlerError::ParamsMismatch(id).into()),
+            },
+            (None, None) => self.load_filters()?,
+        };
 
         let client = self.store.client_for(&session_key).await?;
-        let (
-            HomeTimelineResponseBody {
-                data: tweets,
-                includes,
-                meta,
-            },
+        let context = context.for_account(&client.user_id, "home_timeline");
+        let cache_key = TimelineCache::key(&client.user_id, "timeline", &api_params);
+        let (fetched, cache_hit) = match self.timeline_cache.get(&cache_key) {
+            Some(cached) => (cached, true),
+            None => {
+                let (body, remaining, reset) = client.timeline(&mut api_params).await?;
+                info!(
+                    "successfully retrieved {} tweets (reverse_chronological)",
+                    body.data.len(),
+                );
+                // the tweets are saved before the filters change them. a failure here should not cost the user the timeline
+                if let Err(err) = self.tweet_store.upsert(&client.user_id, &body.data).await {
+                    warn!("could not save the fetched tweets: {}", err);
+                }
+                let fetched = CachedTimeline {
+                    body,
+                    remaining,
+                    reset,
+                };
+                self.timeline_cache.insert(cache_key, fetched.clone());
+                (fetched, false)
+            }
+        };
+        let content = if dry_run {
+            self.dry_run_content(fetched, &filters, &context, cache_hit)
+        } else {
+            self.home_timeline_content(fetched, &filters, &context, include_dropped, cache_hit)
+        };
+        Ok(Response::new(content, id))
+    }
+
+    /// Runs the filters over a fetched home timeline and builds the result. An empty timeline (e.g. no tweets newer than `since_id`) results in an empty `data`.
+    pub(crate) fn home_timeline_content(
+        &self,
+        fetched: CachedTimeline,
+        filters: &[Filter],
+        context: &FilterContext,
+        include_dropped: bool,
+        cache_hit: bool,
+    ) -> ResponseContent {
+        let CachedTimeline {
+            body,
             remaining,
             reset,
-        ) = client.timeline(&mut api_params).await?;
-        info!(
-            "successfully retrieved {} tweets (reverse_chronological)",
-            tweets.len(),
-        );
+        } = fetched;
+        let warnings = Self::warnings_for(&body);
+        let (body, outcome) = self.filter_body(body, filters, context);
+
+        ResponseContent::HomeTimeline {
+            meta: ResponseTimelineMeta {
+                api_calls_remaining: remaining,
+                api_calls_reset: reset,
+                filters_bypassed: outcome.bypassed,
+                warnings,
+                next_token: body.next_token(),
+                filter_errors: outcome.errors,
+                cache_hit: self.timeline_cache.is_enabled().then_some(cache_hit),
+            },
+            body,
+            dropped: include_dropped.then_some(outcome.dropped),
+        }
+    }
 
-        let filters = Filter::load(self.filter_path.as_ref(), &self.scopes)?;
+    /// Runs the filters over a fetched home timeline without dropping anything, reporting which filter would have dropped each tweet.
+    pub(crate) fn dry_run_content(
+        &self,
+        fetched: CachedTimeline,
+        filters: &[Filter],
+        context: &FilterContext,
+        cache_hit: bool,
+    ) -> ResponseContent {
+        let CachedTimeline {
+            body,
+            remaining,
+            reset,
+        } = fetched;
+        let warnings = Self::warnings_for(&body);
+        let next_token = body.next_token();
 
-        let mut filtered_tweets = vec![];
-        'outer: for tweet in tweets {
-            let mut result = tweet;
-            for filter in &filters {
-                match filter.run(&result)? {
-                    Some(t) => result = t,
-                    None => continue 'outer,
-                }
-            }
-            filtered_tweets.push(result);
+        let mut total = FilterOutcome::default();
+        let mut dry_run = vec![];
+        for tweet in body.data {
+            // each tweet runs on its own, so that the drop is attributed to it even if a filter expands it
+            let outcome = self.apply_filters(filters, vec![tweet.clone()], context);
+            let dropped_by = match (outcome.kept.is_empty(), outcome.dropped.first()) {
+                (true, Some(dropped)) => Some(dropped.filter.clone()),
+                _ => None,
+            };
+            total.absorb(outcome);
+            dry_run.push(DryRunTweet { dropped_by, tweet });
         }
 
-        let content = ResponseContent::HomeTimeline {
-            meta: ResponsePlainMeta {
+        ResponseContent::HomeTimelineDryRun {
+            meta: ResponseTimelineMeta {
                 api_calls_remaining: remaining,
                 api_calls_reset: reset,
+                filters_bypassed: total.bypassed,
+                warnings,
+                next_token,
+                filter_errors: total.errors,
+                cache_hit: self.timeline_cache.is_enabled().then_some(cache_hit),
             },
-            body: HomeTimelineResponseBody {
-                data: filtered_tweets,
-                includes,
-                meta,
-            },
-        };
-        Ok(Response {
-            jsonrpc: JSONRPC_VERSION.to_string(),
-            content,
-            id,
-        })
+            dry_run,
+            includes: body.includes,
+        }
     }
 
     async fn handle_status(
@@ -371,15 +1466,110 @@ impl Handler {
             return Err(HandlerError::ParamsMismatch(id));
         }
 
+        // it only reads what is known already, so that polling it does not spend the rate limit
+        let account_count = match self.store.account_count().await {
+            Ok(count) => Some(count),
+            Err(err) => {
+                warn!("could not count the accounts: {}", err);
+                None
+            }
+        };
+        let (valid_accounts, expired_accounts) = self.store.known_states();
         let content = ResponseContent::Status {
             version: VERSION.to_string(),
+            uptime_secs: self.started_at.elapsed().as_secs(),
+            account_count,
+            valid_accounts,
+            expired_accounts,
         };
 
-        Ok(Response {
-            jsonrpc: JSONRPC_VERSION.to_string(),
-            content,
+        Ok(Response::new(content, id))
+    }
+
+    async fn handle_system_stats(
+        &self,
+        id: String,
+        params: EmptyParams,
+    ) -> Result<Response, HandlerError> {
+        if !params.validate() {
+            return Err(HandlerError::ParamsMismatch(id));
+        }
+
+        let scheduler = &self.store.scheduler;
+        let content = ResponseContent::SystemStats {
+            queue_depth: scheduler.queue_depth(),
+            max_requests_per_second: scheduler.max_requests_per_second(),
+        };
+
+        Ok(Response::new(content, id))
+    }
+
+    fn handle_metrics(&self, id: String, params: EmptyParams) -> Result<Response, HandlerError> {
+        if !params.validate() {
+            return Err(HandlerError::ParamsMismatch(id));
+        }
+
+        Ok(Response::new(
+            ResponseContent::Metrics(self.metrics.snapshot()),
             id,
-        })
+        ))
+    }
+
+    async fn handle_queue_status(
+        &self,
+        id: String,
+        params: EmptyParams,
+    ) -> Result<Response, HandlerError> {
+        if !params.validate() {
+            return Err(HandlerError::ParamsMismatch(id));
+        }
+
+        let scheduled_tweets = match self.scheduled_tweets.pending_count().await {
+            Ok(count) => Some(count),
+            Err(err) => {
+                warn!("could not count the scheduled tweets: {}", err);
+                None
+            }
+        };
+        let scheduler = &self.store.scheduler;
+        let content = ResponseContent::QueueStatus {
+            pending_requests: scheduler.pending(),
+            exhausted_limits: scheduler.exhausted_limits(),
+            scheduled_tweets,
+        };
+
+        Ok(Response::new(content, id))
+    }
+
+    // Only reports what the scheduler has seen, so it does not call the API
+    async fn handle_rate_limit(
+        &self,
+        id: String,
+        params: RateLimitParams,
+    ) -> Result<Response, AppError> {
+        let RateLimitParams { session_key } = params;
+        let user_id = self.store.id_for(&session_key).await?;
+        let content = ResponseContent::RateLimit {
+            limits: self
+                .store
+                .scheduler
+                .rate_limits(&user_id, TRACKED_ENDPOINTS),
+        };
+
+        Ok(Response::new(content, id))
+    }
+
+    async fn handle_healthcheck(
+        &self,
+        id: String,
+        params: HealthcheckParams,
+    ) -> Result<Response, AppError> {
+        let HealthcheckParams { session_key } = params;
+        let content = ResponseContent::Healthcheck {
+            accounts: self.store.healthcheck(&session_key).await?,
+        };
+
+        Ok(Response::new(content, id))
     }
 
     async fn handle_account_list(
@@ -388,20 +1578,21 @@ impl Handler {
         params: AccountListParams,
     ) -> Result<Response, AppError> {
         let AccountListParams { session_key } = params;
+        let accounts = self.store.accounts(&session_key).await?;
         let content = ResponseContent::AccountList {
             owner: self.store.id_for(&session_key).await?,
-            session_keys: self.store.accounts(&session_key).await?,
+            session_keys: accounts
+                .iter()
+                .map(|a| (a.twitter_id.clone(), a.session_key.clone()))
+                .collect(),
+            accounts,
         };
 
-        Ok(Response {
-            jsonrpc: JSONRPC_VERSION.to_string(),
-            content,
-            id,
-        })
+        Ok(Response::new(content, id))
     }
 
     async fn handle_account_add(
-        &mut self,
+        &self,
         id: String,
         params: AccountAddParams,
     ) -> Result<Response, AppError> {
@@ -415,10 +1606,1368 @@ impl Handler {
             session_key,
         };
 
-        Ok(Response {
-            jsonrpc: JSONRPC_VERSION.to_string(),
-            content,
-            id,
+        Ok(Response::new(content, id))
+    }
+
+    async fn handle_account_scopes(
+        &self,
+        id: String,
+        params: AccountScopesParams,
+    ) -> Result<Response, AppError> {
+        let AccountScopesParams { session_key } = params;
+        let content = ResponseContent::AccountScopes {
+            twitter_id: self.store.id_for(&session_key).await?,
+            scopes: self.store.scopes_for(&session_key).await?,
+        };
+
+        Ok(Response::new(content, id))
+    }
+
+    async fn handle_account_remove(
+        &self,
+        id: String,
+        params: AccountRemoveParams,
+    ) -> Result<Response, AppError> {
+        let AccountRemoveParams { session_key } = params;
+        let (twitter_id, revocation_error) = match self.store.remove(&session_key).await {
+            Ok(removed) => removed,
+            Err(CredentialStoreError::UnknownAccount(key)) => {
+                return Err(HandlerError::UnknownAccount(key).into())
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        let content = ResponseContent::AccountRemove {
+            twitter_id,
+            removed: true,
+            revocation_error,
+        };
+
+        Ok(Response::new(content, id))
+    }
+
+    async fn handle_account_prune(
+        &self,
+        id: String,
+        params: AccountPruneParams,
+    ) -> Result<Response, AppError> {
+        let AccountPruneParams { session_key } = params;
+        let content = ResponseContent::AccountPrune {
+            removed: self.store.prune(Some(&session_key)).await?,
+        };
+
+        Ok(Response::new(content, id))
+    }
+
+    async fn handle_filter_snippets(
+        &self,
+        id: String,
+        params: EmptyParams,
+    ) -> Result<Response, AppError> {
+        if !params.validate() {
+            return Err(HandlerError::ParamsMismatch(id).into());
+        }
+
+        let content = ResponseContent::FilterSnippets {
+            snippets: snippet::list()?,
+        };
+
+        Ok(Response::new(content, id))
+    }
+
+    async fn handle_export_timeline(
+        &self,
+        id: String,
+        params: ExportTimelineParams,
+    ) -> Result<Response, AppError> {
+        let format = params.format;
+        let mut exported = vec![];
+        self.export_pages(params, |tweets| {
+            exported.extend(tweets);
+            Ok(())
         })
+        .await?;
+
+        let document =
+            export::render(&exported, format).context("could not serialize the exported tweets")?;
+        let content = ResponseContent::ExportTimeline {
+            format,
+            count: exported.len(),
+            document: Some(document),
+        };
+
+        Ok(Response::new(content, id))
+    }
+
+    /// Writes each exported tweet to `out` as a line as soon as its page is fetched and filtered, instead of buffering the whole export.
+    async fn stream_export_timeline(
+        &self,
+        id: String,
+        params: ExportTimelineParams,
+        out: &mut (dyn AsyncWrite + Send + Unpin),
+    ) -> Result<Response, AppError> {
+        // pages are serialized as they arrive and written out by the loop below, which also sends heartbeats
+        let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+        let export = self.export_pages(params, |tweets| {
+            for tweet in tweets {
+                let line = serde_json::to_string(&tweet).context("could not serialize a tweet")?;
+                // the receiver outlives the export, so this never fails
+                let _ = tx.send(line);
+            }
+            Ok(())
+        });
+        tokio::pin!(export);
+
+        let mut heartbeat = Heartbeat::new(self.heartbeat_interval);
+        let count = loop {
+            tokio::select! {
+                count = &mut export => break count?,
+                Some(line) = rx.recv() => {
+                    write_line(out, &line).await?;
+                    heartbeat.wrote();
+                }
+                // a failed write means the client has gone away, so the export is abandoned
+                _ = heartbeat.due() => heartbeat.send(out).await?,
+            }
+        };
+        // the last page may not have been written yet
+        while let Ok(line) = rx.try_recv() {
+            write_line(out, &line).await?;
+        }
+
+        let content = ResponseContent::ExportTimeline {
+            format: ExportFormat::Ndjson,
+            count,
+            document: None,
+        };
+
+        Ok(Response::new(content, id))
+    }
+
+    /// Pages through the timeline until `count` tweets are fetched, passing the filtered tweets of each page to `on_page`. Returns the number of exported tweets.
+    async fn export_pages(
+        &self,
+        params: ExportTimelineParams,
+        mut on_page: impl FnMut(Vec<Tweet>) -> Result<(), AppError>,
+    ) -> Result<usize, AppError> {
+        let ExportTimelineParams {
+            session_key,
+            count,
+            format: _,
+            skip_filters,
+            context,
+        } = params;
+
+        let count = if count > self.export_max_count {
+            warn!(
+                "export of {} tweets requested but capped to {}",
+                count, self.export_max_count
+            );
+            self.export_max_count
+        } else {
+            count
+        };
+
+        let client = self.store.client_for(&session_key).await?;
+        let context = context.for_account(&client.user_id, "export.timeline");
+        let filters = if skip_filters {
+            vec![]
+        } else {
+            self.load_filters()?
+        };
+
+        let mut api_params = HashMap::from([(
+            "tweet.fields".to_owned(),
+            serde_json::Value::from("created_at,author_id,public_metrics"),
+        )]);
+        let mut fetched = 0;
+        let mut exported = 0;
+        while fetched < count {
+            // the endpoint accepts 1 to 100 for max_results
+            let page_size = (count - fetched).min(100);
+            api_params.insert("max_results".to_owned(), page_size.into());
+
+            let (body, _remaining, _reset) = client.timeline(&mut api_params).await?;
+            fetched += body.data.len();
+            let tweets = self.apply_filters(&filters, body.data, &context).kept;
+            exported += tweets.len();
+            on_page(tweets)?;
+
+            match body.meta["next_token"].as_str() {
+                Some(token) => {
+                    api_params.insert("pagination_token".to_owned(), token.into());
+                }
+                None => break,
+            }
+        }
+        info!("exported {} of {} fetched tweets", exported, fetched);
+
+        Ok(exported)
+    }
+
+    async fn handle_pinned_tweet(
+        &self,
+        id: String,
+        params: PinnedTweetParams,
+    ) -> Result<Response, AppError> {
+        let PinnedTweetParams {
+            session_key,
+            target_user_id,
+            context,
+        } = params;
+
+        let client = self.store.client_for(&session_key).await?;
+        let context = context.for_account(&client.user_id, "user.pinned");
+        let (tweet, remaining, reset) = client.pinned_tweet(&target_user_id).await?;
+        let tweet = match tweet {
+            Some(tweet) => {
+                let filters = self.load_filters()?;
+                self.apply_filters(&filters, vec![tweet], &context)
+                    .kept
+                    .pop()
+            }
+            None => None,
+        };
+
+        let content = ResponseContent::PinnedTweet {
+            meta: ResponsePlainMeta {
+                api_calls_remaining: remaining,
+                api_calls_reset: reset,
+            },
+            tweet,
+        };
+
+        Ok(Response::new(content, id))
     }
+
+    async fn handle_tweet_show(
+        &self,
+        id: String,
+        params: TweetShowParams,
+    ) -> Result<Response, AppError> {
+        let TweetShowParams {
+            session_key,
+            tweet_id,
+            context,
+        } = params;
+        if !is_tweet_id(&tweet_id) {
+            return Err(HandlerError::ParamsMismatch(id).into());
+        }
+
+        let client = self.store.client_for(&session_key).await?;
+        let context = context.for_account(&client.user_id, "tweet.show");
+        let (tweet, remaining, reset) = client.show_tweet(&tweet_id).await?;
+        let meta = ResponsePlainMeta {
+            api_calls_remaining: remaining,
+            api_calls_reset: reset,
+        };
+        let content = self.tweet_show_content(tweet, &self.load_filters()?, &context, meta);
+
+        Ok(Response::new(content, id))
+    }
+
+    /// Runs the filters over a fetched tweet, telling which filter dropped it if one did.
+    pub(crate) fn tweet_show_content(
+        &self,
+        tweet: Tweet,
+        filters: &[Filter],
+        context: &FilterContext,
+        meta: ResponsePlainMeta,
+    ) -> ResponseContent {
+        let outcome = self.apply_filters(filters, vec![tweet], context);
+        let tweet = outcome.kept.into_iter().next();
+        let dropped_by = match tweet {
+            Some(_) => None,
+            None => outcome.dropped.into_iter().next().map(|d| d.filter),
+        };
+
+        ResponseContent::TweetShow {
+            meta,
+            tweet,
+            dropped_by,
+            filter_errors: outcome.errors,
+        }
+    }
+
+    async fn handle_tweet_create(
+        &self,
+        id: String,
+        params: TweetCreateParams,
+    ) -> Result<Response, AppError> {
+        let TweetCreateParams {
+            session_key,
+            text,
+            reply_to_tweet_id,
+            quote_tweet_id,
+            reply_settings,
+            scheduled_at,
+        } = params;
+        if text.trim().is_empty() || scheduled_at.is_some_and(|at| at <= clock::now()) {
+            return Err(HandlerError::ParamsMismatch(id).into());
+        }
+        self.require_scope(&session_key, "tweet.write").await?;
+
+        if let Some(scheduled_at) = scheduled_at {
+            let tweet = ScheduledTweet {
+                scheduled_id: 0,
+                text,
+                reply_to_tweet_id,
+                quote_tweet_id,
+                reply_settings,
+                scheduled_at,
+                error: None,
+            };
+            let scheduled_id = self
+                .scheduled_tweets
+                .add(&session_key, &tweet)
+                .await?
+                .ok_or_else(|| CredentialStoreError::UnknownAccount(session_key.clone()))?;
+            info!("scheduled tweet {} at {}", scheduled_id, scheduled_at);
+            let content = ResponseContent::TweetSchedule {
+                scheduled_id,
+                scheduled_at,
+            };
+            return Ok(Response::new(content, id));
+        }
+
+        let client = self.store.client_for(&session_key).await?;
+        let ((tweet_id, text), remaining, reset) = client
+            .create_tweet(
+                &text,
+                reply_to_tweet_id.as_deref(),
+                quote_tweet_id.as_deref(),
+                reply_settings,
+            )
+            .await?;
+        info!("posted tweet {}", tweet_id);
+
+        let content = ResponseContent::TweetCreate {
+            meta: ResponsePlainMeta {
+                api_calls_remaining: remaining,
+                api_calls_reset: reset,
+            },
+            id: tweet_id,
+            text,
+        };
+
+        Ok(Response::new(content, id))
+    }
+
+    async fn handle_tweet_delete(
+        &self,
+        id: String,
+        params: TweetDeleteParams,
+    ) -> Result<Response, AppError> {
+        let TweetDeleteParams {
+            session_key,
+            tweet_id,
+        } = params;
+        if !is_tweet_id(&tweet_id) {
+            return Err(HandlerError::ParamsMismatch(id).into());
+        }
+        self.require_scope(&session_key, "tweet.write").await?;
+
+        let client = self.store.client_for(&session_key).await?;
+        let (deleted, remaining, reset) = client.delete_tweet(&tweet_id).await?;
+        info!("deleted tweet {}", tweet_id);
+
+        let content = ResponseContent::TweetDelete {
+            meta: ResponsePlainMeta {
+                api_calls_remaining: remaining,
+                api_calls_reset: reset,
+            },
+            deleted,
+        };
+
+        Ok(Response::new(content, id))
+    }
+
+    async fn handle_scheduled_list(
+        &self,
+        id: String,
+        params: ScheduledListParams,
+    ) -> Result<Response, AppError> {
+        let ScheduledListParams { session_key } = params;
+        // fails for unknown accounts rather than listing nothing
+        self.store.id_for(&session_key).await?;
+
+        let content = ResponseContent::ScheduledList {
+            scheduled: self.scheduled_tweets.list(&session_key).await?,
+        };
+
+        Ok(Response::new(content, id))
+    }
+
+    async fn handle_scheduled_cancel(
+        &self,
+        id: String,
+        params: ScheduledCancelParams,
+    ) -> Result<Response, AppError> {
+        let ScheduledCancelParams {
+            session_key,
+            scheduled_id,
+        } = params;
+        self.store.id_for(&session_key).await?;
+
+        let canceled = self
+            .scheduled_tweets
+            .cancel(&session_key, scheduled_id)
+            .await?;
+        if canceled {
+            info!("canceled scheduled tweet {}", scheduled_id);
+        }
+        let content = ResponseContent::ScheduledCancel {
+            scheduled_id,
+            canceled,
+        };
+
+        Ok(Response::new(content, id))
+    }
+
+    /// Posts the scheduled tweets which are due, including ones which fell due while the daemon was not running. A tweet which fails for a reason other than the network or the rate limit is marked as failed and not posted again. The others are tried again on the next call.
+    pub async fn post_scheduled_tweets(&self) {
+        let due = match self.scheduled_tweets.due(clock::now()).await {
+            Ok(due) => due,
+            Err(err) => {
+                warn!("could not read the scheduled tweets: {}", err);
+                return;
+            }
+        };
+
+        for (session_key, tweet) in due {
+            let result = async {
+                let client = self.store.client_for(&session_key).await?;
+                let ((tweet_id, _), _, _) = client
+                    .create_tweet(
+                        &tweet.text,
+                        tweet.reply_to_tweet_id.as_deref(),
+                        tweet.quote_tweet_id.as_deref(),
+                        tweet.reply_settings,
+                    )
+                    .await?;
+                Ok::<_, AppError>(tweet_id)
+            }
+            .await;
+
+            let saved = match result {
+                Ok(tweet_id) => {
+                    info!(
+                        "posted scheduled tweet {} as {}",
+                        tweet.scheduled_id, tweet_id
+                    );
+                    self.scheduled_tweets.posted(tweet.scheduled_id).await
+                }
+                Err(err) if is_transient(&err) => {
+                    warn!(
+                        "could not post scheduled tweet {} yet: {}",
+                        tweet.scheduled_id, err
+                    );
+                    Ok(())
+                }
+                Err(err) => {
+                    warn!(
+                        "could not post scheduled tweet {}: {}",
+                        tweet.scheduled_id, err
+                    );
+                    self.scheduled_tweets
+                        .failed(tweet.scheduled_id, &err.to_string())
+                        .await
+                }
+            };
+            if let Err(err) = saved {
+                error!(
+                    "could not save the state of scheduled tweet {}: {}",
+                    tweet.scheduled_id, err
+                );
+            }
+        }
+    }
+
+    async fn handle_quote_tweets(
+        &self,
+        id: String,
+        params: QuoteTweetsParams,
+    ) -> Result<Response, AppError> {
+        let QuoteTweetsParams {
+            session_key,
+            tweet_id,
+            next_token,
+            context,
+        } = params;
+
+        let mut api_params = HashMap::new();
+        if let Some(token) = next_token {
+            api_params.insert("pagination_token".to_owned(), token.into());
+        }
+
+        let client = self.store.client_for(&session_key).await?;
+        let context = context.for_account(&client.user_id, "tweet.quotes");
+        let (body, remaining, reset) = client.quote_tweets(&tweet_id, &mut api_params).await?;
+        info!(
+            "successfully retrieved {} tweets quoting {}",
+            body.data.len(),
+            tweet_id
+        );
+        let (meta, body) = self.filter_page(body, remaining, reset, &context)?;
+
+        let content = ResponseContent::QuoteTweets { meta, body };
+
+        Ok(Response::new(content, id))
+    }
+
+    async fn handle_my_likes(
+        &self,
+        id: String,
+        params: MyLikesParams,
+    ) -> Result<Response, AppError> {
+        let MyLikesParams {
+            session_key,
+            max_results,
+            next_token,
+            context,
+        } = params;
+        self.require_scope(&session_key, "like.read").await?;
+
+        let mut api_params = HashMap::new();
+        if let Some(max_results) = max_results {
+            api_params.insert("max_results".to_owned(), max_results.into());
+        }
+        if let Some(token) = next_token {
+            api_params.insert("pagination_token".to_owned(), token.into());
+        }
+
+        let client = self.store.client_for(&session_key).await?;
+        let context = context.for_account(&client.user_id, "likes.list");
+        let (body, remaining, reset) = client.liked_tweets(&mut api_params).await?;
+        info!("successfully retrieved {} liked tweets", body.data.len());
+        let (meta, body) = self.filter_page(body, remaining, reset, &context)?;
+
+        let content = ResponseContent::MyLikes { meta, body };
+
+        Ok(Response::new(content, id))
+    }
+
+    async fn handle_mentions(
+        &self,
+        id: String,
+        params: MentionsParams,
+    ) -> Result<Response, AppError> {
+        let MentionsParams {
+            session_key,
+            max_results,
+            pagination_token,
+            context,
+        } = params;
+        if max_results.is_some_and(|n| !(5..=100).contains(&n)) {
+            return Err(HandlerError::ParamsMismatch(id).into());
+        }
+
+        let mut api_params = HashMap::new();
+        if let Some(max_results) = max_results {
+            api_params.insert("max_results".to_owned(), max_results.into());
+        }
+        if let Some(token) = pagination_token {
+            api_params.insert("pagination_token".to_owned(), token.into());
+        }
+
+        let client = self.store.client_for(&session_key).await?;
+        let context = context.for_account(&client.user_id, "mentions");
+        let (body, remaining, reset) = client.mentions(&mut api_params).await?;
+        info!("successfully retrieved {} mentions", body.data.len());
+        let (meta, body) = self.filter_page(body, remaining, reset, &context)?;
+
+        let content = ResponseContent::Mentions { meta, body };
+
+        Ok(Response::new(content, id))
+    }
+
+    async fn handle_mutes_list(
+        &self,
+        id: String,
+        params: EmptyParams,
+    ) -> Result<Response, HandlerError> {
+        if !params.validate() {
+            return Err(HandlerError::ParamsMismatch(id));
+        }
+
+        Ok(self.mutes_response(id))
+    }
+
+    async fn handle_mutes_add(&self, id: String, mute: Mute) -> Result<Response, AppError> {
+        if mute.keyword.trim().is_empty() {
+            return Err(HandlerError::ParamsMismatch(id).into());
+        }
+
+        info!("muting {:?} until {:?}", mute.keyword, mute.until);
+        self.mute_store.save(&mute).await?;
+        // adding the same keyword again updates its expiry
+        let mut mutes = self.mutes.lock().unwrap();
+        mutes.retain(|m| m.keyword != mute.keyword);
+        mutes.push(mute);
+        drop(mutes);
+
+        Ok(self.mutes_response(id))
+    }
+
+    async fn handle_mutes_remove(
+        &self,
+        id: String,
+        params: MutesRemoveParams,
+    ) -> Result<Response, AppError> {
+        let MutesRemoveParams { keyword } = params;
+        info!("unmuting {:?}", keyword);
+        self.mute_store.remove(&keyword).await?;
+        self.mutes.lock().unwrap().retain(|m| m.keyword != keyword);
+
+        Ok(self.mutes_response(id))
+    }
+
+    // Removes expired mutes and returns the rest
+    fn mutes_response(&self, id: String) -> Response {
+        let now = clock::now();
+        let mut mutes = self.mutes.lock().unwrap();
+        mutes.retain(|m| m.is_active(now));
+
+        Response::new(
+            ResponseContent::Mutes {
+                mutes: mutes.clone(),
+            },
+            id,
+        )
+    }
+
+    async fn handle_list_memberships(
+        &self,
+        id: String,
+        params: ListMembershipsParams,
+    ) -> Result<Response, AppError> {
+        let ListMembershipsParams {
+            session_key,
+            member_id,
+            next_token,
+        } = params;
+
+        let mut api_params = HashMap::new();
+        if let Some(token) = next_token {
+            api_params.insert("pagination_token".to_owned(), token.into());
+        }
+
+        let client = self.store.client_for(&session_key).await?;
+        let member_id = member_id.unwrap_or_else(|| client.user_id.clone());
+        let (body, remaining, reset) = client.list_memberships(&member_id, &api_params).await?;
+
+        let content = ResponseContent::ListMemberships {
+            meta: ResponsePlainMeta {
+                api_calls_remaining: remaining,
+                api_calls_reset: reset,
+            },
+            body,
+        };
+
+        Ok(Response::new(content, id))
+    }
+
+    async fn handle_list_member(
+        &self,
+        id: String,
+        params: ListMemberParams,
+        add: bool,
+    ) -> Result<Response, AppError> {
+        let ListMemberParams {
+            session_key,
+            list_id,
+            member_id,
+        } = params;
+
+        let client = self.store.client_for(&session_key).await?;
+        let (is_member, remaining, reset) =
+            client.set_list_member(&list_id, &member_id, add).await?;
+        info!(
+            "{} {} {} list {}",
+            if add { "added" } else { "removed" },
+            member_id,
+            if add { "to" } else { "from" },
+            list_id
+        );
+
+        let content = ResponseContent::ListMember {
+            meta: ResponsePlainMeta {
+                api_calls_remaining: remaining,
+                api_calls_reset: reset,
+            },
+            list_id,
+            member_id,
+            is_member,
+        };
+
+        Ok(Response::new(content, id))
+    }
+
+    async fn handle_like(
+        &self,
+        id: String,
+        params: LikeParams,
+        like: bool,
+    ) -> Result<Response, AppError> {
+        let LikeParams {
+            session_key,
+            tweet_id,
+        } = params;
+        if !is_tweet_id(&tweet_id) {
+            return Err(HandlerError::ParamsMismatch(id).into());
+        }
+        self.require_scope(&session_key, "like.write").await?;
+
+        let client = self.store.client_for(&session_key).await?;
+        let (liked, remaining, reset) = client.set_like(&tweet_id, like).await?;
+        info!(
+            "{} tweet {}",
+            if like { "liked" } else { "unliked" },
+            tweet_id
+        );
+
+        let content = ResponseContent::Like {
+            meta: ResponsePlainMeta {
+                api_calls_remaining: remaining,
+                api_calls_reset: reset,
+            },
+            tweet_id,
+            liked,
+        };
+
+        Ok(Response::new(content, id))
+    }
+
+    /// Reads the tweets stored for the account without calling the API, running the filters over them as the live timeline does.
+    async fn handle_history(
+        &self,
+        id: String,
+        params: HistoryParams,
+    ) -> Result<Response, AppError> {
+        let HistoryParams {
+            session_key,
+            before_id,
+            limit,
+            context,
+        } = params;
+        let limit = limit.unwrap_or(HISTORY_DEFAULT_LIMIT);
+        if !(1..=HISTORY_MAX_LIMIT).contains(&limit)
+            || !before_id.as_deref().is_none_or(is_tweet_id)
+        {
+            return Err(HandlerError::ParamsMismatch(id).into());
+        }
+
+        let twitter_id = self.store.id_for(&session_key).await?;
+        let stored = self
+            .tweet_store
+            .history(&twitter_id, before_id.as_deref(), limit)
+            .await?;
+        info!("read {} stored tweets of {}", stored.len(), twitter_id);
+        // a full page may be followed by older tweets
+        let next_before_id = if stored.len() == limit {
+            stored
+                .last()
+                .and_then(|t| t.field("id").as_str())
+                .map(String::from)
+        } else {
+            None
+        };
+
+        let context = context.for_account(&twitter_id, "history");
+        let outcome = self.apply_filters(&self.load_filters()?, stored, &context);
+        let content = ResponseContent::History {
+            data: outcome.kept,
+            next_before_id,
+            filter_errors: outcome.errors,
+        };
+
+        Ok(Response::new(content, id))
+    }
+
+    async fn handle_subscribe(
+        &self,
+        id: String,
+        params: SubscribeParams,
+        subscriptions: &mut Subscriptions,
+    ) -> Result<Response, AppError> {
+        let SubscribeParams {
+            session_key,
+            poll_interval_secs,
+            since_id,
+            context,
+        } = params;
+        let interval = poll_interval_secs
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_POLL_INTERVAL);
+        if interval < MIN_POLL_INTERVAL || !since_id.as_deref().is_none_or(is_tweet_id) {
+            return Err(HandlerError::ParamsMismatch(id).into());
+        }
+        if subscriptions.len() >= MAX_SUBSCRIPTIONS {
+            return Err(HandlerError::TooManySubscriptions(MAX_SUBSCRIPTIONS).into());
+        }
+        // fails for unknown accounts here rather than in the first poll
+        self.store.id_for(&session_key).await?;
+
+        let subscription = subscriptions.add(session_key, context, since_id, interval);
+        info!("started the subscription {}", subscription);
+        let content = ResponseContent::Subscribe {
+            subscription,
+            poll_interval_secs: interval.as_secs(),
+        };
+
+        Ok(Response::new(content, id))
+    }
+
+    /// Polls the subscription and returns the notification to push, or None if there is nothing new. A subscription whose account can no longer be used is ended.
+    pub async fn poll_subscription(
+        &self,
+        subscriptions: &mut Subscriptions,
+        id: &str,
+    ) -> Option<String> {
+        let subscription = subscriptions.get_mut(id)?;
+        let result = self.fetch_new_tweets(subscription).await;
+        subscription.polled();
+
+        let content = match result {
+            Ok(Some((meta, body))) => SubscriptionContent::Tweets { meta, body },
+            Ok(None) => return None,
+            Err(err) => {
+                warn!("could not poll the subscription {}: {}", id, err);
+                let ended = matches!(err, AppError::CredentialStore(_));
+                if ended {
+                    subscriptions.remove(id);
+                }
+                SubscriptionContent::Error {
+                    error: err.into(),
+                    ended,
+                }
+            }
+        };
+        let notification = Notification {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            method: SUBSCRIPTION_NOTIFICATION,
+            params: SubscriptionUpdate {
+                subscription: id.to_owned(),
+                content,
+            },
+        };
+        // SAFETY: the notification is serde::Serialize so it should always be able to be serialized
+        Some(serde_json::to_string(&notification).unwrap())
+    }
+
+    /// Fetches the tweets newer than the last poll and runs the filters over the ones not pushed yet. Only the first page is fetched, so a poll picks up at most `max_results` tweets.
+    async fn fetch_new_tweets(
+        &self,
+        subscription: &mut Subscription,
+    ) -> Result<Option<(ResponseTimelineMeta, HomeTimelineResponseBody)>, AppError> {
+        let client = self.store.client_for(&subscription.session_key).await?;
+        let context = subscription
+            .context
+            .clone()
+            .for_account(&client.user_id, "subscribe");
+        let mut api_params = HashMap::new();
+        let baseline = subscription.since_id.is_none();
+        if let Some(since_id) = &subscription.since_id {
+            api_params.insert("since_id".to_owned(), since_id.clone().into());
+        }
+        let (mut body, remaining, reset) = client.timeline(&mut api_params).await?;
+        if let Err(err) = self.tweet_store.upsert(&client.user_id, &body.data).await {
+            warn!("could not save the fetched tweets: {}", err);
+        }
+
+        let newest = body.meta["newest_id"]
+            .as_str()
+            .map(String::from)
+            .or_else(|| {
+                body.data
+                    .first()
+                    .and_then(|t| t.field("id").as_str())
+                    .map(String::from)
+            });
+        if newest.is_some() {
+            subscription.since_id = newest;
+        }
+        body.data.retain(|tweet| {
+            tweet
+                .field("id")
+                .as_str()
+                .is_none_or(|id| subscription.first_seen(id))
+        });
+        // the tweets on the timeline when subscribing are only remembered
+        if baseline || body.data.is_empty() {
+            return Ok(None);
+        }
+
+        let filters = self.load_filters()?;
+        let warnings = Self::warnings_for(&body);
+        let (body, outcome) = self.filter_body(body, &filters, &context);
+        if body.data.is_empty() {
+            return Ok(None);
+        }
+        let meta = ResponseTimelineMeta {
+            api_calls_remaining: remaining,
+            api_calls_reset: reset,
+            filters_bypassed: outcome.bypassed,
+            warnings,
+            next_token: None,
+            filter_errors: outcome.errors,
+            cache_hit: None,
+        };
+
+        Ok(Some((meta, body)))
+    }
+
+    async fn handle_retweet(
+        &self,
+        id: String,
+        params: RetweetParams,
+        retweet: bool,
+    ) -> Result<Response, AppError> {
+        let RetweetParams {
+            session_key,
+            tweet_id,
+        } = params;
+        if !is_tweet_id(&tweet_id) {
+            return Err(HandlerError::ParamsMismatch(id).into());
+        }
+        self.require_scope(&session_key, "tweet.write").await?;
+
+        let client = self.store.client_for(&session_key).await?;
+        let (retweeted, remaining, reset) = client.set_retweet(&tweet_id, retweet).await?;
+        info!(
+            "{} tweet {}",
+            if retweet { "retweeted" } else { "unretweeted" },
+            tweet_id
+        );
+
+        let content = ResponseContent::Retweet {
+            meta: ResponsePlainMeta {
+                api_calls_remaining: remaining,
+                api_calls_reset: reset,
+            },
+            tweet_id,
+            retweeted,
+        };
+
+        Ok(Response::new(content, id))
+    }
+
+    async fn handle_search(&self, id: String, params: SearchParams) -> Result<Response, AppError> {
+        let SearchParams {
+            session_key,
+            query,
+            max_results,
+            next_token,
+            mut api_params,
+            context,
+        } = params;
+        if query.trim().is_empty() || query.chars().count() > SEARCH_QUERY_MAX_LEN {
+            return Err(HandlerError::ParamsMismatch(id).into());
+        }
+
+        api_params.insert("query".to_owned(), query.clone().into());
+        if let Some(max_results) = max_results {
+            api_params.insert("max_results".to_owned(), max_results.into());
+        }
+        // unlike the timelines, the search endpoint takes the token as `next_token`
+        if let Some(token) = next_token {
+            api_params.insert("next_token".to_owned(), token.into());
+        }
+
+        let client = self.store.client_for(&session_key).await?;
+        let context = context.for_account(&client.user_id, "search");
+        let (body, remaining, reset) = client.search_recent(&mut api_params).await?;
+        info!(
+            "successfully retrieved {} tweets matching {:?}",
+            body.data.len(),
+            query
+        );
+        let (meta, body) = self.filter_page(body, remaining, reset, &context)?;
+
+        let content = ResponseContent::Search { meta, body };
+
+        Ok(Response::new(content, id))
+    }
+
+    async fn handle_user_lookup(
+        &self,
+        id: String,
+        params: UserLookupParams,
+    ) -> Result<Response, AppError> {
+        let UserLookupParams {
+            session_key,
+            target_ids,
+        } = params;
+        if target_ids.is_empty() {
+            return Err(HandlerError::ParamsMismatch(id).into());
+        }
+
+        let (mut users, claim) = self.user_cache.resolve(&target_ids).await;
+        let cached = users.len();
+        let (errors, remaining, reset) = if claim.ids.is_empty() {
+            // no API call was made, so the rate limit is unknown
+            (vec![], 0, 0)
+        } else {
+            let client = self.store.client_for(&session_key).await?;
+            let ((fetched, errors), remaining, reset) = client.lookup_users(&claim.ids).await?;
+            self.user_cache.insert(&fetched);
+            users.extend(fetched);
+            (errors, remaining, reset)
+        };
+        drop(claim);
+        info!(
+            "looked up {} users ({} from the cache, {} could not be resolved)",
+            users.len(),
+            cached,
+            errors.len()
+        );
+
+        let content = ResponseContent::UserLookup {
+            meta: ResponsePlainMeta {
+                api_calls_remaining: remaining,
+                api_calls_reset: reset,
+            },
+            users,
+            errors,
+        };
+
+        Ok(Response::new(content, id))
+    }
+
+    async fn handle_filter_profile(
+        &self,
+        id: String,
+        params: FilterProfileParams,
+    ) -> Result<Response, AppError> {
+        let FilterProfileParams { profile } = params;
+
+        if let Some(profile) = &profile {
+            let filters = Filter::load(&self.filter_dirs, &self.scopes)?;
+            if !filters.iter().any(|f| f.meta.profiles.contains(profile)) {
+                warn!("no filter belongs to the profile {}", profile);
+            }
+        }
+        info!("switching the filter profile to {:?}", profile);
+        *self.active_filter_profile.lock().unwrap() = profile.clone();
+
+        let content = ResponseContent::FilterProfile { profile };
+
+        Ok(Response::new(content, id))
+    }
+
+    /// Fails unless the account has granted the scope. The configured scopes are assumed for accounts whose scopes were not recorded.
+    async fn require_scope(&self, session_key: &str, scope: &str) -> Result<(), AppError> {
+        let granted = match self.store.scopes_for(session_key).await? {
+            Some(scopes) => scopes.iter().any(|s| s == scope),
+            None => self.scopes.contains(scope),
+        };
+        if !granted {
+            return Err(HandlerError::MissingScope(scope.to_owned()).into());
+        }
+
+        Ok(())
+    }
+
+    /// Loads the filters which belong to the active profile.
+    fn load_filters(&self) -> Result<Vec<Filter>, AppError> {
+        let profile = self.active_filter_profile.lock().unwrap().clone();
+        let filters = Filter::load(&self.filter_dirs, &self.scopes)?
+            .into_iter()
+            .filter(|f| f.in_profile(profile.as_deref()))
+            .collect();
+
+        Ok(filters)
+    }
+
+    /// Picks the installed filters with the given names in the given order. Returns None if any of the names is unknown.
+    fn select_filters(&self, names: &[String]) -> Result<Option<Vec<Filter>>, AppError> {
+        let installed = Filter::load(&self.filter_dirs, &self.scopes)?;
+        let selected = names
+            .iter()
+            .map(|name| installed.iter().find(|f| f.is_named(name)).cloned())
+            .collect();
+
+        Ok(selected)
+    }
+
+    /// Lists what the response meta should warn about the body.
+    fn warnings_for(body: &HomeTimelineResponseBody) -> Vec<String> {
+        let mut warnings = vec![];
+        if body.details_omitted {
+            warnings.push("expansions and fields were omitted because the API access tier does not allow them".to_owned());
+        }
+        warnings
+    }
+
+    /// Runs the filters over the tweets in the body. The kept tweets are moved into the returned body, so the `kept` of the outcome is empty.
+    fn filter_body(
+        &self,
+        body: HomeTimelineResponseBody,
+        filters: &[Filter],
+        context: &FilterContext,
+    ) -> (HomeTimelineResponseBody, FilterOutcome) {
+        let HomeTimelineResponseBody {
+            data: tweets,
+            includes,
+            meta,
+            details_omitted,
+        } = body;
+        let mut outcome = self.apply_filters(filters, tweets, context);
+
+        let body = HomeTimelineResponseBody {
+            data: std::mem::take(&mut outcome.kept),
+            includes,
+            meta,
+            details_omitted,
+        };
+        (body, outcome)
+    }
+
+    /// Filters a page fetched from a paginated endpoint and builds its meta, carrying the token for the next page.
+    fn filter_page(
+        &self,
+        body: HomeTimelineResponseBody,
+        remaining: usize,
+        reset: usize,
+        context: &FilterContext,
+    ) -> Result<(ResponseTimelineMeta, HomeTimelineResponseBody), AppError> {
+        let warnings = Self::warnings_for(&body);
+        let (body, outcome) = self.filter_body(body, &self.load_filters()?, context);
+        let meta = ResponseTimelineMeta {
+            api_calls_remaining: remaining,
+            api_calls_reset: reset,
+            filters_bypassed: outcome.bypassed,
+            warnings,
+            next_token: body.next_token(),
+            filter_errors: outcome.errors,
+            cache_hit: None,
+        };
+
+        Ok((meta, body))
+    }
+
+    /// Runs the filters over each tweet in order. A tweet is dropped once a filter returns null for it, or if it contains a muted keyword. Tweets by allowlisted authors are kept as they are without running any filter. A filter which fails on a tweet is handled according to filter_error_policy.
+    fn apply_filters(
+        &self,
+        filters: &[Filter],
+        tweets: Vec<Tweet>,
+        context: &FilterContext,
+    ) -> FilterOutcome {
+        let now = clock::now();
+        let mutes: Vec<Mute> = self
+            .mutes
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|m| m.is_active(now))
+            .cloned()
+            .collect();
+
+        let mut outcome = FilterOutcome::default();
+        for tweet in tweets {
+            if self.is_allowlisted(&tweet) {
+                outcome.bypassed += 1;
+                outcome.kept.push(tweet);
+                continue;
+            }
+
+            if let Some(mute) = mutes.iter().find(|m| m.matches(&tweet)) {
+                outcome.dropped.push(DroppedTweet {
+                    filter: format!("mute:{}", mute.keyword),
+                    tweet,
+                });
+                continue;
+            }
+
+            // (the tweet before any filter modified it, the tweet so far, whether a filter accepted it). A filter may expand a tweet into several, each of which runs through the rest of the filters. An accepted tweet skips the rest.
+            let mut current = vec![(tweet.clone(), tweet, false)];
+            for filter in filters {
+                let mut next = vec![];
+                for (original, tweet, accepted) in current {
+                    if accepted {
+                        next.push((original, tweet, true));
+                        continue;
+                    }
+                    match filter.run(&tweet, context, self.filter_timeout, &self.filter_http) {
+                        Ok(FilterVerdict::Keep) => next.push((original, tweet, false)),
+                        Ok(FilterVerdict::Drop) => outcome.dropped.push(DroppedTweet {
+                            filter: filter.name().to_owned(),
+                            tweet: original,
+                        }),
+                        Ok(FilterVerdict::Accept(tweet)) => next.push((original, tweet, true)),
+                        Ok(FilterVerdict::Transform(mut tweets)) if tweets.len() == 1 => {
+                            // SAFETY: there is exactly one tweet
+                            next.push((original, tweets.pop().unwrap(), false))
+                        }
+                        Ok(FilterVerdict::Transform(tweets)) => {
+                            next.extend(tweets.into_iter().map(|t| (t.clone(), t, false)))
+                        }
+                        Err(err) => {
+                            warn!("filter {} failed: {}", filter.name(), err);
+                            outcome.record_error(filter.name(), &err);
+                            if self.filter_error_policy == FilterErrorPolicy::DropTweet {
+                                outcome.dropped.push(DroppedTweet {
+                                    filter: filter.name().to_owned(),
+                                    tweet: original,
+                                });
+                            } else {
+                                next.push((original, tweet, false));
+                            }
+                        }
+                    }
+                }
+                current = next;
+            }
+            outcome
+                .kept
+                .extend(current.into_iter().map(|(_, tweet, _)| tweet));
+        }
+
+        outcome
+    }
+
+    // Whether the author id or the username (case-insensitive, with or without @) is in the allowlist
+    fn is_allowlisted(&self, tweet: &Tweet) -> bool {
+        if self.filter_allowlist_authors.is_empty() {
+            return false;
+        }
+
+        let id = tweet.field("author_id").as_str();
+        let username = tweet.field("author")["username"].as_str();
+        self.filter_allowlist_authors.iter().any(|entry| {
+            let entry = entry.trim_start_matches('@');
+            Some(entry) == id || username.is_some_and(|u| u.eq_ignore_ascii_case(entry))
+        })
+    }
+}
+
+#[derive(Default)]
+struct FilterOutcome {
+    kept: Vec<Tweet>,
+    // number of tweets which skipped the filters because their authors are allowlisted
+    bypassed: usize,
+    dropped: Vec<DroppedTweet>,
+    errors: Vec<FilterFailure>,
+}
+
+impl FilterOutcome {
+    // Counts the failure under the filter, keeping the first error message
+    fn record_error(&mut self, filter: &str, err: &FilterError) {
+        match self.errors.iter_mut().find(|f| f.filter == filter) {
+            Some(failure) => failure.count += 1,
+            None => self.errors.push(FilterFailure {
+                filter: filter.to_owned(),
+                error: err.to_string(),
+                count: 1,
+            }),
+        }
+    }
+
+    // Adds up the counts of another outcome, discarding its tweets
+    fn absorb(&mut self, other: FilterOutcome) {
+        self.bypassed += other.bypassed;
+        for failure in other.errors {
+            match self.errors.iter_mut().find(|f| f.filter == failure.filter) {
+                Some(existing) => existing.count += failure.count,
+                None => self.errors.push(failure),
+            }
+        }
+    }
+}
+
+async fn write_line(out: &mut (dyn AsyncWrite + Send + Unpin), line: &str) -> std::io::Result<()> {
+    out.write_all(line.as_bytes()).await?;
+    out.write_all(b"\n").await?;
+    out.flush().await
+}
+
+/// Pretty-prints JSON on a single line (`{"a": 1, "b": [2, 3]}`), as each message on a connection is a line of its own.
+struct SpacedFormatter;
+
+impl serde_json::ser::Formatter for SpacedFormatter {
+    fn begin_array_value<W: ?Sized + std::io::Write>(
+        &mut self,
+        writer: &mut W,
+        first: bool,
+    ) -> std::io::Result<()> {
+        if first {
+            Ok(())
+        } else {
+            writer.write_all(b", ")
+        }
+    }
+
+    fn begin_object_key<W: ?Sized + std::io::Write>(
+        &mut self,
+        writer: &mut W,
+        first: bool,
+    ) -> std::io::Result<()> {
+        if first {
+            Ok(())
+        } else {
+            writer.write_all(b", ")
+        }
+    }
+
+    fn begin_object_value<W: ?Sized + std::io::Write>(
+        &mut self,
+        writer: &mut W,
+    ) -> std::io::Result<()> {
+        writer.write_all(b": ")
+    }
+}
+
+/// Sends heartbeats on a streaming connection once it has been idle for the interval. Streamed exports and connections with subscriptions share it.
+pub struct Heartbeat {
+    interval: Option<Duration>,
+    last_write: Instant,
+}
+
+impl Heartbeat {
+    /// No heartbeat is sent if `interval` is None.
+    pub fn new(interval: Option<Duration>) -> Self {
+        Self {
+            interval,
+            last_write: Instant::now(),
+        }
+    }
+
+    /// Records a line written to the connection, which postpones the next heartbeat.
+    pub fn wrote(&mut self) {
+        self.last_write = Instant::now();
+    }
+
+    /// Waits until a heartbeat is due. Never finishes if heartbeats are disabled.
+    pub fn due(&self) -> impl Future<Output = ()> {
+        let deadline = self.interval.map(|interval| self.last_write + interval);
+        async move {
+            match deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        }
+    }
+
+    /// Writes a heartbeat. An error means the client has gone away.
+    pub async fn send(&mut self, out: &mut (dyn AsyncWrite + Send + Unpin)) -> std::io::Result<()> {
+        write_line(out, HEARTBEAT).await?;
+        self.wrote();
+        Ok(())
+    }
+}
+
+// Returns whether the error may go away by itself, so that a scheduled tweet failing with it is tried again later.
+fn is_transient(err: &AppError) -> bool {
+    match err {
+        AppError::ApiClient(err) => {
+            err.is_network() || matches!(err, ApiClientError::RateLimited { .. })
+        }
+        AppError::CredentialStore(CredentialStoreError::Database(_)) => true,
+        _ => false,
+    }
+}
+
+/// Returns whether the string is a tweet id, which consists of digits.
+fn is_tweet_id(id: &str) -> bool {
+    !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit())
 }
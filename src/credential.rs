@@ -1,42 +1,89 @@
-use std::{cell::RefCell, collections::HashMap, path::PathBuf, sync::Arc};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
 
+use serde::Serialize;
 use sqlx::PgPool;
 use thiserror::Error;
-use tracing::info;
+use tokio::{sync::Semaphore, task::JoinSet};
+use tracing::{info, warn};
 use uuid::Uuid;
 
 use crate::{
-    api::ApiClient,
-    auth::Auth,
+    api::{ApiClient, ApiClientError},
+    auth::{Auth, AuthError, NewAccount},
     cache::{Cache, CacheManager, CacheManagerError, Credential, CredentialState},
+    clock,
     error::AppError,
+    scheduler::Scheduler,
 };
 
 #[derive(Debug, Error)]
 pub enum CredentialStoreError {
     #[error("unknown account: {0}")]
     UnknownAccount(String),
+    #[error("the tokens of account {0} could not be refreshed and may have been revoked. add the account again")]
+    Revoked(String),
     #[error(transparent)]
     CacheManager(#[from] CacheManagerError),
     #[error("database error: {0}")]
     Database(#[from] sqlx::Error),
 }
 
+#[derive(Debug, Serialize)]
+pub struct AccountSummary {
+    pub twitter_id: String,
+    pub session_key: String,
+    // The last time a request used this account, in epoch sec. None if it has never been used.
+    pub last_used_at: Option<i64>,
+    // Avatar as of the last time the token was validated. None if it has not been fetched yet.
+    pub profile_image_url: Option<String>,
+    // Username as of the last time the token was validated. None if it has not been fetched yet.
+    pub username: Option<String>,
+    // `valid` or `expired` as of the last time the token was validated. `expired` accounts need to be authorized again. `cached` if it has not been validated yet.
+    pub token_state: CredentialState,
+}
+
+// How many accounts are checked at once by healthcheck
+const HEALTHCHECK_CONCURRENCY: usize = 4;
+
+#[derive(Debug, Serialize)]
+pub struct AccountHealth {
+    pub twitter_id: String,
+    // Whether the Twitter API could be reached with the account.
+    pub reachable: bool,
+    // `valid` or `expired`. `cached` if it could not be determined.
+    pub token_state: CredentialState,
+    // Round-trip time of the check in milliseconds.
+    pub latency_ms: u128,
+    pub error: Option<String>,
+}
+
 pub struct CredentialStore {
     cm: CacheManager,
-    credentials: RefCell<HashMap<String, Credential>>,
+    credentials: Mutex<HashMap<String, Credential>>,
+    // Twitter id -> the state of the token as of the last time it was used or checked
+    states: Arc<Mutex<HashMap<String, CredentialState>>>,
     auth: Auth,
     conn: Arc<PgPool>,
+    pub scheduler: Arc<Scheduler>,
 }
 
 impl CredentialStore {
     pub fn new(
         cache_path: PathBuf,
+        cache_key: Option<String>,
         auth: Auth,
         conn: PgPool,
+        scheduler: Arc<Scheduler>,
     ) -> Result<Self, CredentialStoreError> {
-        let cm = CacheManager::new(cache_path);
-        let Cache { accounts, scopes } = cm.load()?.unwrap_or_default();
+        let cm = CacheManager::new(cache_path).with_passphrase(cache_key);
+        let Cache {
+            accounts, scopes, ..
+        } = cm.load()?.unwrap_or_default();
 
         let credentials = if scopes == auth.scopes {
             accounts
@@ -47,8 +94,10 @@ impl CredentialStore {
         Ok(Self {
             cm,
             auth,
-            credentials: RefCell::new(credentials),
+            credentials: Mutex::new(credentials),
+            states: Arc::new(Mutex::new(HashMap::new())),
             conn: Arc::new(conn),
+            scheduler,
         })
     }
 
@@ -66,22 +115,89 @@ impl CredentialStore {
         Ok(rec.twitter_id)
     }
 
-    // Returns Twitter accounts (id and session key) available to the current user (the account which they were authenticated and ones they own).
+    /// Returns the scopes granted when the account was authorized, or None if they were not recorded (accounts added by older versions).
+    pub async fn scopes_for(
+        &self,
+        session_key: &str,
+    ) -> Result<Option<Vec<String>>, CredentialStoreError> {
+        let rec = sqlx::query!(
+            "select scopes from accounts where session_key = $1",
+            session_key
+        )
+        .fetch_one(self.conn.as_ref())
+        .await
+        .map_err(maybe_notfound(session_key.into()))?;
+
+        Ok(rec.scopes)
+    }
+
+    // Returns Twitter accounts available to the current user (the account which they were authenticated and ones they own), most recently used first.
     pub async fn accounts(
         &self,
         session_key: &str,
-    ) -> Result<HashMap<String, String>, CredentialStoreError> {
-        let accounts = sqlx::query!("select twitter_id, session_key from accounts where session_key = $1 or owned_by = (select id from accounts where session_key = $1)", session_key)
+    ) -> Result<Vec<AccountSummary>, CredentialStoreError> {
+        let accounts = sqlx::query!("select twitter_id, session_key, last_used_at, profile_image_url, username, token_state from accounts where session_key = $1 or owned_by = (select id from accounts where session_key = $1) order by last_used_at desc nulls last, id", session_key)
             .fetch_all(self.conn.as_ref())
             .await?
             .into_iter()
             // TODO: authenticate if needed
-            .map(|rec| (rec.twitter_id, rec.session_key.unwrap_or("".to_owned())))
+            .map(|rec| AccountSummary {
+                twitter_id: rec.twitter_id,
+                session_key: rec.session_key.unwrap_or("".to_owned()),
+                last_used_at: rec.last_used_at,
+                profile_image_url: rec.profile_image_url,
+                username: rec.username,
+                token_state: CredentialState::from_column(rec.token_state.as_deref()),
+            })
             .collect();
 
         Ok(accounts)
     }
 
+    /// Returns the number of accounts.
+    pub async fn account_count(&self) -> Result<i64, CredentialStoreError> {
+        let rec = sqlx::query!("select count(*) as count from accounts")
+            .fetch_one(self.conn.as_ref())
+            .await?;
+
+        Ok(rec.count.unwrap_or_default())
+    }
+
+    /// Returns how many accounts were valid and expired the last time they were used or checked. Accounts not used since the start are in neither. This calls neither the API nor the database.
+    pub fn known_states(&self) -> (usize, usize) {
+        let states = self.states.lock().unwrap();
+        let count_of = |state| states.values().filter(|s| **s == state).count();
+        (
+            count_of(CredentialState::Valid),
+            count_of(CredentialState::Expired),
+        )
+    }
+
+    // Records the state of the token, so that it is listed along with the account. A failure to save it is only logged, as it should not fail the request which found the state.
+    async fn remember_state(&self, twitter_id: &str, state: CredentialState) {
+        let Some(column) = state.column() else {
+            return;
+        };
+        self.states
+            .lock()
+            .unwrap()
+            .insert(twitter_id.to_owned(), state);
+        if let Some(cred) = self.credentials.lock().unwrap().get_mut(twitter_id) {
+            cred.state = state;
+        }
+
+        let result = sqlx::query!(
+            "update accounts set token_state = $1 where twitter_id = $2 and token_state is distinct from $1",
+            column,
+            twitter_id
+        )
+        .execute(self.conn.as_ref())
+        .await;
+        if let Err(err) = result {
+            warn!("could not save the token state of {}: {}", twitter_id, err);
+        }
+    }
+
     pub async fn client_for(&self, session_key: &str) -> Result<ApiClient, AppError> {
         let rec = sqlx::query!(
             r#"
@@ -96,6 +212,16 @@ impl CredentialStore {
             other => other.into(),
         })?;
 
+        let now = clock::now();
+        sqlx::query!(
+            "update accounts set last_used_at = $1 where id = $2",
+            now,
+            rec.id
+        )
+        .execute(self.conn.as_ref())
+        .await
+        .map_err(CredentialStoreError::Database)?;
+
         let cred = Credential {
             access_token: rec.access_token,
             refresh_token: rec.refresh_token,
@@ -114,8 +240,8 @@ impl CredentialStore {
 
         if state == CredentialState::Valid {
             info!("found valid token for {session_key}");
-            match ApiClient::new(cred.access_token.clone()).await {
-                Ok(client) => return Ok(client),
+            match ApiClient::new(cred.access_token.clone(), self.scheduler.clone()).await {
+                Ok(client) => return self.remember_profile(rec.id, client).await,
                 Err(_) => state = CredentialState::Expired,
             }
         }
@@ -139,18 +265,305 @@ impl CredentialStore {
                     .map_err(CredentialStoreError::Database)?;
 
                     info!("successfully refreshed tokens");
-                    let client = ApiClient::new(acc).await?;
-                    return Ok(client);
+                    let client = ApiClient::new(acc, self.scheduler.clone()).await?;
+                    return self.remember_profile(rec.id, client).await;
+                }
+                Err(AuthError::InvalidGrant(reason)) => {
+                    warn!(
+                        "could not refresh tokens for {}: {}",
+                        rec.twitter_id, reason
+                    );
+                    self.remember_state(&rec.twitter_id, CredentialState::Expired)
+                        .await;
+                    return Err(CredentialStoreError::Revoked(rec.twitter_id).into());
+                }
+                // the token may still be refreshed once the token endpoint is reachable again
+                Err(AuthError::Api(err)) => {
+                    warn!("could not refresh tokens for {}: {}", rec.twitter_id, err);
+                    return Err(err.into());
+                }
+                Err(err) => {
+                    warn!("could not refresh tokens for {}: {}", rec.twitter_id, err);
+                    return Err(err.into());
                 }
-                Err(e) => return Err(e.into()),
             };
         }
 
         unreachable!();
     }
 
+    // Saves the avatar fetched while validating the token, so that account lists stay up to date
+    async fn remember_profile(&self, id: i32, client: ApiClient) -> Result<ApiClient, AppError> {
+        self.remember_state(&client.user_id, CredentialState::Valid)
+            .await;
+        sqlx::query!(
+            "update accounts set profile_image_url = $1, username = $2 where id = $3 and (profile_image_url is distinct from $1 or username is distinct from $2)",
+            client.profile_image_url,
+            client.username,
+            id
+        )
+        .execute(self.conn.as_ref())
+        .await
+        .map_err(CredentialStoreError::Database)?;
+
+        Ok(client)
+    }
+
+    /// Checks the connectivity and the token of every account available to the session by calling `users/me`. The checks run concurrently.
+    pub async fn healthcheck(
+        &self,
+        session_key: &str,
+    ) -> Result<Vec<AccountHealth>, CredentialStoreError> {
+        let accounts = sqlx::query!(
+            "select twitter_id, access_token from accounts where session_key = $1 or owned_by = (select id from accounts where session_key = $1) order by id",
+            session_key
+        )
+        .fetch_all(self.conn.as_ref())
+        .await?;
+
+        let semaphore = Arc::new(Semaphore::new(HEALTHCHECK_CONCURRENCY));
+        let mut checks = JoinSet::new();
+        for (i, rec) in accounts.into_iter().enumerate() {
+            let semaphore = semaphore.clone();
+            checks.spawn(async move {
+                // SAFETY: the semaphore is never closed
+                let _permit = semaphore.acquire().await.unwrap();
+                let start = Instant::now();
+                let result = ApiClient::validate_token(&rec.access_token).await;
+                let latency_ms = start.elapsed().as_millis();
+
+                let (reachable, token_state, error) = match result {
+                    Ok(true) => (true, CredentialState::Valid, None),
+                    Ok(false) => (true, CredentialState::Expired, None),
+                    Err(err) if err.is_network() || matches!(err, ApiClientError::Http(_)) => {
+                        (false, CredentialState::Cached, Some(err.to_string()))
+                    }
+                    Err(err) => (true, CredentialState::Cached, Some(err.to_string())),
+                };
+                let health = AccountHealth {
+                    twitter_id: rec.twitter_id,
+                    reachable,
+                    token_state,
+                    latency_ms,
+                    error,
+                };
+                (i, health)
+            });
+        }
+
+        let mut reports = vec![];
+        while let Some(result) = checks.join_next().await {
+            match result {
+                Ok(report) => reports.push(report),
+                Err(err) => warn!("healthcheck task failed: {}", err),
+            }
+        }
+        reports.sort_by_key(|(i, _)| *i);
+        for (_, health) in &reports {
+            self.remember_state(&health.twitter_id, health.token_state)
+                .await;
+        }
+
+        Ok(reports.into_iter().map(|(_, health)| health).collect())
+    }
+
+    /// Validates the tokens of the accounts available to the session (or every account if `session_key` is None), and removes the ones which are invalid even after trying to refresh them. Returns the Twitter ids of the removed accounts.
+    pub async fn prune(&self, session_key: Option<&str>) -> Result<Vec<String>, AppError> {
+        let accounts = sqlx::query!(
+            "select id, twitter_id, access_token, refresh_token from accounts where $1::text is null or session_key = $1 or owned_by = (select id from accounts where session_key = $1) order by id",
+            session_key
+        )
+        .fetch_all(self.conn.as_ref())
+        .await
+        .map_err(CredentialStoreError::Database)?;
+
+        let mut removed = vec![];
+        for rec in accounts {
+            match ApiClient::validate_token(&rec.access_token).await {
+                Ok(true) => {
+                    self.remember_state(&rec.twitter_id, CredentialState::Valid)
+                        .await;
+                    continue;
+                }
+                Ok(false) => {}
+                Err(err) => {
+                    // do not remove accounts only because Twitter is unreachable
+                    warn!(
+                        "could not validate the token for {}: {}",
+                        rec.twitter_id, err
+                    );
+                    continue;
+                }
+            }
+
+            match self.auth.refresh_tokens(rec.refresh_token).await {
+                Ok((acc, refr)) => {
+                    sqlx::query!(
+                        "update accounts set access_token = $1, refresh_token = $2 where id = $3",
+                        acc,
+                        refr,
+                        rec.id
+                    )
+                    .execute(self.conn.as_ref())
+                    .await
+                    .map_err(CredentialStoreError::Database)?;
+                    self.remember_state(&rec.twitter_id, CredentialState::Valid)
+                        .await;
+                }
+                Err(AuthError::InvalidGrant(reason)) => {
+                    info!("removing account {}: {}", rec.twitter_id, reason);
+                    if self.delete_account(rec.id, &rec.twitter_id).await? {
+                        removed.push(rec.twitter_id);
+                    }
+                }
+                Err(err) => {
+                    warn!(
+                        "could not refresh the token for {}: {}",
+                        rec.twitter_id, err
+                    );
+                }
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// Revokes the tokens of the account and forgets it. The account is removed even if the revocation fails, in which case the error is returned along with the Twitter id.
+    pub async fn remove(
+        &self,
+        session_key: &str,
+    ) -> Result<(String, Option<String>), CredentialStoreError> {
+        let rec = sqlx::query!(
+            "select id, twitter_id, access_token, refresh_token from accounts where session_key = $1",
+            session_key
+        )
+        .fetch_one(self.conn.as_ref())
+        .await
+        .map_err(maybe_notfound(session_key.into()))?;
+
+        let revocation_error = match self
+            .auth
+            .revoke_tokens(rec.access_token, rec.refresh_token)
+            .await
+        {
+            Ok(()) => None,
+            Err(err) => {
+                warn!(
+                    "could not revoke the tokens of {}. removing it anyway: {}",
+                    rec.twitter_id, err
+                );
+                Some(err.to_string())
+            }
+        };
+        info!("removing account {}", rec.twitter_id);
+        if !self.delete_account(rec.id, &rec.twitter_id).await? {
+            // removed by another request in the meantime
+            return Err(CredentialStoreError::UnknownAccount(session_key.into()));
+        }
+
+        Ok((rec.twitter_id, revocation_error))
+    }
+
+    // Deletes the account. Accounts it owned are left without an owner.
+    // Returns false if the account was not there anymore. The cache is saved without the account, and a failure to save it is only logged as the account is gone from the database anyway.
+    async fn delete_account(
+        &self,
+        id: i32,
+        twitter_id: &str,
+    ) -> Result<bool, CredentialStoreError> {
+        let mut tx = self.conn.begin().await?;
+        sqlx::query!(
+            "update accounts set owned_by = null where owned_by = $1",
+            id
+        )
+        .execute(&mut tx)
+        .await?;
+        let result = sqlx::query!("delete from accounts where id = $1", id)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+
+        self.credentials.lock().unwrap().remove(twitter_id);
+        self.states.lock().unwrap().remove(twitter_id);
+        if let Err(err) = self.save_cache() {
+            warn!(
+                "could not save the cache after removing {}: {}",
+                twitter_id, err
+            );
+        }
+
+        Ok(true)
+    }
+
+    /// Writes the credentials in memory to the cache file.
+    pub fn save_cache(&self) -> Result<(), AppError> {
+        let credentials = self.credentials.lock().unwrap().clone();
+        self.cm.save(self.auth.scopes.clone(), credentials)
+    }
+
+    /// Reloads the cache file and saves the credentials in memory over it. Accounts added to the file by another process (e.g. the `add` command) while the daemon was running are kept. For accounts in both, the credential in memory wins as its tokens are the ones refreshed last.
+    pub fn merge_and_save_cache(&self) -> Result<(), AppError> {
+        let Cache {
+            accounts, scopes, ..
+        } = self.cm.load()?.unwrap_or_default();
+        let mut credentials = if scopes == self.auth.scopes {
+            accounts
+        } else {
+            HashMap::new()
+        };
+        credentials.extend(self.credentials.lock().unwrap().clone());
+        self.cm.save(self.auth.scopes.clone(), credentials)
+    }
+
+    /// Twitter ids of the accounts in the cache, sorted.
+    pub fn cached_accounts(&self) -> Vec<String> {
+        let mut ids: Vec<String> = self.credentials.lock().unwrap().keys().cloned().collect();
+        ids.sort();
+        ids
+    }
+
+    /// Authorizes a new account in the foreground (see `Auth::add_account`) and saves it. The credential is merged into the cache, leaving the other accounts as they are. Returns the Twitter id and the session key.
+    pub async fn add_account(
+        &self,
+        owner_key: Option<String>,
+        open: impl FnOnce(&str),
+    ) -> Result<(String, String), AppError> {
+        let NewAccount {
+            client,
+            credential,
+            scopes,
+        } = self.auth.add_account(self.scheduler.clone(), open).await?;
+
+        let session_key = Uuid::new_v4().to_string();
+        save_account(
+            &client,
+            &credential,
+            scopes,
+            owner_key,
+            &self.conn,
+            &session_key,
+        )
+        .await?;
+
+        let twitter_id = client.user_id;
+        self.states
+            .lock()
+            .unwrap()
+            .insert(twitter_id.clone(), CredentialState::Valid);
+        self.credentials
+            .lock()
+            .unwrap()
+            .insert(twitter_id.clone(), credential);
+        self.save_cache()?;
+
+        Ok((twitter_id, session_key))
+    }
+
     pub async fn start_auth(
-        &mut self,
+        &self,
         owner_key: Option<String>,
     ) -> Result<(String, String), AppError> {
         let session_key = Uuid::new_v4().to_string();
@@ -158,12 +571,29 @@ impl CredentialStore {
             .auth
             .start_auth({
                 let conn = self.conn.clone();
+                let scheduler = self.scheduler.clone();
+                let states = self.states.clone();
                 let session_key = session_key.clone();
-                move |acc, refr| {
+                move |acc, refr, scopes| {
                     tokio::spawn(async move {
-                        info!("token retrieved: {}, {}", acc, refr);
-                        match add_credential(acc, refr, owner_key, conn, session_key).await {
-                            Ok(_) => {}
+                        info!("token retrieved for session {}", session_key);
+                        match add_credential(
+                            acc,
+                            refr,
+                            scopes,
+                            owner_key,
+                            conn,
+                            scheduler,
+                            session_key,
+                        )
+                        .await
+                        {
+                            Ok(twitter_id) => {
+                                states
+                                    .lock()
+                                    .unwrap()
+                                    .insert(twitter_id, CredentialState::Valid);
+                            }
                             Err(err) => {
                                 tracing::error!("error while adding credentials: {}", err);
                             }
@@ -180,12 +610,32 @@ impl CredentialStore {
 async fn add_credential(
     access_token: String,
     refresh_token: String,
+    scopes: Vec<String>,
     owner_key: Option<String>,
     conn: Arc<PgPool>,
+    scheduler: Arc<Scheduler>,
     session_key: String,
-) -> Result<(), AppError> {
-    let client = ApiClient::new(access_token.clone()).await?;
+) -> Result<String, AppError> {
+    let client = ApiClient::new(access_token.clone(), scheduler).await?;
+    let credential = Credential {
+        access_token,
+        refresh_token,
+        state: CredentialState::Valid,
+    };
+
+    save_account(&client, &credential, scopes, owner_key, &conn, &session_key).await?;
+    Ok(client.user_id)
+}
 
+// Inserts the account, or updates it if it has been added already. The token has just been issued, so it is saved as valid.
+async fn save_account(
+    client: &ApiClient,
+    credential: &Credential,
+    scopes: Vec<String>,
+    owner_key: Option<String>,
+    conn: &PgPool,
+    session_key: &str,
+) -> Result<(), AppError> {
     let owner_id = match owner_key {
         Some(key) => sqlx::query!(
             r#"
@@ -193,7 +643,7 @@ async fn add_credential(
                 "#,
             key
         )
-        .fetch_one(conn.as_ref())
+        .fetch_one(conn)
         .await
         .map(|rec| rec.id)
         .map(Some)
@@ -204,18 +654,21 @@ async fn add_credential(
     sqlx::query!(
         r#"
             insert into accounts
-                (twitter_id, access_token, refresh_token, session_key, owned_by)
-            values ($1, $2, $3, $4, $5)
+                (twitter_id, access_token, refresh_token, session_key, owned_by, scopes, profile_image_url, username, token_state)
+            values ($1, $2, $3, $4, $5, $6, $7, $8, 'valid')
             on conflict (twitter_id) do
-                update set access_token = $2, refresh_token = $3, session_key = $4, owned_by = $5
+                update set access_token = $2, refresh_token = $3, session_key = $4, owned_by = $5, scopes = $6, profile_image_url = $7, username = $8, token_state = 'valid'
             "#,
         client.user_id,
-        access_token,
-        refresh_token,
+        credential.access_token,
+        credential.refresh_token,
         session_key,
-        owner_id
+        owner_id,
+        &scopes,
+        client.profile_image_url,
+        client.username
     )
-    .execute(conn.as_ref())
+    .execute(conn)
     .await
     .map_err(CredentialStoreError::Database)?;
 
@@ -1,6 +1,7 @@
 use crate::{
     api::ApiClientError, auth::AuthError, cache::CacheManagerError, connection::HandlerError,
-    credential::CredentialStoreError, filter::FilterError, ListenerError,
+    credential::CredentialStoreError, filter::FilterError, serve::ListenerError,
+    store::TweetStoreError,
 };
 use thiserror::Error;
 
@@ -15,7 +16,9 @@ pub enum AppError {
     CacheManager(#[from] CacheManagerError),
     #[error("cred store error: {0}")]
     CredentialStore(#[from] CredentialStoreError),
-    #[error("auth error")]
+    #[error("tweet store error: {0}")]
+    TweetStore(#[from] TweetStoreError),
+    #[error("auth error: {0}")]
     Auth(#[from] AuthError),
     #[error("api client error: {0}")]
     ApiClient(#[from] ApiClientError),
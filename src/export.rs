@@ -0,0 +1,104 @@
+use serde::{Deserialize, Serialize};
+
+use crate::tweet::Tweet;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Json,
+    Csv,
+    // One tweet per line. Streamed over the connection as tweets are fetched.
+    Ndjson,
+}
+
+const CSV_HEADER: [&str; 9] = [
+    "id",
+    "created_at",
+    "author_id",
+    "author_username",
+    "text",
+    "retweet_count",
+    "reply_count",
+    "like_count",
+    "quote_count",
+];
+
+/// Serializes the tweets into a single document in the given format.
+pub fn render(tweets: &[Tweet], format: ExportFormat) -> Result<String, serde_json::Error> {
+    match format {
+        ExportFormat::Json => serde_json::to_string(tweets),
+        ExportFormat::Csv => Ok(to_csv(tweets)),
+        ExportFormat::Ndjson => tweets
+            .iter()
+            .map(|t| serde_json::to_string(t).map(|line| line + "\n"))
+            .collect(),
+    }
+}
+
+/// Flattens the key fields of the tweets into CSV (RFC 4180) with a header row.
+fn to_csv(tweets: &[Tweet]) -> String {
+    let mut out = CSV_HEADER.join(",");
+    out.push_str("\r\n");
+    for tweet in tweets {
+        let metrics = tweet.field("public_metrics");
+        let row = [
+            tweet.field("id"),
+            tweet.field("created_at"),
+            tweet.field("author_id"),
+            &tweet.field("author")["username"],
+            tweet.field("text"),
+            &metrics["retweet_count"],
+            &metrics["reply_count"],
+            &metrics["like_count"],
+            &metrics["quote_count"],
+        ];
+        let cells: Vec<String> = row.iter().map(|v| csv_cell(v)).collect();
+        out.push_str(&cells.join(","));
+        out.push_str("\r\n");
+    }
+    out
+}
+
+fn csv_cell(value: &serde_json::Value) -> String {
+    let s = match value {
+        serde_json::Value::Null => return String::new(),
+        serde_json::Value::String(s) => s.clone(),
+        other => other.to_string(),
+    };
+    if s.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_escapes_cells() -> Result<(), Box<dyn std::error::Error>> {
+        let tweets: Vec<Tweet> = serde_json::from_str(
+            r#"[
+                {
+                    "id": "1",
+                    "created_at": "2022-10-27T15:41:35.000Z",
+                    "author_id": "42",
+                    "author": { "id": "42", "username": "alice" },
+                    "text": "hello, \"world\"\nbye",
+                    "public_metrics": { "retweet_count": 1, "reply_count": 2, "like_count": 3, "quote_count": 4 }
+                },
+                { "id": "2", "text": "plain" }
+            ]"#,
+        )?;
+
+        let csv = render(&tweets, ExportFormat::Csv)?;
+        let expected = "id,created_at,author_id,author_username,text,retweet_count,reply_count,like_count,quote_count\r\n\
+            1,2022-10-27T15:41:35.000Z,42,alice,\"hello, \"\"world\"\"\nbye\",1,2,3,4\r\n\
+            2,,,,plain,,,,\r\n";
+        assert_eq!(csv, expected);
+
+        Ok(())
+    }
+}
@@ -1,29 +1,207 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::{
+    cell::Cell,
     collections::HashSet,
     fs::File,
     io::Read,
     path::{Path, PathBuf},
+    rc::Rc,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 use thiserror::Error;
-use tracing::error;
+use tokio::runtime::{Handle, RuntimeFlavor};
+use tracing::{debug, error, info, warn};
 
-use crate::{error::AppError, tweet::Tweet};
-use mlua::prelude::*;
+use crate::{snippet, tweet::Tweet};
+use mlua::{prelude::*, HookTriggers};
 
-#[derive(Debug)]
+// How many VM instructions a filter runs between checks of its timeout
+const TIMEOUT_CHECK_INSTRUCTIONS: u32 = 1000;
+// Longer log messages from a filter are cut to this many characters
+const LOG_MESSAGE_MAX_CHARS: usize = 1000;
+// How many lines a filter can log in a single run. The rest are discarded.
+const LOG_MAX_LINES: usize = 100;
+
+// Responses to `http.get`/`http.post` longer than this are rejected. The body is read no further.
+const HTTP_MAX_BODY_BYTES: usize = 1024 * 1024;
+
+// Writes a message logged by the named filter
+type LogEmitter = fn(&str, &str);
+
+#[derive(Debug, Clone)]
 pub struct Filter {
     pub src: String,
     pub meta: FilterMeta,
+    // Name of the directory the filter was loaded from. Empty for inline filters.
+    pub dir_name: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct FilterMeta {
     name: String,
     description: String,
     author: String,
     entrypoint: String,
     scopes: HashSet<String>,
+    // Profiles this filter belongs to. A filter without profiles runs in every profile.
+    #[serde(default)]
+    pub profiles: HashSet<String>,
+    // Position in the filter chain, which takes precedence over a numeric prefix of the directory name.
+    #[serde(default)]
+    order: Option<i64>,
+}
+
+/// A filter given in a request instead of being installed in the filter directory.
+#[derive(Debug, Clone, Deserialize)]
+pub struct InlineFilter {
+    pub name: Option<String>,
+    // Lua source, which is run in the same way as an installed entrypoint.
+    pub source: String,
+}
+
+/// Request-scoped information such as the user's timezone and locale, which only the frontend knows, and the account being served. Filters can read it through the read-only `ctx` global.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FilterContext {
+    #[serde(default = "FilterContext::default_timezone")]
+    pub timezone: String,
+    #[serde(default = "FilterContext::default_locale")]
+    pub locale: String,
+    // Set by the backend rather than the request: the authenticated account whose tweets are filtered
+    #[serde(default, skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+    // Set by the backend rather than the request: the method filtering the tweets without the version, e.g. `home_timeline` or `search`
+    #[serde(default, skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+}
+
+impl FilterContext {
+    fn default_timezone() -> String {
+        "UTC".to_owned()
+    }
+
+    fn default_locale() -> String {
+        "en".to_owned()
+    }
+
+    /// Tells the filters which account and method they run for.
+    pub fn for_account(self, user_id: &str, method: &str) -> Self {
+        Self {
+            user_id: Some(user_id.to_owned()),
+            method: Some(method.to_owned()),
+            ..self
+        }
+    }
+}
+
+impl Default for FilterContext {
+    fn default() -> Self {
+        Self {
+            timezone: Self::default_timezone(),
+            locale: Self::default_locale(),
+            user_id: None,
+            method: None,
+        }
+    }
+}
+
+/// What happens to a tweet when a filter fails on it, e.g. with a Lua runtime error.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterErrorPolicy {
+    // Go on with the next filter as if the failed one returned the tweet unchanged
+    #[default]
+    SkipFilter,
+    // Drop the tweet as if the failed filter returned null
+    DropTweet,
+}
+
+/// Backs `http.get` and `http.post` in filters. Only the hosts in the allowlist can be requested, so that a filter cannot send the tweets elsewhere.
+#[derive(Debug, Clone)]
+pub struct FilterHttp {
+    client: reqwest::Client,
+    timeout: Duration,
+    allowed_hosts: Arc<HashSet<String>>,
+}
+
+impl FilterHttp {
+    pub fn new(allowed_hosts: Vec<String>, timeout: Duration) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(timeout)
+            // a redirect could lead the request (and a POST body) to a host out of the allowlist
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .expect("the HTTP client for filters could not be built");
+        Self {
+            client,
+            timeout,
+            allowed_hosts: Arc::new(allowed_hosts.into_iter().collect()),
+        }
+    }
+
+    // The request may take no longer than what is left until `deadline`, as the wait counts towards the timeout of the filter
+    fn request(
+        &self,
+        method: reqwest::Method,
+        url: &str,
+        body: Option<String>,
+        deadline: Instant,
+    ) -> LuaResult<(u16, String)> {
+        let parsed = url::Url::parse(url)
+            .map_err(|err| LuaError::RuntimeError(format!("invalid url {}: {}", url, err)))?;
+        let allowed = matches!(parsed.scheme(), "http" | "https")
+            && parsed
+                .host_str()
+                .is_some_and(|host| self.allowed_hosts.contains(host));
+        if !allowed {
+            return Err(LuaError::RuntimeError(format!(
+                "{} is not in filter_http_allowed_hosts",
+                url
+            )));
+        }
+
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let mut req = self
+            .client
+            .request(method, parsed)
+            .timeout(self.timeout.min(remaining));
+        if let Some(body) = body {
+            req = req.body(body);
+        }
+        block_on(async move {
+            let mut resp = req.send().await?;
+            let status = resp.status().as_u16();
+            let mut bytes = Vec::new();
+            while let Some(chunk) = resp.chunk().await? {
+                if bytes.len() + chunk.len() > HTTP_MAX_BODY_BYTES {
+                    return Ok((status, None));
+                }
+                bytes.extend_from_slice(&chunk);
+            }
+            Ok::<_, reqwest::Error>((status, Some(bytes)))
+        })?
+        .map_err(|err| LuaError::RuntimeError(format!("request to {} failed: {}", url, err)))
+        .and_then(|(status, bytes)| match bytes {
+            Some(bytes) => Ok((status, String::from_utf8_lossy(&bytes).into_owned())),
+            None => Err(LuaError::RuntimeError(format!(
+                "the response from {} is larger than {} bytes",
+                url, HTTP_MAX_BODY_BYTES
+            ))),
+        })
+    }
+}
+
+/// What a filter decided to do with a tweet.
+#[derive(Debug, PartialEq)]
+pub enum FilterVerdict {
+    /// The filter returned the tweet unchanged.
+    Keep,
+    /// The filter changed the tweet, or returned an array of tweets to expand it into several. Never empty.
+    Transform(Vec<Tweet>),
+    /// The filter returned nil or an empty array.
+    Drop,
+    /// The filter returned `{ tweet = t, final = true }`. The tweet is accepted and the later filters do not run on it.
+    Accept(Tweet),
 }
 
 // TODO: use struct?
@@ -42,28 +220,67 @@ pub enum FilterError {
 }
 
 impl Filter {
+    /// Loads the filters in the subdirectories of `dirs`, in the order they run.
+    /// A filter with `order` in its binchotan.toml is placed by that value. Otherwise a numeric prefix of the directory name (`10-normalize`) is used.
+    /// Filters with neither run after the others. Ties are broken by the directory name.
+    /// If several of `dirs` contain a filter with the same directory name, the one in the earliest is loaded. Directories which do not exist are skipped, as long as one of them exists.
     pub fn load(
-        dir: &Path,
+        dirs: &[PathBuf],
         available_scopes: &HashSet<String>,
     ) -> Result<Vec<Filter>, FilterError> {
-        if !dir.is_dir() {
-            return Err(FilterError::PathNotDir(dir.to_owned()));
+        let existing: Vec<&PathBuf> = dirs.iter().filter(|dir| dir.is_dir()).collect();
+        if existing.is_empty() {
+            return Err(FilterError::PathNotDir(
+                dirs.first().cloned().unwrap_or_default(),
+            ));
+        }
+        for dir in dirs.iter().filter(|dir| !dir.is_dir()) {
+            warn!(
+                "skipping the filter directory {}: not a directory",
+                dir.display()
+            );
         }
 
-        dir.read_dir()?
-            .filter_map(|entry| match entry {
-                Ok(entry) => Some(entry.path()),
-                _ => None,
-            })
-            .filter(|path| path.is_dir())
-            .map(|dir| match Self::load_single(&dir, available_scopes) {
-                Ok(filter) => Ok(filter),
-                Err(err) => {
-                    error!("could not load filter in {}/ : {}", dir.display(), err);
-                    Err(err)
+        let mut filters: Vec<Filter> = vec![];
+        for dir in existing {
+            let mut paths = dir
+                .read_dir()?
+                .filter_map(|entry| match entry {
+                    Ok(entry) => Some(entry.path()),
+                    _ => None,
+                })
+                .filter(|path| path.is_dir())
+                .collect::<Vec<_>>();
+            // read_dir does not guarantee any order, which decides the warnings below
+            paths.sort();
+            for path in paths {
+                let filter = match Self::load_single(&path, available_scopes) {
+                    Ok(filter) => filter,
+                    Err(err) => {
+                        error!("could not load filter in {}/ : {}", path.display(), err);
+                        return Err(err);
+                    }
+                };
+                if filters.iter().any(|f| f.dir_name == filter.dir_name) {
+                    warn!(
+                        "ignoring the filter in {}/: a filter named {} is in an earlier directory",
+                        path.display(),
+                        filter.dir_name
+                    );
+                    continue;
                 }
-            })
-            .collect()
+                filters.push(filter);
+            }
+        }
+        filters.sort_by_cached_key(|filter| {
+            let order = filter
+                .meta
+                .order
+                .or_else(|| numeric_prefix(&filter.dir_name));
+            (order.is_none(), order, filter.dir_name.clone())
+        });
+
+        Ok(filters)
     }
 
     fn load_single(dir: &Path, available_scopes: &HashSet<String>) -> Result<Filter, FilterError> {
@@ -77,22 +294,764 @@ impl Filter {
         let meta: FilterMeta = toml::from_str(&meta_buf).map_err(FilterError::MetaParse)?;
 
         let mut src = String::new();
-        File::open(&dir.join(&meta.entrypoint))?.read_to_string(&mut src)?;
+        File::open(dir.join(&meta.entrypoint))?.read_to_string(&mut src)?;
 
         let diff: Vec<String> = meta.scopes.difference(available_scopes).cloned().collect();
         if !diff.is_empty() {
             return Err(FilterError::InsufficientScopes(meta.name, diff));
         }
 
-        Ok(Filter { src, meta })
+        let dir_name = dir
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        Ok(Filter {
+            src,
+            meta,
+            dir_name,
+        })
+    }
+
+    /// Builds a filter from an inline source. The source is compiled once here so that syntax errors are reported before any tweet is filtered.
+    pub fn inline(index: usize, filter: InlineFilter) -> Result<Filter, FilterError> {
+        let name = filter.name.unwrap_or_else(|| format!("inline-{}", index));
+        // inline filters come from any client, so they are compiled and run in the same sandbox as installed ones
+        sandbox()?
+            .load(&filter.source)
+            .set_name(&name)?
+            .into_function()?;
+
+        let meta = FilterMeta {
+            name,
+            description: String::new(),
+            author: String::new(),
+            entrypoint: String::new(),
+            scopes: HashSet::new(),
+            profiles: HashSet::new(),
+            order: None,
+        };
+        Ok(Filter {
+            src: filter.source,
+            meta,
+            dir_name: String::new(),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.meta.name
+    }
+
+    /// Describes the filter for the log, e.g. `word mute by sei0o: Mutes some words`.
+    pub fn summary(&self) -> String {
+        format!(
+            "{} by {}: {}",
+            self.meta.name, self.meta.author, self.meta.description
+        )
     }
 
-    /// Applies the filter on the given post. The filter is a Lua script which returns a Tweet or null.
-    pub fn run(&self, tweet: &Tweet) -> Result<Option<Tweet>, FilterError> {
+    /// Returns whether the filter is referred to by the name, which is either its directory name or `name` in binchotan.toml.
+    pub fn is_named(&self, name: &str) -> bool {
+        self.meta.name == name || (!self.dir_name.is_empty() && self.dir_name == name)
+    }
+
+    /// Returns whether the filter should run while the given profile is active. Every filter runs when no profile is active.
+    pub fn in_profile(&self, profile: Option<&str>) -> bool {
+        match profile {
+            Some(profile) => self.meta.profiles.is_empty() || self.meta.profiles.contains(profile),
+            None => true,
+        }
+    }
+
+    /// Applies the filter on the given post. The filter is a Lua script which returns one of:
+    /// - the tweet, changed or not
+    /// - an array of tweets to expand the tweet into several (e.g. to unroll a thread)
+    /// - nil or an empty array to drop the tweet
+    /// - `{ tweet = t, final = true }` to accept `t` (or the tweet as it is, if `tweet` is omitted) and skip the later filters. Without `final = true`, the table stands for `t`. A table with keys other than `tweet` and `final` is a tweet.
+    ///
+    /// Returned tweets inherit the annotations unless the filter gave them their own. The script is aborted with an error once it runs longer than `timeout`, including the time spent waiting for `http.get`/`http.post`.
+    pub fn run(
+        &self,
+        tweet: &Tweet,
+        ctx: &FilterContext,
+        timeout: Duration,
+        http: &FilterHttp,
+    ) -> Result<FilterVerdict, FilterError> {
+        let lua = sandbox()?;
+        let started = Instant::now();
+        lua.set_hook(
+            HookTriggers {
+                every_nth_instruction: Some(TIMEOUT_CHECK_INSTRUCTIONS),
+                ..Default::default()
+            },
+            move |_lua, _debug| {
+                if started.elapsed() > timeout {
+                    return Err(LuaError::RuntimeError(format!(
+                        "the filter did not finish in {:?}",
+                        timeout
+                    )));
+                }
+                Ok(())
+            },
+        )?;
+        snippet::install_require(&lua)?;
+        install_log(&lua, self.name())?;
+        install_http(&lua, http, started + timeout)?;
+        let post = lua.to_value(tweet)?;
+        if let LuaValue::Table(post) = &post {
+            // so that filters can write `post.annotations.score = 0.9` right away
+            post.set("annotations", lua.to_value(&tweet.annotations())?)?;
+        }
+        lua.globals().set("post", post)?;
+        lua.globals()
+            .set("ctx", read_only(&lua, lua.to_value(ctx)?)?)?;
+        let ret: LuaValue = lua.load(&self.src).eval()?;
+
+        // a filter which returns a new table (or clears them) keeps the annotations made so far
+        let inherit = |mut result: Tweet| {
+            if result.annotations().is_empty() {
+                result.set_annotations(tweet.annotations());
+            }
+            result
+        };
+        // a table of only `tweet` and `final` says what to do with the tweet rather than being one
+        let control = match &ret {
+            LuaValue::Table(table) if !is_empty(table) => table
+                .clone()
+                .pairs::<LuaValue, LuaValue>()
+                .all(|pair| {
+                    matches!(pair, Ok((LuaValue::String(key), _)) if key == "tweet" || key == "final")
+                })
+                .then(|| table.clone()),
+            _ => None,
+        };
+        let result: Option<Tweet> = match control {
+            Some(table) => {
+                let given: Option<Tweet> = lua.from_value(table.get("tweet")?)?;
+                if table.get::<_, LuaValue>("final")? == LuaValue::Boolean(true) {
+                    let accepted = given.map(inherit).unwrap_or_else(|| tweet.clone());
+                    return Ok(FilterVerdict::Accept(accepted));
+                }
+                given
+            }
+            None => lua.from_value(ret)?,
+        };
+        let Some(result) = result else {
+            return Ok(FilterVerdict::Drop);
+        };
+        let tweets: Vec<Tweet> = result.into_tweets().into_iter().map(inherit).collect();
+        Ok(match &tweets[..] {
+            [] => FilterVerdict::Drop,
+            [result] if result == tweet => FilterVerdict::Keep,
+            _ => FilterVerdict::Transform(tweets),
+        })
+    }
+}
+
+// Whether the table has no entries at all
+fn is_empty(table: &LuaTable) -> bool {
+    table.clone().pairs::<LuaValue, LuaValue>().next().is_none()
+}
+
+/// Creates the Lua state filters run in. Only the libraries which cannot reach outside the state are loaded, so that a filter cannot run commands (`os`, `io`) or load files (`dofile`, `loadfile`).
+fn sandbox() -> LuaResult<Lua> {
+    let lua = Lua::new_with(
+        LuaStdLib::TABLE | LuaStdLib::STRING | LuaStdLib::MATH | LuaStdLib::UTF8,
+        LuaOptions::default(),
+    )?;
+    let globals = lua.globals();
+    for name in ["dofile", "loadfile", "load"] {
+        globals.set(name, LuaValue::Nil)?;
+    }
+    drop(globals);
+    Ok(lua)
+}
+
+/// Parses the leading digits of a name such as `10-normalize`.
+fn numeric_prefix(name: &str) -> Option<i64> {
+    let end = name
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(name.len());
+    name[..end].parse().ok()
+}
+
+/// Wraps a table so that filters can read it but not change it. Other values are returned as they are.
+fn read_only<'lua>(lua: &'lua Lua, value: LuaValue<'lua>) -> LuaResult<LuaValue<'lua>> {
+    if !matches!(value, LuaValue::Table(_)) {
+        return Ok(value);
+    }
+    lua.load(
+        r#"
+        local t = ...
+        return setmetatable({}, {
+            __index = t,
+            __newindex = function() error("the table is read-only", 2) end,
+            __pairs = function() return next, t, nil end,
+            __len = function() return #t end,
+            __metatable = false,
+        })
+        "#,
+    )
+    .call(value)
+}
+
+/// Exposes `log.info`, `log.warn` and `log.debug` to the filter. They take any number of values of any type and never raise an error.
+fn install_log(lua: &Lua, filter: &str) -> LuaResult<()> {
+    let levels: [(&str, LogEmitter); 3] = [
+        ("info", |filter, msg| info!(filter, "{}", msg)),
+        ("warn", |filter, msg| warn!(filter, "{}", msg)),
+        ("debug", |filter, msg| debug!(filter, "{}", msg)),
+    ];
+    let lines = Rc::new(Cell::new(0));
+
+    let log = lua.create_table()?;
+    for (level, emit) in levels {
+        let filter = filter.to_owned();
+        let lines = lines.clone();
+        let func = lua.create_function(move |lua, args: LuaMultiValue| {
+            lines.set(lines.get() + 1);
+            match lines.get() {
+                n if n <= LOG_MAX_LINES => emit(&filter, &log_message(lua, args)),
+                n if n == LOG_MAX_LINES + 1 => warn!(
+                    filter = filter.as_str(),
+                    "the filter logged more than {} lines; the rest are discarded", LOG_MAX_LINES
+                ),
+                _ => {}
+            }
+            Ok(())
+        })?;
+        log.set(level, func)?;
+    }
+    lua.globals().set("log", log)
+}
+
+/// Exposes `http.get(url)` and `http.post(url, body)` to the filter. Both return a table with `status` and `body`, and raise an error if the request cannot be made or does not finish by `deadline`.
+fn install_http(lua: &Lua, http: &FilterHttp, deadline: Instant) -> LuaResult<()> {
+    let table = lua.create_table()?;
+
+    let client = http.clone();
+    let get = lua.create_function(move |lua, url: String| {
+        let (status, body) = client.request(reqwest::Method::GET, &url, None, deadline)?;
+        http_response(lua, status, body)
+    })?;
+    table.set("get", get)?;
+
+    let client = http.clone();
+    let post = lua.create_function(move |lua, (url, body): (String, String)| {
+        let (status, body) = client.request(reqwest::Method::POST, &url, Some(body), deadline)?;
+        http_response(lua, status, body)
+    })?;
+    table.set("post", post)?;
+
+    lua.globals().set("http", table)
+}
+
+fn http_response(lua: &Lua, status: u16, body: String) -> LuaResult<LuaTable<'_>> {
+    let resp = lua.create_table()?;
+    resp.set("status", status)?;
+    resp.set("body", body)?;
+    Ok(resp)
+}
+
+/// Waits for a future from a filter. Filters run synchronously in the middle of a request, so the request is blocked with `block_in_place` rather than made async: the Lua state is not `Send` and cannot be held across an await. The other tasks queued on the worker thread move to other workers while waiting, but the task running the filter is blocked as a whole. The requests of a batch are joined in one task (see `Handler::handle_batch`), so they all wait while one filter waits for a response. This requires the multi-threaded runtime the daemon runs on.
+fn block_on<F: std::future::Future>(fut: F) -> LuaResult<F::Output> {
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            Ok(tokio::task::block_in_place(|| handle.block_on(fut)))
+        }
+        _ => Err(LuaError::RuntimeError(
+            "http is not available in this runtime".to_owned(),
+        )),
+    }
+}
+
+/// Joins the arguments of a `log` call into a message. Tables are shown as JSON when possible.
+fn log_message(lua: &Lua, args: LuaMultiValue) -> String {
+    let msg = args
+        .into_iter()
+        .map(|value| match value {
+            LuaValue::String(s) => s.to_string_lossy().into_owned(),
+            LuaValue::Table(_) => lua
+                .from_value::<serde_json::Value>(value.clone())
+                .map(|json| json.to_string())
+                .unwrap_or_else(|_| format!("<{}>", value.type_name())),
+            _ => lua
+                .globals()
+                .get::<_, LuaFunction>("tostring")
+                .and_then(|tostring| tostring.call::<_, String>(value.clone()))
+                .unwrap_or_else(|_| format!("<{}>", value.type_name())),
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    match msg.char_indices().nth(LOG_MESSAGE_MAX_CHARS) {
+        Some((end, _)) => format!("{}... (truncated)", &msg[..end]),
+        None => msg,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The tweet which comes out of a filter that does not expand it
+    fn output(verdict: FilterVerdict, tweet: &Tweet) -> Option<Tweet> {
+        match verdict {
+            FilterVerdict::Keep => Some(tweet.clone()),
+            FilterVerdict::Transform(mut tweets) => tweets.pop(),
+            FilterVerdict::Drop => None,
+            FilterVerdict::Accept(tweet) => Some(tweet),
+        }
+    }
+
+    #[test]
+    fn abort_runaway_filter() -> Result<(), Box<dyn std::error::Error>> {
+        let filter = Filter::inline(
+            0,
+            InlineFilter {
+                name: None,
+                source: "while true do end".to_owned(),
+            },
+        )?;
+        let tweet: Tweet = serde_json::from_str(r#"{ "id": "1", "text": "hello" }"#)?;
+
+        let started = Instant::now();
+        let result = filter.run(
+            &tweet,
+            &FilterContext::default(),
+            Duration::from_millis(100),
+            &FilterHttp::new(vec![], Duration::from_secs(1)),
+        );
+        assert!(matches!(result, Err(FilterError::Lua(_))));
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        Ok(())
+    }
+
+    #[test]
+    fn no_access_outside_the_filter() -> Result<(), Box<dyn std::error::Error>> {
+        let filter = Filter::inline(
+            0,
+            InlineFilter {
+                name: None,
+                source: r#"
+                    post.text = table.concat({ type(os), type(io), type(load), type(dofile), type(loadfile), type(string.format) }, ",")
+                    return post
+                "#
+                .to_owned(),
+            },
+        )?;
+        let tweet: Tweet = serde_json::from_str(r#"{ "id": "1", "text": "hello" }"#)?;
+
+        let result = filter.run(
+            &tweet,
+            &FilterContext::default(),
+            Duration::from_secs(1),
+            &FilterHttp::new(vec![], Duration::from_secs(1)),
+        )?;
+        let FilterVerdict::Transform(result) = result else {
+            panic!("the filter did not run");
+        };
+        assert_eq!(
+            result[0].field("text").as_str(),
+            Some("nil,nil,nil,nil,nil,function")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn sandbox_inline_filters() -> Result<(), Box<dyn std::error::Error>> {
+        let tweet: Tweet = serde_json::from_str(r#"{ "id": "1", "text": "hello" }"#)?;
+        let http = FilterHttp::new(vec![], Duration::from_secs(1));
+        for source in [
+            "io.popen('curl http://example.com') return post",
+            "os.execute('true') return post",
+            "return dofile('/etc/passwd')",
+        ] {
+            let filter = Filter::inline(
+                0,
+                InlineFilter {
+                    name: None,
+                    source: source.to_owned(),
+                },
+            )?;
+            let result = filter.run(
+                &tweet,
+                &FilterContext::default(),
+                Duration::from_secs(1),
+                &http,
+            );
+            assert!(matches!(result, Err(FilterError::Lua(_))), "{}", source);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn log_from_filter() -> Result<(), Box<dyn std::error::Error>> {
+        let filter = Filter::inline(
+            0,
+            InlineFilter {
+                name: None,
+                source: r#"
+                    log.info("checking", post.id)
+                    log.warn({ nested = { 1, 2 } }, nil, true)
+                    log.debug(string.rep("x", 5000), function() end)
+                    for i = 1, 200 do log.info(i) end
+                    return post
+                "#
+                .to_owned(),
+            },
+        )?;
+        let tweet: Tweet = serde_json::from_str(r#"{ "id": "1", "text": "hello" }"#)?;
+
+        let result = filter.run(
+            &tweet,
+            &FilterContext::default(),
+            Duration::from_secs(1),
+            &FilterHttp::new(vec![], Duration::from_secs(1)),
+        )?;
+        assert_eq!(result, FilterVerdict::Keep);
+
         let lua = Lua::new();
-        lua.globals().set("post", lua.to_value(tweet)?)?;
-        let ret = lua.load(&self.src).eval()?;
-        let v: Option<Tweet> = lua.from_value(ret)?;
-        Ok(v)
+        let args = (lua.create_string(&"あ".repeat(2000))?,).to_lua_multi(&lua)?;
+        let msg = log_message(&lua, args);
+        assert_eq!(
+            msg,
+            format!("{}... (truncated)", "あ".repeat(LOG_MESSAGE_MAX_CHARS))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_account_context() -> Result<(), Box<dyn std::error::Error>> {
+        let tweet: Tweet =
+            serde_json::from_str(r#"{ "id": "1", "text": "hello", "author_id": "42" }"#)?;
+        let run = |source: &str, ctx: &FilterContext| -> Result<Option<Tweet>, FilterError> {
+            let filter = Filter::inline(
+                0,
+                InlineFilter {
+                    name: None,
+                    source: source.to_owned(),
+                },
+            )?;
+            filter
+                .run(
+                    &tweet,
+                    ctx,
+                    Duration::from_secs(1),
+                    &FilterHttp::new(vec![], Duration::from_secs(1)),
+                )
+                .map(|verdict| output(verdict, &tweet))
+        };
+        let ctx = FilterContext::default().for_account("42", "home_timeline");
+        // the user_id and the method cannot be given by the request
+        let requested: FilterContext =
+            serde_json::from_str(r#"{ "user_id": "1", "method": "search" }"#)?;
+        assert!(requested.user_id.is_none() && requested.method.is_none());
+
+        let own = "if post.author_id == ctx.user_id and ctx.method == 'home_timeline' then post.text = 'mine' end return post";
+        let result = run(own, &ctx)?.unwrap();
+        assert_eq!(result.field("text").as_str(), Some("mine"));
+        let result = run(own, &FilterContext::default())?.unwrap();
+        assert_eq!(result.field("text").as_str(), Some("hello"));
+
+        let keys =
+            "local n = 0 for _ in pairs(ctx) do n = n + 1 end post.text = tostring(n) return post";
+        assert_eq!(run(keys, &ctx)?.unwrap().field("text").as_str(), Some("4"));
+        assert!(matches!(
+            run("ctx.user_id = '1' return post", &ctx),
+            Err(FilterError::Lua(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn annotate_tweets() -> Result<(), Box<dyn std::error::Error>> {
+        let run = |source: &str, tweet: &Tweet| -> Result<Option<Tweet>, FilterError> {
+            let filter = Filter::inline(
+                0,
+                InlineFilter {
+                    name: None,
+                    source: source.to_owned(),
+                },
+            )?;
+            filter
+                .run(
+                    tweet,
+                    &FilterContext::default(),
+                    Duration::from_secs(1),
+                    &FilterHttp::new(vec![], Duration::from_secs(1)),
+                )
+                .map(|verdict| output(verdict, tweet))
+        };
+        let tweet: Tweet = serde_json::from_str(r#"{ "id": "1", "text": "hello" }"#)?;
+
+        let untouched = run("return post", &tweet)?.unwrap();
+        assert!(untouched.field("annotations").is_null());
+
+        let scored = run("post.annotations.score = 0.9 return post", &tweet)?.unwrap();
+        let translated = run(
+            "post.annotations.translated_text = 'こんにちは' return post",
+            &scored,
+        )?
+        .unwrap();
+        assert_eq!(translated.field("annotations")["score"], 0.9);
+        assert_eq!(
+            translated.field("annotations")["translated_text"],
+            "こんにちは"
+        );
+
+        let rebuilt = run("return { id = post.id, text = 'replaced' }", &translated)?.unwrap();
+        assert_eq!(rebuilt.annotations(), translated.annotations());
+
+        Ok(())
+    }
+
+    #[test]
+    fn accept_as_final() -> Result<(), Box<dyn std::error::Error>> {
+        let run = |source: &str, tweet: &Tweet| -> Result<FilterVerdict, FilterError> {
+            let filter = Filter::inline(
+                0,
+                InlineFilter {
+                    name: None,
+                    source: source.to_owned(),
+                },
+            )?;
+            filter.run(
+                tweet,
+                &FilterContext::default(),
+                Duration::from_secs(1),
+                &FilterHttp::new(vec![], Duration::from_secs(1)),
+            )
+        };
+        let tweet: Tweet = serde_json::from_str(
+            r#"{ "id": "1", "text": "hello", "annotations": { "score": 1 } }"#,
+        )?;
+
+        let changed = run("post.text = 'bye' return post", &tweet)?;
+        let FilterVerdict::Transform(changed) = changed else {
+            panic!("the tweet was not changed");
+        };
+        assert_eq!(changed[0].field("text").as_str(), Some("bye"));
+
+        let accepted = run(
+            "return { tweet = { id = post.id, text = 'ok' }, final = true }",
+            &tweet,
+        )?;
+        let FilterVerdict::Accept(accepted) = accepted else {
+            panic!("the tweet was not accepted");
+        };
+        assert_eq!(accepted.field("text").as_str(), Some("ok"));
+        assert_eq!(accepted.annotations(), tweet.annotations());
+        // the tweet is accepted as it is if the filter gives none
+        assert_eq!(
+            run("return { final = true }", &tweet)?,
+            FilterVerdict::Accept(tweet.clone())
+        );
+        // `final` in a tweet is a field like any other
+        assert!(matches!(
+            run("post.final = true return post", &tweet)?,
+            FilterVerdict::Transform(_)
+        ));
+        // a table of only control keys is never taken as a tweet
+        assert_eq!(run("return {}", &tweet)?, FilterVerdict::Drop);
+        assert_eq!(
+            run("return { final = false }", &tweet)?,
+            FilterVerdict::Drop
+        );
+        assert_eq!(
+            run("return { tweet = post, final = false }", &tweet)?,
+            FilterVerdict::Keep
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn expand_into_several_tweets() -> Result<(), Box<dyn std::error::Error>> {
+        let run = |source: &str, tweet: &Tweet| -> Result<FilterVerdict, FilterError> {
+            let filter = Filter::inline(
+                0,
+                InlineFilter {
+                    name: None,
+                    source: source.to_owned(),
+                },
+            )?;
+            filter.run(
+                tweet,
+                &FilterContext::default(),
+                Duration::from_secs(1),
+                &FilterHttp::new(vec![], Duration::from_secs(1)),
+            )
+        };
+        let tweet: Tweet = serde_json::from_str(
+            r#"{ "id": "2", "text": "quoting", "annotations": { "score": 1 } }"#,
+        )?;
+
+        assert_eq!(run("return post", &tweet)?, FilterVerdict::Keep);
+        assert_eq!(run("return nil", &tweet)?, FilterVerdict::Drop);
+        assert_eq!(run("return {}", &tweet)?, FilterVerdict::Drop);
+        assert_eq!(run("return { {}, {} }", &tweet)?, FilterVerdict::Drop);
+
+        let FilterVerdict::Transform(split) =
+            run("return { { id = '1', text = 'quoted' }, post }", &tweet)?
+        else {
+            panic!("the tweet was not expanded");
+        };
+        let ids: Vec<&str> = split
+            .iter()
+            .filter_map(|t| t.field("id").as_str())
+            .collect();
+        assert_eq!(ids, vec!["1", "2"]);
+        assert_eq!(split[0].field("annotations")["score"], 1);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn http_from_filter() -> Result<(), Box<dyn std::error::Error>> {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr();
+        std::thread::spawn(move || {
+            for mut req in server.incoming_requests() {
+                let mut body = String::new();
+                req.as_reader().read_to_string(&mut body).unwrap();
+                let resp = tiny_http::Response::from_string(format!("{} {}", req.method(), body))
+                    .with_status_code(201);
+                req.respond(resp).unwrap();
+            }
+        });
+        let http = FilterHttp::new(vec!["127.0.0.1".to_owned()], Duration::from_secs(1));
+        let tweet: Tweet = serde_json::from_str(r#"{ "id": "1", "text": "hello" }"#)?;
+
+        let run = |source: String| {
+            let filter = Filter::inline(0, InlineFilter { name: None, source })?;
+            filter.run(
+                &tweet,
+                &FilterContext::default(),
+                Duration::from_secs(1),
+                &http,
+            )
+        };
+
+        let source = format!(
+            r#"
+                local got = http.get("http://{addr}/")
+                local posted = http.post("http://{addr}/", post.text)
+                post.text = got.status .. " " .. got.body .. "/" .. posted.body
+                return post
+            "#
+        );
+        let result = output(run(source)?, &tweet).unwrap();
+        assert_eq!(result.field("text").as_str(), Some("201 GET /POST hello"));
+
+        let source = r#"return http.get("http://localhost/")"#.to_owned();
+        assert!(matches!(run(source), Err(FilterError::Lua(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn limit_http_from_filter() -> Result<(), Box<dyn std::error::Error>> {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr();
+        std::thread::spawn(move || {
+            for req in server.incoming_requests() {
+                let resp = match req.url() {
+                    "/slow" => {
+                        std::thread::sleep(Duration::from_secs(2));
+                        tiny_http::Response::from_string("late")
+                    }
+                    _ => tiny_http::Response::from_string("a".repeat(HTTP_MAX_BODY_BYTES + 1)),
+                };
+                let _ = req.respond(resp);
+            }
+        });
+        let http = FilterHttp::new(vec!["127.0.0.1".to_owned()], Duration::from_secs(5));
+        let tweet: Tweet = serde_json::from_str(r#"{ "id": "1", "text": "hello" }"#)?;
+        let run = |source: String| {
+            let filter = Filter::inline(0, InlineFilter { name: None, source })?;
+            filter.run(
+                &tweet,
+                &FilterContext::default(),
+                Duration::from_millis(300),
+                &http,
+            )
+        };
+
+        let err = run(format!(r#"return http.get("http://{addr}/large")"#)).unwrap_err();
+        assert!(err.to_string().contains("larger than"), "{}", err);
+
+        // waiting for the response uses up the timeout of the filter
+        let started = Instant::now();
+        let result = run(format!(r#"return http.get("http://{addr}/slow")"#));
+        assert!(matches!(result, Err(FilterError::Lua(_))));
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn load_in_declared_order() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir().join(format!("binchotan-{}-order", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        // listed in the order they should run, which differs from the order of the names
+        let filters = [
+            ("zz-first", Some(1), "a"),
+            ("9-second", None, "b"),
+            ("10-third", None, "c"),
+            ("a-last", None, "d"),
+        ];
+        for (name, order, suffix) in filters {
+            let filter_dir = dir.join(name);
+            std::fs::create_dir_all(&filter_dir)?;
+            let order = order.map(|o| format!("order = {}", o)).unwrap_or_default();
+            std::fs::write(
+                filter_dir.join("binchotan.toml"),
+                format!(
+                    "name = \"{}\"\ndescription = \"\"\nauthor = \"\"\nentrypoint = \"main.lua\"\nscopes = []\n{}\n",
+                    name, order
+                ),
+            )?;
+            std::fs::write(
+                filter_dir.join("main.lua"),
+                format!("post.text = post.text .. \"{}\"\nreturn post", suffix),
+            )?;
+        }
+
+        // the same name in a later directory is shadowed, and a missing directory is skipped
+        let later = dir.with_extension("later");
+        let shadowed = later.join("9-second");
+        std::fs::create_dir_all(&shadowed)?;
+        std::fs::write(
+            shadowed.join("binchotan.toml"),
+            "name = \"shadowed\"\ndescription = \"\"\nauthor = \"\"\nentrypoint = \"main.lua\"\nscopes = []\n",
+        )?;
+        std::fs::write(shadowed.join("main.lua"), "return nil")?;
+        let dirs = [dir.clone(), dir.with_extension("missing"), later.clone()];
+
+        let loaded = Filter::load(&dirs, &HashSet::new())?;
+        let names: Vec<&str> = loaded.iter().map(Filter::name).collect();
+        assert_eq!(names, vec!["zz-first", "9-second", "10-third", "a-last"]);
+        std::fs::remove_dir_all(&later)?;
+
+        let http = FilterHttp::new(vec![], Duration::from_secs(1));
+        let mut tweet: Tweet = serde_json::from_str(r#"{ "id": "1", "text": "" }"#)?;
+        for filter in &loaded {
+            let verdict = filter.run(
+                &tweet,
+                &FilterContext::default(),
+                Duration::from_secs(1),
+                &http,
+            )?;
+            tweet = output(verdict, &tweet).unwrap();
+        }
+        assert_eq!(tweet.field("text").as_str(), Some("abcd"));
+        std::fs::remove_dir_all(&dir)?;
+
+        Ok(())
     }
 }
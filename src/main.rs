@@ -1,39 +1,69 @@
-use crate::{auth::Auth, config::Config, connection::Request};
+use crate::{auth::Auth, config::Config};
 use anyhow::Context;
 use connection::Handler;
 use credential::CredentialStore;
 use error::AppError;
-use sqlx::postgres::PgPoolOptions;
+use filter::FilterHttp;
+use metrics::Metrics;
+use record::Recorder;
+use scheduler::{RetryPolicy, Scheduler};
+use serve::Listener;
+use sqlx::{postgres::PgPoolOptions, PgPool};
 use std::{
-    io::{BufRead, BufReader, Write},
-    os::unix::net::{UnixListener, UnixStream},
-    path::{Path, PathBuf},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
-use thiserror::Error;
-use tracing::error;
+use store::{MuteStore, PgTweetStore, ScheduledTweetStore, TweetStore};
+use timeline_cache::TimelineCache;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{info, warn};
+use user_cache::UserCache;
 
 mod api;
 mod auth;
 mod cache;
+mod clock;
 mod config;
 mod connection;
 mod credential;
 mod error;
+mod export;
 mod filter;
 mod methods;
-mod models;
+mod metrics;
+mod mute;
+mod record;
+mod scheduler;
+mod serve;
+mod snippet;
+mod store;
+mod subscription;
+mod timeline_cache;
 mod tweet;
+mod user_cache;
 
 const VERSION: &str = "0.1.0";
+// How often tweets older than tweet_retention_days are deleted
+const TWEET_PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// How often due scheduled tweets are looked for. A tweet is posted up to this late.
+const SCHEDULED_TWEET_INTERVAL: Duration = Duration::from_secs(15);
 
 #[tokio::main]
 async fn main() -> Result<(), AppError> {
     dotenvy::dotenv().ok();
     tracing_subscriber::fmt::init();
 
-    let config = Config::new()?;
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let config = Config::new(take_config_flag(&mut args)?)?;
+    api::configure_http(Duration::from_secs(config.http_timeout_secs))?;
 
-    let result = start(config).await;
+    let result = match args.first().map(String::as_str) {
+        Some("prune") => prune(config).await,
+        Some("add-account") => add_account(config).await,
+        Some("replay") => replay(config, &args[1..]),
+        _ => start(config).await,
+    };
     if let Err(err) = &result {
         println!("{}", err);
     }
@@ -42,93 +72,243 @@ async fn main() -> Result<(), AppError> {
 }
 
 async fn start(config: Config) -> Result<(), AppError> {
-    let auth = Auth::new(
-        config.twitter_client_id,
-        config.twitter_client_secret,
-        config.redirect_host,
-        config.scopes.clone(),
-    );
-    let conn = PgPoolOptions::new()
-        .max_connections(5)
-        .connect(&config.database_url)
-        .await
-        .context("could not connect to the database")?;
-    let store = CredentialStore::new(config.cache_path.into(), auth, conn)?;
-
-    let mut listener = Listener::new(&config.socket_path)?;
+    let conn = connect(&config).await?;
+    let store = open_store(&config, conn.clone())?;
 
-    let sock_path = config.socket_path.clone();
-    ctrlc::set_handler(move || {
-        std::fs::remove_file(&sock_path).unwrap();
-        std::process::exit(0);
-    })
-    .context("could not create a Ctrl-C(SIGINT) handler")?;
+    let recorder = match &config.record_path {
+        Some(path) => Some(Arc::new(Recorder::open(path)?)),
+        None => None,
+    };
+    let mut listener = Listener::bind(&config.socket_path, config.allow_remote, recorder)?
+        .with_shutdown_grace(Duration::from_secs(config.shutdown_grace_secs))
+        .with_max_request_bytes(config.max_request_bytes);
 
     // validate filters' scopes in advance
-    filter::Filter::load(config.filter_dir.as_ref(), &config.scopes)?;
+    for filter in filter::Filter::load(&config.filter_dirs, &config.scopes)? {
+        info!("found filter {}", filter.summary());
+    }
+
+    let mute_store = MuteStore::new(conn.clone());
+    let mutes = store::merge_mutes(&config.mute_keywords, mute_store.load(clock::now()).await?);
 
     let handler = Handler {
         store,
-        filter_path: config.filter_dir.clone(),
+        tweet_store: Box::new(PgTweetStore::new(conn.clone())),
+        scheduled_tweets: ScheduledTweetStore::new(conn),
+        filter_dirs: config.filter_dirs.clone(),
         scopes: config.scopes.clone(),
+        strict_jsonrpc: config.strict_jsonrpc,
+        export_max_count: config.export_max_count,
+        active_filter_profile: Mutex::new(config.active_filter_profile.clone()),
+        filter_allowlist_authors: config.filter_allowlist_authors.clone(),
+        pretty_responses: config.pretty_responses,
+        heartbeat_interval: config.heartbeat_interval_secs.map(Duration::from_secs),
+        mutes: Mutex::new(mutes),
+        mute_store,
+        filter_error_policy: config.filter_error_policy,
+        filter_timeout: Duration::from_millis(config.filter_timeout_ms),
+        filter_http: FilterHttp::new(
+            config.filter_http_allowed_hosts.clone(),
+            Duration::from_secs(config.filter_http_timeout_secs),
+        ),
+        timeline_cache: TimelineCache::new(Duration::from_secs(config.timeline_cache_ttl_secs)),
+        metrics: Metrics::default(),
+        started_at: Instant::now(),
+        user_cache: UserCache::new(
+            config.user_cache_size,
+            Duration::from_secs(config.user_cache_ttl_secs),
+        ),
     };
 
-    listener.listen(handler).await?;
+    let handler = Arc::new(handler);
+    if let Some(secs) = config.metrics_log_interval_secs.filter(|secs| *secs > 0) {
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(Duration::from_secs(secs));
+            // the first tick completes at once
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                info!("{}", handler.metrics.summary());
+            }
+        });
+    }
+    if let Some(days) = config.tweet_retention_days {
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            let retention = Duration::from_secs(days * 24 * 60 * 60);
+            let mut ticker = tokio::time::interval(TWEET_PRUNE_INTERVAL);
+            loop {
+                ticker.tick().await;
+                prune_tweets(handler.tweet_store.as_ref(), retention).await;
+            }
+        });
+    }
+    {
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            // the first tick completes at once, which posts the tweets which fell due while the daemon was not running
+            let mut ticker = tokio::time::interval(SCHEDULED_TWEET_INTERVAL);
+            loop {
+                ticker.tick().await;
+                handler.post_scheduled_tweets().await;
+            }
+        });
+    }
+    listener.listen(handler.clone(), shutdown_signal()).await?;
+    handler.store.merge_and_save_cache()?;
+    // the socket is unlinked when the listener is dropped
+    drop(listener);
+    info!("shut down");
 
     Ok(())
 }
 
-#[derive(Debug, Error)]
-pub enum ListenerError {
-    #[error("could not bind to the socket. another backend might be running?")]
-    Bind(#[source] std::io::Error),
-    #[error("could not parse the socket payload: {0}")]
-    Parse(#[source] serde_json::Error),
+/// Completes on Ctrl-C, or SIGTERM on Unix.
+async fn shutdown_signal() {
+    let terminate = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                term.recv().await;
+            }
+            Err(err) => {
+                warn!("could not listen for SIGTERM: {}", err);
+                std::future::pending::<()>().await
+            }
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
+    info!("shutting down...");
 }
 
-struct Listener {
-    socket: UnixListener,
-    path: PathBuf,
+/// Removes every account whose tokens are no longer valid even after refreshing, and prints them.
+async fn prune(config: Config) -> Result<(), AppError> {
+    let store = open_store(&config, connect(&config).await?)?;
+    let removed = store.prune(None).await?;
+    if removed.is_empty() {
+        println!("no accounts were removed");
+    }
+    for id in removed {
+        println!("removed account {}", id);
+    }
+
+    Ok(())
 }
 
-impl Listener {
-    pub fn new<T: AsRef<Path>>(socket_path: T) -> Result<Self, ListenerError> {
-        Ok(Self {
-            socket: UnixListener::bind(socket_path.as_ref()).map_err(ListenerError::Bind)?,
-            path: socket_path.as_ref().to_owned(),
+/// Authorizes a new account in the terminal, without starting the daemon.
+async fn add_account(config: Config) -> Result<(), AppError> {
+    let store = open_store(&config, connect(&config).await?)?;
+    let cached = store.cached_accounts().len();
+    println!(
+        "you have {} account{}, authenticating a new one.",
+        cached,
+        if cached == 1 { "" } else { "s" }
+    );
+
+    let (twitter_id, session_key) = store
+        .add_account(None, |url| {
+            println!(
+                "open the following URL in your browser to continue: {}",
+                url
+            )
         })
-    }
+        .await?;
+    println!(
+        "added account {} (session key: {})",
+        twitter_id, session_key
+    );
 
-    pub async fn listen(&mut self, mut handler: Handler) -> Result<(), AppError> {
-        for stream in self.socket.incoming().flatten() {
-            if let Err(err) = Self::handle_stream(&mut handler, stream).await {
-                error!("{}", err);
-            }
-        }
+    Ok(())
+}
 
-        Ok(())
-    }
+/// Re-issues requests recorded in `record_path` against the running daemon. Usage: `replay <file> [session_key]`
+fn replay(config: Config, args: &[String]) -> Result<(), AppError> {
+    let record_path = args
+        .first()
+        .ok_or_else(|| anyhow::anyhow!("usage: binchotan-backend replay <file> [session_key]"))?;
+    record::replay(
+        record_path.as_ref(),
+        config.socket_path.as_ref(),
+        args.get(1).map(String::as_str),
+    )
+}
 
-    async fn handle_stream(handler: &mut Handler, mut stream: UnixStream) -> Result<(), AppError> {
-        let stream_ = stream.try_clone()?;
-        let mut reader = BufReader::new(stream_);
-        let mut payload = String::new();
-        reader.read_line(&mut payload)?;
+/// Removes `--config <path>` (or `--config=<path>`) from the arguments and returns the path.
+fn take_config_flag(args: &mut Vec<String>) -> Result<Option<PathBuf>, AppError> {
+    let Some(pos) = args
+        .iter()
+        .position(|arg| arg == "--config" || arg.starts_with("--config="))
+    else {
+        return Ok(None);
+    };
 
-        let req: Request = serde_json::from_str(&payload).map_err(ListenerError::Parse)?;
-        let resp = handler.handle(req).await;
-        // SAFETY: Response is serde::Serialize so it should always be able to be serialized
-        let json = serde_json::to_string(&resp).unwrap();
+    let flag = args.remove(pos);
+    let path = match flag.strip_prefix("--config=") {
+        Some(path) => path.to_owned(),
+        None if pos < args.len() => args.remove(pos),
+        None => return Err(anyhow::anyhow!("--config requires a path").into()),
+    };
 
-        stream.write_all(json.as_bytes())?;
-        stream.flush()?;
+    Ok(Some(path.into()))
+}
 
-        Ok(())
+// Deletes the tweets which have not been fetched within the retention period. Failures are only logged, to be retried on the next run.
+async fn prune_tweets(tweet_store: &dyn TweetStore, retention: Duration) {
+    let cutoff = SystemTime::now()
+        .checked_sub(retention)
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default();
+    match tweet_store.prune(cutoff).await {
+        Ok(0) => {}
+        Ok(deleted) => {
+            info!("deleted {} tweets older than the retention period", deleted);
+            if let Err(err) = tweet_store.compact().await {
+                warn!("could not compact the tweet store: {}", err);
+            }
+        }
+        Err(err) => warn!("could not delete old tweets: {}", err),
     }
 }
 
-impl Drop for Listener {
-    fn drop(&mut self) {
-        std::fs::remove_file(&self.path).unwrap();
-    }
+async fn connect(config: &Config) -> Result<PgPool, AppError> {
+    let conn = PgPoolOptions::new()
+        .max_connections(5)
+        .connect(&config.database_url)
+        .await
+        .context("could not connect to the database")?;
+
+    Ok(conn)
+}
+
+fn open_store(config: &Config, conn: PgPool) -> Result<CredentialStore, AppError> {
+    let auth = Auth::new(
+        config.twitter_client_id.clone(),
+        config.twitter_client_secret.clone(),
+        config.redirect_host.clone(),
+        config.scopes.clone(),
+    )
+    .with_callback_timeout(Duration::from_secs(config.auth_callback_timeout_secs));
+    let retry = RetryPolicy {
+        max_retries: config.max_retries,
+        max_wait: Duration::from_secs(config.max_retry_wait_secs),
+    };
+    let scheduler = Arc::new(
+        Scheduler::new(config.max_requests_per_second)
+            .with_retry(retry)
+            .with_wait_for_reset(config.wait_for_rate_limit_reset),
+    );
+    let store = CredentialStore::new(
+        config.cache_path.clone().into(),
+        // an empty key (e.g. `BINCHOTAN_CACHE_KEY=`) means no encryption
+        config.cache_key.clone().filter(|key| !key.is_empty()),
+        auth,
+        conn,
+        scheduler,
+    )?;
+
+    Ok(store)
 }
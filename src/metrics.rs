@@ -0,0 +1,107 @@
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+/// Counts the requests the daemon has served, by method and by error code. Shared by every connection.
+#[derive(Default)]
+pub struct Metrics {
+    total: AtomicU64,
+    // method -> requests
+    methods: Mutex<HashMap<&'static str, u64>>,
+    // error code -> responses
+    errors: Mutex<HashMap<isize, u64>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MetricsSnapshot {
+    pub total_requests: u64,
+    // Requests per method. Requests which could not be parsed have no method and are not included.
+    pub methods: BTreeMap<String, u64>,
+    // Error responses per error code.
+    pub errors: BTreeMap<isize, u64>,
+}
+
+impl Metrics {
+    /// Counts a request, along with the error code if it failed.
+    pub fn record(&self, method: Option<&'static str>, error_code: Option<isize>) {
+        self.total.fetch_add(1, Ordering::Relaxed);
+        if let Some(method) = method {
+            *self.methods.lock().unwrap().entry(method).or_default() += 1;
+        }
+        if let Some(code) = error_code {
+            *self.errors.lock().unwrap().entry(code).or_default() += 1;
+        }
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let methods = self
+            .methods
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(method, count)| (method.to_string(), *count))
+            .collect();
+        let errors = self
+            .errors
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(code, count)| (*code, *count))
+            .collect();
+
+        MetricsSnapshot {
+            total_requests: self.total.load(Ordering::Relaxed),
+            methods,
+            errors,
+        }
+    }
+
+    /// A line summarizing the counts for the log.
+    pub fn summary(&self) -> String {
+        let MetricsSnapshot {
+            total_requests,
+            methods,
+            errors,
+        } = self.snapshot();
+        let failed: u64 = errors.values().sum();
+        let busiest = methods
+            .iter()
+            .max_by_key(|(_, count)| **count)
+            .map(|(method, count)| format!(", most called: {} ({})", method, count))
+            .unwrap_or_default();
+        format!(
+            "served {} requests ({} failed){}",
+            total_requests, failed, busiest
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn count_by_method_and_error() {
+        let metrics = Metrics::default();
+        metrics.record(Some("v0.status"), None);
+        metrics.record(Some("v0.status"), None);
+        metrics.record(Some("v0.home_timeline"), Some(-32602));
+        metrics.record(None, Some(-32600));
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.total_requests, 4);
+        assert_eq!(snapshot.methods["v0.status"], 2);
+        assert_eq!(snapshot.methods["v0.home_timeline"], 1);
+        assert_eq!(snapshot.errors[&-32602], 1);
+        assert_eq!(snapshot.errors[&-32600], 1);
+        assert_eq!(
+            metrics.summary(),
+            "served 4 requests (2 failed), most called: v0.status (2)"
+        );
+    }
+}
@@ -0,0 +1,46 @@
+use serde::{Deserialize, Serialize};
+
+use crate::tweet::Tweet;
+
+/// A muted keyword. Tweets containing it (case-insensitive) are dropped before the filters run, until `until` if it is set.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Mute {
+    pub keyword: String,
+    // Expiry in epoch sec. The mute never expires if None.
+    #[serde(default)]
+    pub until: Option<i64>,
+}
+
+impl Mute {
+    pub fn is_active(&self, now: i64) -> bool {
+        self.until.is_none_or(|until| now < until)
+    }
+
+    pub fn matches(&self, tweet: &Tweet) -> bool {
+        tweet
+            .field("text")
+            .as_str()
+            .is_some_and(|text| text.to_lowercase().contains(&self.keyword.to_lowercase()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expired_mutes_are_ignored() -> Result<(), Box<dyn std::error::Error>> {
+        let tweet: Tweet =
+            serde_json::from_str(r#"{ "id": "1", "text": "The Final is tonight" }"#)?;
+        let mute = Mute {
+            keyword: "final".to_owned(),
+            until: Some(1000),
+        };
+
+        assert!(mute.matches(&tweet));
+        assert!(mute.is_active(999));
+        assert!(!mute.is_active(1000));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,156 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    path::Path,
+    sync::Mutex,
+};
+
+use serde_json::Value;
+use tracing::info;
+
+use crate::{connection::Response, error::AppError};
+
+const REDACTED: &str = "[REDACTED]";
+// Members whose values are credentials and must not be written to the record
+const SECRET_KEYS: [&str; 4] = [
+    "session_key",
+    "session_keys",
+    "access_token",
+    "refresh_token",
+];
+
+/// Appends each JSON-RPC request and its response to a JSONL file so that the traffic can be replayed later. Session keys and tokens are redacted.
+pub struct Recorder {
+    file: Mutex<File>,
+}
+
+impl Recorder {
+    pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path.as_ref())?;
+        info!("recording requests to {}", path.as_ref().display());
+
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn record(&self, req: &Value, resp: &Response) -> std::io::Result<()> {
+        let mut req = req.clone();
+        redact(&mut req);
+        // SAFETY: Response is serde::Serialize so it should always be able to be serialized
+        let mut resp = serde_json::to_value(resp).unwrap();
+        redact(&mut resp);
+
+        let line = serde_json::json!({ "request": req, "response": resp });
+        let mut file = self.file.lock().unwrap();
+        writeln!(file, "{}", line)?;
+        file.flush()
+    }
+}
+
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (k, v) in map.iter_mut() {
+                if SECRET_KEYS.contains(&k.as_str()) {
+                    redact_all(v);
+                } else {
+                    redact(v);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+fn redact_all(value: &mut Value) {
+    match value {
+        Value::String(s) => *s = REDACTED.to_owned(),
+        Value::Object(map) => map.values_mut().for_each(redact_all),
+        Value::Array(values) => values.iter_mut().for_each(redact_all),
+        _ => {}
+    }
+}
+
+fn unredact(value: &mut Value, session_key: &str) {
+    match value {
+        Value::Object(map) => {
+            for (k, v) in map.iter_mut() {
+                match v {
+                    Value::String(s) if k == "session_key" && s == REDACTED => {
+                        *s = session_key.to_owned()
+                    }
+                    other => unredact(other, session_key),
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(|v| unredact(v, session_key)),
+        _ => {}
+    }
+}
+
+/// Re-issues the requests in a record file against the daemon listening on `socket_path`, and prints each response. Redacted session keys are replaced with `session_key` if given.
+pub fn replay(
+    record_path: &Path,
+    socket_path: &Path,
+    session_key: Option<&str>,
+) -> Result<(), AppError> {
+    let reader = BufReader::new(File::open(record_path)?);
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: Value = serde_json::from_str(&line).map_err(anyhow::Error::from)?;
+        let mut req = entry["request"].clone();
+        if let Some(key) = session_key {
+            unredact(&mut req, key);
+        }
+
+        let mut stream = UnixStream::connect(socket_path)?;
+        writeln!(stream, "{}", req)?;
+        stream.flush()?;
+
+        let mut resp = String::new();
+        BufReader::new(stream).read_line(&mut resp)?;
+        println!("{}", resp.trim_end());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redact_and_restore_session_keys() {
+        let mut req = serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": "v0.home_timeline",
+            "params": { "session_key": "secret", "api_params": { "max_results": 10 } },
+            "id": "1"
+        });
+        redact(&mut req);
+        assert_eq!(req["params"]["session_key"], REDACTED);
+        assert_eq!(req["params"]["api_params"]["max_results"], 10);
+
+        unredact(&mut req, "new");
+        assert_eq!(req["params"]["session_key"], "new");
+    }
+
+    #[test]
+    fn redact_nested_secrets() {
+        let mut resp = serde_json::json!({
+            "result": { "owner": "123", "session_keys": { "123": "secret" } }
+        });
+        redact(&mut resp);
+        assert_eq!(resp["result"]["owner"], "123");
+        assert_eq!(resp["result"]["session_keys"]["123"], REDACTED);
+    }
+}
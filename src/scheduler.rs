@@ -0,0 +1,328 @@
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::time::{sleep_until, Instant};
+use tracing::debug;
+
+/// An API request waiting for its turn.
+#[derive(Debug, Clone, Serialize)]
+pub struct PendingRequest {
+    pub user_id: String,
+    pub endpoint: String,
+    // When the request started waiting, in epoch sec
+    pub queued_at: u64,
+}
+
+/// An endpoint whose rate limit is exhausted for the account.
+#[derive(Debug, Clone, Serialize)]
+pub struct ExhaustedLimit {
+    pub user_id: String,
+    pub endpoint: String,
+    // When the rate limit is reset, in epoch sec
+    pub reset: usize,
+}
+
+/// The rate limit last reported for an endpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct RateLimitStatus {
+    pub remaining: usize,
+    // When the rate limit is reset, in epoch sec
+    pub reset: usize,
+}
+
+// Wait before the first retry of a rate-limited request which does not say when the limit is reset. Doubled on each retry.
+const RETRY_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// How requests rejected with 429 (Too Many Requests) are retried. Nothing is retried by default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    // Upper bound of the total time a request waits for its retries
+    pub max_wait: Duration,
+}
+
+impl RetryPolicy {
+    /// Returns how long to wait before the retry. It is until the rate limit is reset (`x-rate-limit-reset` in epoch sec) if known, or an exponential backoff otherwise.
+    pub fn delay(&self, attempt: u32, reset: Option<usize>) -> Duration {
+        match reset {
+            // the clocks may be off by a bit, so wait at least a second
+            Some(reset) => Duration::from_secs((reset as u64).saturating_sub(epoch_secs()).max(1)),
+            None => RETRY_INITIAL_BACKOFF * 2u32.saturating_pow(attempt),
+        }
+    }
+}
+
+/// Paces outbound API requests across all accounts and endpoints. Every request waits for a global slot, so that no more than `max_requests_per_second` requests are sent. Requests for an endpoint whose rate limit is exhausted additionally wait until the limit is reset. The wait is keyed by account so that exhausted accounts do not hold back others.
+pub struct Scheduler {
+    interval: Option<Duration>,
+    // tokio's Mutex is fair, so requests take the global slots in FIFO order.
+    next_slot: tokio::sync::Mutex<Instant>,
+    // (user id, endpoint) -> when the rate limit is reset, as an Instant and in epoch sec
+    blocked: Mutex<HashMap<(String, String), (Instant, usize)>>,
+    // (user id, endpoint) -> the rate limit reported by the last response
+    limits: Mutex<HashMap<(String, String), RateLimitStatus>>,
+    // ticket -> the request waiting with it
+    queued: Mutex<HashMap<u64, PendingRequest>>,
+    next_ticket: AtomicU64,
+    retry: RetryPolicy,
+    // Whether a request for an exhausted endpoint waits until the reset, or is refused at once
+    wait_for_reset: bool,
+}
+
+impl Scheduler {
+    pub fn new(max_requests_per_second: Option<f64>) -> Self {
+        let interval = max_requests_per_second
+            .filter(|rps| *rps > 0.0)
+            .map(|rps| Duration::from_secs_f64(1.0 / rps));
+
+        Self {
+            interval,
+            next_slot: tokio::sync::Mutex::new(Instant::now()),
+            blocked: Mutex::new(HashMap::new()),
+            limits: Mutex::new(HashMap::new()),
+            queued: Mutex::new(HashMap::new()),
+            next_ticket: AtomicU64::new(0),
+            retry: RetryPolicy::default(),
+            wait_for_reset: true,
+        }
+    }
+
+    /// Sets whether requests for an endpoint whose rate limit is exhausted wait until the reset (the default) or fail with the limit.
+    pub fn with_wait_for_reset(mut self, wait: bool) -> Self {
+        self.wait_for_reset = wait;
+        self
+    }
+
+    /// Sets how requests rejected with 429 are retried.
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        self.retry
+    }
+
+    /// Waits until the account may send a request to the endpoint. If its rate limit is exhausted and the scheduler does not wait for the reset, the limit is returned instead.
+    pub async fn acquire(&self, user_id: &str, endpoint: &str) -> Result<(), ExhaustedLimit> {
+        let blocked = self
+            .blocked
+            .lock()
+            .unwrap()
+            .get(&key(user_id, endpoint))
+            .copied();
+        if let Some((until, reset)) = blocked {
+            if !self.wait_for_reset && until > Instant::now() {
+                return Err(ExhaustedLimit {
+                    user_id: user_id.to_owned(),
+                    endpoint: endpoint.to_owned(),
+                    reset,
+                });
+            }
+        }
+
+        let _guard = QueueGuard::new(self, user_id, endpoint);
+        if let Some((until, _)) = blocked {
+            debug!("rate limit for {} exhausted. waiting until reset", endpoint);
+            sleep_until(until).await;
+        }
+
+        if let Some(interval) = self.interval {
+            let mut next_slot = self.next_slot.lock().await;
+            sleep_until(*next_slot).await;
+            *next_slot = Instant::now() + interval;
+        }
+
+        Ok(())
+    }
+
+    /// Records the rate limit reported by the API (`x-rate-limit-remaining` and `x-rate-limit-reset` in epoch sec) after a request.
+    pub fn observe(&self, user_id: &str, endpoint: &str, remaining: usize, reset: usize) {
+        self.limits
+            .lock()
+            .unwrap()
+            .insert(key(user_id, endpoint), RateLimitStatus { remaining, reset });
+
+        let mut blocked = self.blocked.lock().unwrap();
+        if remaining > 0 {
+            blocked.remove(&key(user_id, endpoint));
+            return;
+        }
+
+        let wait = Duration::from_secs((reset as u64).saturating_sub(epoch_secs()));
+        blocked.insert(key(user_id, endpoint), (Instant::now() + wait, reset));
+    }
+
+    /// Returns the number of requests waiting for their turn.
+    pub fn queue_depth(&self) -> usize {
+        self.queued.lock().unwrap().len()
+    }
+
+    /// Returns the requests waiting for their turn, oldest first.
+    pub fn pending(&self) -> Vec<PendingRequest> {
+        let queued = self.queued.lock().unwrap();
+        let mut tickets: Vec<_> = queued.iter().collect();
+        tickets.sort_by_key(|(ticket, _)| **ticket);
+        tickets.into_iter().map(|(_, req)| req.clone()).collect()
+    }
+
+    /// Returns the rate limits which are exhausted and not reset yet, the earliest reset first.
+    pub fn exhausted_limits(&self) -> Vec<ExhaustedLimit> {
+        let now = Instant::now();
+        let mut limits: Vec<ExhaustedLimit> = self
+            .blocked
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, (until, _))| *until > now)
+            .map(|((user_id, endpoint), (_, reset))| ExhaustedLimit {
+                user_id: user_id.clone(),
+                endpoint: endpoint.clone(),
+                reset: *reset,
+            })
+            .collect();
+        limits.sort_by_key(|limit| limit.reset);
+        limits
+    }
+
+    /// Returns the rate limits last reported for the account, for each of `endpoints` and any other endpoint it has called. Endpoints it has never called are None.
+    pub fn rate_limits(
+        &self,
+        user_id: &str,
+        endpoints: &[&str],
+    ) -> BTreeMap<String, Option<RateLimitStatus>> {
+        let mut limits: BTreeMap<String, Option<RateLimitStatus>> = endpoints
+            .iter()
+            .map(|endpoint| (endpoint.to_string(), None))
+            .collect();
+        for ((user, endpoint), status) in self.limits.lock().unwrap().iter() {
+            if user == user_id {
+                limits.insert(endpoint.clone(), Some(*status));
+            }
+        }
+        limits
+    }
+
+    /// Returns the global ceiling of requests per second, or None if it is unlimited.
+    pub fn max_requests_per_second(&self) -> Option<f64> {
+        self.interval.map(|i| 1.0 / i.as_secs_f64())
+    }
+}
+
+fn key(user_id: &str, endpoint: &str) -> (String, String) {
+    (user_id.to_owned(), endpoint.to_owned())
+}
+
+fn epoch_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+// Keeps the queue correct even if the waiting future is dropped.
+struct QueueGuard<'a> {
+    scheduler: &'a Scheduler,
+    ticket: u64,
+}
+
+impl<'a> QueueGuard<'a> {
+    fn new(scheduler: &'a Scheduler, user_id: &str, endpoint: &str) -> Self {
+        let ticket = scheduler.next_ticket.fetch_add(1, Ordering::SeqCst);
+        let req = PendingRequest {
+            user_id: user_id.to_owned(),
+            endpoint: endpoint.to_owned(),
+            queued_at: epoch_secs(),
+        };
+        scheduler.queued.lock().unwrap().insert(ticket, req);
+        Self { scheduler, ticket }
+    }
+}
+
+impl Drop for QueueGuard<'_> {
+    fn drop(&mut self) {
+        self.scheduler.queued.lock().unwrap().remove(&self.ticket);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn pace_requests() {
+        let scheduler = Scheduler::new(Some(50.0));
+        let start = Instant::now();
+        for _ in 0..3 {
+            scheduler.acquire("1", "tweets").await.unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(40));
+        assert_eq!(scheduler.queue_depth(), 0);
+    }
+
+    #[tokio::test]
+    async fn exhausted_account_does_not_block_others() {
+        let scheduler = Scheduler::new(None);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        scheduler.observe("1", "tweets", 0, now.as_secs() as usize + 60);
+
+        let other =
+            tokio::time::timeout(Duration::from_millis(100), scheduler.acquire("2", "tweets"));
+        assert!(other.await.is_ok());
+
+        let blocked =
+            tokio::time::timeout(Duration::from_millis(100), scheduler.acquire("1", "tweets"));
+        assert!(blocked.await.is_err());
+        assert_eq!(scheduler.queue_depth(), 0);
+
+        let limits = scheduler.exhausted_limits();
+        assert_eq!(limits.len(), 1);
+        assert_eq!(limits[0].user_id, "1");
+    }
+
+    #[tokio::test]
+    async fn refuse_exhausted_endpoint() {
+        let scheduler = Scheduler::new(None).with_wait_for_reset(false);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let reset = now.as_secs() as usize + 60;
+        scheduler.observe("1", "tweets", 0, reset);
+
+        let refused =
+            tokio::time::timeout(Duration::from_millis(100), scheduler.acquire("1", "tweets"));
+        let limit = refused.await.unwrap().unwrap_err();
+        assert_eq!(limit.endpoint, "tweets");
+        assert_eq!(limit.reset, reset);
+        assert!(scheduler.acquire("1", "users").await.is_ok());
+
+        // a reset in the past no longer refuses anything
+        scheduler.observe("1", "tweets", 0, now.as_secs() as usize - 1);
+        assert!(scheduler.acquire("1", "tweets").await.is_ok());
+    }
+
+    #[test]
+    fn report_unknown_limits() {
+        let scheduler = Scheduler::new(None);
+        scheduler.observe("1", "tweets", 10, 100);
+        scheduler.observe("1", "users/:id/mentions", 3, 200);
+        scheduler.observe("2", "users", 5, 300);
+
+        let limits = scheduler.rate_limits("1", &["tweets", "users"]);
+        assert_eq!(limits.len(), 3);
+        assert_eq!(
+            limits["tweets"],
+            Some(RateLimitStatus {
+                remaining: 10,
+                reset: 100
+            })
+        );
+        assert_eq!(limits["users/:id/mentions"].unwrap().remaining, 3);
+        // only the other account has called it
+        assert_eq!(limits["users"], None);
+    }
+}
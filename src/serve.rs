@@ -0,0 +1,1439 @@
+use crate::{
+    connection::{BatchResponse, Handler, Heartbeat},
+    error::AppError,
+    record::Recorder,
+    subscription::{Subscriptions, SUBSCRIPTION_HEARTBEAT_INTERVAL},
+};
+use std::{
+    future::Future,
+    net::ToSocketAddrs,
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{TcpListener, UnixListener},
+    sync::watch,
+    task::JoinSet,
+};
+use tracing::{error, info, warn};
+
+// How long in-flight requests may take to finish after a shutdown is requested
+const DEFAULT_SHUTDOWN_GRACE: Duration = Duration::from_secs(10);
+// Requests longer than this are refused unless configured otherwise
+const DEFAULT_MAX_REQUEST_BYTES: usize = 4 * 1024 * 1024;
+// A listen address with this prefix is bound as TCP instead of a Unix socket path
+const TCP_PREFIX: &str = "tcp://";
+
+#[derive(Debug, Error)]
+pub enum ListenerError {
+    #[error("could not bind to the socket: {0}")]
+    Bind(#[source] std::io::Error),
+    #[error("another backend is already running on {0:?}")]
+    AlreadyRunning(PathBuf),
+    #[error("{0:?} exists but is not a socket. remove it or change socket_path")]
+    NotSocket(PathBuf),
+    #[error("could not accept a connection: {0}")]
+    Accept(#[source] std::io::Error),
+    #[error("invalid TCP address {0:?}. use tcp://host:port")]
+    InvalidAddress(String),
+    #[error("refusing to listen on {0}, which is reachable from other hosts. set allow_remote = true to allow it")]
+    RemoteAddress(String),
+}
+
+enum Socket {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+// A connection from either kind of socket
+trait Connection: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Connection for T {}
+
+impl Socket {
+    async fn accept(&self) -> std::io::Result<Box<dyn Connection>> {
+        Ok(match self {
+            Socket::Unix(socket) => Box::new(socket.accept().await?.0),
+            Socket::Tcp(socket) => Box::new(socket.accept().await?.0),
+        })
+    }
+}
+
+/// Serves JSON-RPC over a Unix socket or TCP. Each line a client sends is a request (or a batch), and each response is written back as a line in the same order. Connections are served concurrently.
+pub struct Listener {
+    socket: Socket,
+    // Path of the Unix socket, which is removed on drop
+    path: Option<PathBuf>,
+    recorder: Option<Arc<Recorder>>,
+    shutdown_grace: Duration,
+    max_request_bytes: usize,
+}
+
+impl Listener {
+    pub fn new<T: AsRef<Path>>(
+        socket_path: T,
+        recorder: Option<Arc<Recorder>>,
+    ) -> Result<Self, ListenerError> {
+        let path = socket_path.as_ref();
+
+        Ok(Self {
+            socket: Socket::Unix(bind_unix(path)?),
+            path: Some(path.to_owned()),
+            recorder,
+            shutdown_grace: DEFAULT_SHUTDOWN_GRACE,
+            max_request_bytes: DEFAULT_MAX_REQUEST_BYTES,
+        })
+    }
+
+    /// Binds `address`, which is either `tcp://host:port` or a path of a Unix socket. A TCP address must be a loopback one unless `allow_remote` is set, as the session keys go through the connections in plaintext.
+    pub fn bind(
+        address: &str,
+        allow_remote: bool,
+        recorder: Option<Arc<Recorder>>,
+    ) -> Result<Self, ListenerError> {
+        let host = match address.strip_prefix(TCP_PREFIX) {
+            Some(host) => host,
+            None => return Self::new(address, recorder),
+        };
+
+        let addrs: Vec<_> = host
+            .to_socket_addrs()
+            .map_err(|_| ListenerError::InvalidAddress(address.to_owned()))?
+            .collect();
+        if addrs.is_empty() {
+            return Err(ListenerError::InvalidAddress(address.to_owned()));
+        }
+        if !allow_remote && addrs.iter().any(|addr| !addr.ip().is_loopback()) {
+            return Err(ListenerError::RemoteAddress(address.to_owned()));
+        }
+
+        let socket = std::net::TcpListener::bind(&addrs[..]).map_err(ListenerError::Bind)?;
+        socket.set_nonblocking(true).map_err(ListenerError::Bind)?;
+        info!(
+            "listening on {}",
+            socket.local_addr().map_err(ListenerError::Bind)?
+        );
+
+        Ok(Self {
+            socket: Socket::Tcp(TcpListener::from_std(socket).map_err(ListenerError::Bind)?),
+            path: None,
+            recorder,
+            shutdown_grace: DEFAULT_SHUTDOWN_GRACE,
+            max_request_bytes: DEFAULT_MAX_REQUEST_BYTES,
+        })
+    }
+
+    pub fn with_shutdown_grace(mut self, grace: Duration) -> Self {
+        self.shutdown_grace = grace;
+        self
+    }
+
+    /// Sets the longest line (a request or a batch) a client may send, in bytes.
+    pub fn with_max_request_bytes(mut self, max: usize) -> Self {
+        self.max_request_bytes = max;
+        self
+    }
+
+    /// Serves connections until `shutdown` completes. No connection is accepted after that, and the requests being handled are given `shutdown_grace` to finish.
+    pub async fn listen(
+        &mut self,
+        handler: Arc<Handler>,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<(), AppError> {
+        let (stop, stopped) = watch::channel(false);
+        let mut connections = JoinSet::new();
+        tokio::pin!(shutdown);
+        loop {
+            tokio::select! {
+                accepted = self.socket.accept() => {
+                    let stream = accepted.map_err(ListenerError::Accept)?;
+                    // a slow request on one connection must not hold up the others
+                    let handler = handler.clone();
+                    let recorder = self.recorder.clone();
+                    let stopped = stopped.clone();
+                    let max_request_bytes = self.max_request_bytes;
+                    connections.spawn(async move {
+                        let served = Self::handle_stream(
+                            &handler,
+                            recorder.as_deref(),
+                            stream,
+                            stopped,
+                            max_request_bytes,
+                        );
+                        if let Err(err) = served.await {
+                            error!("{}", err);
+                        }
+                    });
+                }
+                // forget the connections which were closed
+                Some(_) = connections.join_next(), if !connections.is_empty() => {}
+                _ = &mut shutdown => break,
+            }
+        }
+
+        info!(
+            "waiting for {} connections to finish their requests",
+            connections.len()
+        );
+        let _ = stop.send(true);
+        let drained = tokio::time::timeout(self.shutdown_grace, async {
+            while connections.join_next().await.is_some() {}
+        })
+        .await;
+        if drained.is_err() {
+            warn!(
+                "aborting {} connections which did not finish in {:?}",
+                connections.len(),
+                self.shutdown_grace
+            );
+            connections.shutdown().await;
+        }
+
+        Ok(())
+    }
+
+    /// Answers the requests on the connection until the client closes it or the listener shuts down. A request being handled is answered before the connection is closed. A line longer than `max_request_bytes` is answered with an error and skipped. Subscriptions made on the connection are polled in between requests, and end with the connection. While there are any, idle connections get heartbeats, so that a client which has gone away is noticed by the failed write.
+    async fn handle_stream(
+        handler: &Handler,
+        recorder: Option<&Recorder>,
+        stream: Box<dyn Connection>,
+        mut stopped: watch::Receiver<bool>,
+        max_request_bytes: usize,
+    ) -> Result<(), AppError> {
+        let (reader, mut writer) = tokio::io::split(stream);
+        let mut lines = LineReader::new(BufReader::new(reader));
+        let mut subscriptions = Subscriptions::default();
+        let mut heartbeat = Heartbeat::new(
+            handler
+                .heartbeat_interval
+                .or(Some(SUBSCRIPTION_HEARTBEAT_INTERVAL)),
+        );
+        loop {
+            let line = tokio::select! {
+                line = lines.next(max_request_bytes) => line?,
+                _ = stopped.changed() => break,
+                _ = heartbeat.due(), if !subscriptions.is_empty() => {
+                    heartbeat.send(&mut writer).await?;
+                    continue;
+                }
+                id = subscriptions.next_due() => {
+                    match handler.poll_subscription(&mut subscriptions, &id).await {
+                        Some(notification) => Line::Notification(notification),
+                        None => continue,
+                    }
+                }
+            };
+            let json = match line {
+                Line::Payload(payload) if payload.trim().is_empty() => continue,
+                Line::Payload(payload) => {
+                    Self::handle_payload(
+                        handler,
+                        recorder,
+                        &payload,
+                        &mut writer,
+                        &mut subscriptions,
+                    )
+                    .await
+                }
+                Line::Notification(notification) => notification,
+                Line::TooLarge => {
+                    warn!("refusing a request longer than {} bytes", max_request_bytes);
+                    let resp = Handler::too_large(max_request_bytes);
+                    handler.render(&serde_json::Value::Null, &resp)
+                }
+                Line::Closed => break,
+            };
+            writer.write_all(json.as_bytes()).await?;
+            writer.write_all(b"\n").await?;
+            writer.flush().await?;
+            heartbeat.wrote();
+        }
+
+        Ok(())
+    }
+
+    /// Handles a line read from a connection and returns the serialized response. `out` receives the lines of a streamed export before the response.
+    async fn handle_payload(
+        handler: &Handler,
+        recorder: Option<&Recorder>,
+        payload: &str,
+        out: &mut (dyn AsyncWrite + Send + Unpin),
+        subscriptions: &mut Subscriptions,
+    ) -> String {
+        let value: serde_json::Value = match serde_json::from_str(payload) {
+            Ok(value) => value,
+            Err(err) => {
+                let resp = Handler::parse_error(err);
+                return handler.render(&serde_json::Value::Null, &resp);
+            }
+        };
+
+        match &value {
+            serde_json::Value::Array(values) => {
+                let resps = handler.handle_batch(values).await;
+                if let (Some(recorder), BatchResponse::Responses(resps)) = (recorder, &resps) {
+                    for (req, resp) in values.iter().zip(resps) {
+                        if let Err(err) = recorder.record(req, resp) {
+                            error!("could not record the request: {}", err);
+                        }
+                    }
+                }
+                handler.render(&value, &resps)
+            }
+            _ => {
+                let resp = match handler.parse_request(&value) {
+                    Ok(req) => handler.handle_streaming(req, out, subscriptions).await,
+                    Err(resp) => *resp,
+                };
+                if let Some(recorder) = recorder {
+                    if let Err(err) = recorder.record(&value, &resp) {
+                        error!("could not record the request: {}", err);
+                    }
+                }
+                handler.render(&value, &resp)
+            }
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Some(path) = &self.path {
+            if let Err(err) = std::fs::remove_file(path) {
+                warn!("could not remove the socket {:?}: {}", path, err);
+            }
+        }
+    }
+}
+
+// A line read from a connection, or one to write to it
+enum Line {
+    Payload(String),
+    TooLarge,
+    Closed,
+    Notification(String),
+}
+
+// Reads the lines of a connection. A line read halfway is kept in between calls, so that reading can be cancelled (e.g. to poll a subscription) without losing it.
+struct LineReader<R> {
+    reader: R,
+    line: Vec<u8>,
+    too_large: bool,
+}
+
+impl<R: AsyncBufRead + Unpin> LineReader<R> {
+    fn new(reader: R) -> Self {
+        Self {
+            reader,
+            line: Vec::new(),
+            too_large: false,
+        }
+    }
+
+    /// Reads a line of at most `max` bytes. The rest of a longer line is read and thrown away instead of being buffered, so that the next line can be read as usual.
+    async fn next(&mut self, max: usize) -> std::io::Result<Line> {
+        loop {
+            let buf = self.reader.fill_buf().await?;
+            if buf.is_empty() {
+                // the last line may lack a newline
+                let line = std::mem::take(&mut self.line);
+                return match (std::mem::take(&mut self.too_large), line.is_empty()) {
+                    (true, _) => Ok(Line::TooLarge),
+                    (false, true) => Ok(Line::Closed),
+                    (false, false) => into_payload(line),
+                };
+            }
+
+            let newline = buf.iter().position(|b| *b == b'\n');
+            let chunk = &buf[..newline.unwrap_or(buf.len())];
+            if !self.too_large && self.line.len() + chunk.len() > max {
+                self.too_large = true;
+                self.line = Vec::new();
+            }
+            if !self.too_large {
+                self.line.extend_from_slice(chunk);
+            }
+            let used = chunk.len() + usize::from(newline.is_some());
+            self.reader.consume(used);
+
+            if newline.is_some() {
+                let line = std::mem::take(&mut self.line);
+                return if std::mem::take(&mut self.too_large) {
+                    Ok(Line::TooLarge)
+                } else {
+                    into_payload(line)
+                };
+            }
+        }
+    }
+}
+
+fn into_payload(mut line: Vec<u8>) -> std::io::Result<Line> {
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line)
+        .map(Line::Payload)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+}
+
+/// Binds the Unix socket. If the path is taken, a socket left behind by a backend which did not shut down cleanly is removed and bound again once, while a socket somebody still answers on is kept.
+fn bind_unix(path: &Path) -> Result<UnixListener, ListenerError> {
+    match UnixListener::bind(path) {
+        Err(err) if err.kind() == std::io::ErrorKind::AddrInUse => {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                return Err(ListenerError::AlreadyRunning(path.to_owned()));
+            }
+            // never unlink a regular file which happens to be at the path
+            let metadata = std::fs::symlink_metadata(path).map_err(ListenerError::Bind)?;
+            if !metadata.file_type().is_socket() {
+                return Err(ListenerError::NotSocket(path.to_owned()));
+            }
+
+            info!("removing the stale socket {:?}", path);
+            std::fs::remove_file(path).map_err(ListenerError::Bind)?;
+            UnixListener::bind(path).map_err(ListenerError::Bind)
+        }
+        result => result.map_err(ListenerError::Bind),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::Auth,
+        clock,
+        connection::{ResponsePlainMeta, METHOD_NAMES},
+        credential::CredentialStore,
+        filter::{Filter, FilterContext, FilterErrorPolicy, FilterHttp, InlineFilter},
+        metrics::Metrics,
+        scheduler::Scheduler,
+        store::{self, MuteStore, PgTweetStore, ScheduledTweetStore},
+        subscription::{MAX_SUBSCRIPTIONS, MIN_POLL_INTERVAL},
+        timeline_cache::{CachedTimeline, TimelineCache},
+        user_cache::UserCache,
+        VERSION,
+    };
+    use anyhow::Context;
+    use serde_json::{json, Value};
+    use sqlx::{postgres::PgPoolOptions, PgPool};
+    use std::{
+        collections::HashSet,
+        io::{Read, Write},
+        net::Shutdown,
+        os::unix::net,
+        sync::{Arc, Mutex},
+        thread,
+        time::Duration,
+    };
+    use tokio::net::{TcpStream, UnixStream};
+
+    // Builds a listener on a temporary socket and a handler which never reaches Twitter or the database unless a method needs them.
+    fn harness(name: &str, strict_jsonrpc: bool) -> Result<(Listener, Handler), AppError> {
+        let conn = PgPoolOptions::new()
+            .connect_lazy("postgres://localhost/binchotan")
+            .context("could not create the pool")?;
+        harness_with_pool(name, strict_jsonrpc, conn)
+    }
+
+    fn harness_with_pool(
+        name: &str,
+        strict_jsonrpc: bool,
+        conn: PgPool,
+    ) -> Result<(Listener, Handler), AppError> {
+        let dir = std::env::temp_dir().join(format!("binchotan-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(dir.join("filters"))?;
+        let socket_path = dir.join("binchotan.sock");
+        let _ = std::fs::remove_file(&socket_path);
+
+        let auth = Auth::new(
+            "client_id".into(),
+            "client_secret".into(),
+            "127.0.0.1:0".into(),
+            HashSet::new(),
+        );
+        let store = CredentialStore::new(
+            dir.join("cache.json"),
+            None,
+            auth,
+            conn.clone(),
+            Arc::new(Scheduler::new(None)),
+        )?;
+
+        let handler = Handler {
+            store,
+            tweet_store: Box::new(PgTweetStore::new(conn.clone())),
+            scheduled_tweets: ScheduledTweetStore::new(conn.clone()),
+            mute_store: MuteStore::new(conn),
+            filter_dirs: vec![dir.join("filters")],
+            scopes: HashSet::new(),
+            strict_jsonrpc,
+            export_max_count: 10,
+            active_filter_profile: Mutex::new(None),
+            filter_allowlist_authors: HashSet::new(),
+            pretty_responses: false,
+            heartbeat_interval: None,
+            mutes: Mutex::new(vec![]),
+            filter_error_policy: FilterErrorPolicy::SkipFilter,
+            filter_timeout: Duration::from_secs(1),
+            filter_http: FilterHttp::new(vec![], Duration::from_secs(1)),
+            timeline_cache: TimelineCache::new(Duration::ZERO),
+            metrics: Metrics::default(),
+            started_at: std::time::Instant::now(),
+            user_cache: UserCache::new(0, Duration::ZERO),
+        };
+
+        Ok((Listener::new(&socket_path, None)?, handler))
+    }
+
+    // Sends the payload from a client connection, closes its writing half and returns what the client read until the daemon closed the stream.
+    async fn round_trip(
+        listener: &Listener,
+        handler: &Handler,
+        payload: &str,
+    ) -> Result<(String, Result<(), AppError>), AppError> {
+        let path = listener.path.clone().unwrap();
+        let payload = format!("{}\n", payload);
+        let client = thread::spawn(move || -> std::io::Result<String> {
+            let mut stream = net::UnixStream::connect(path)?;
+            stream.write_all(payload.as_bytes())?;
+            stream.shutdown(Shutdown::Write)?;
+            let mut resp = String::new();
+            stream.read_to_string(&mut resp)?;
+            Ok(resp)
+        });
+
+        let stream = listener.socket.accept().await?;
+        let (_stop, stopped) = watch::channel(false);
+        let result =
+            Listener::handle_stream(handler, None, stream, stopped, listener.max_request_bytes)
+                .await;
+        let resp = client.join().unwrap()?;
+        Ok((resp, result))
+    }
+
+    async fn call(
+        listener: &Listener,
+        handler: &Handler,
+        request: Value,
+    ) -> Result<Value, Box<dyn std::error::Error>> {
+        let (resp, result) = round_trip(listener, handler, &request.to_string()).await?;
+        result?;
+        Ok(serde_json::from_str(&resp)?)
+    }
+
+    // The redirect server spins on a worker, so the tests need another one.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn status_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let (listener, handler) = harness("status", false)?;
+        let resp = call(
+            &listener,
+            &handler,
+            json!({ "jsonrpc": "2.0", "method": "v0.status", "params": {}, "id": "1" }),
+        )
+        .await?;
+
+        assert_eq!(resp["jsonrpc"], "2.0");
+        assert_eq!(resp["id"], "1");
+        assert_eq!(resp["result"]["version"], VERSION);
+        assert!(resp["result"]["uptime_secs"].is_u64());
+        assert_eq!(resp["result"]["valid_accounts"], 0);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn count_requests() -> Result<(), Box<dyn std::error::Error>> {
+        let (listener, handler) = harness("metrics", false)?;
+        let requests = [
+            json!({ "jsonrpc": "2.0", "method": "v0.status", "params": {}, "id": "1" }),
+            json!({ "jsonrpc": "2.0", "method": "v0.user.lookup", "params": { "session_key": "key", "target_ids": [] }, "id": "2" }),
+            json!({ "jsonrpc": "2.0", "method": "v0.nonexistent", "params": {}, "id": "3" }),
+        ];
+        for req in requests {
+            call(&listener, &handler, req).await?;
+        }
+
+        let metrics = call(
+            &listener,
+            &handler,
+            json!({ "jsonrpc": "2.0", "method": "v0.metrics", "params": {}, "id": "4" }),
+        )
+        .await?;
+        // the metrics request itself is not counted yet
+        assert_eq!(metrics["result"]["total_requests"], 3);
+        assert_eq!(
+            metrics["result"]["methods"],
+            json!({ "v0.status": 1, "v0.user.lookup": 1 })
+        );
+        assert_eq!(
+            metrics["result"]["errors"],
+            json!({ "-32601": 1, "-32602": 1 })
+        );
+        assert_eq!(handler.metrics.snapshot().methods["v0.metrics"], 1);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn pretty_print_on_request() -> Result<(), Box<dyn std::error::Error>> {
+        let (listener, handler) = harness("pretty", true)?;
+        let request = json!({ "jsonrpc": "2.0", "method": "v0.status", "params": {}, "id": "1", "pretty": true });
+        let (resp, result) = round_trip(&listener, &handler, &request.to_string()).await?;
+        result?;
+
+        // still a single line, so that clients reading a line per message are not broken
+        assert_eq!(resp.lines().count(), 1);
+        let line = resp.lines().next().unwrap_or_default();
+        assert!(line.contains(r#""jsonrpc": "2.0", "#));
+        assert_eq!(
+            serde_json::from_str::<Value>(line)?["result"]["version"],
+            VERSION
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn methods_share_a_connection_handler() -> Result<(), Box<dyn std::error::Error>> {
+        let (listener, handler) = harness("methods", false)?;
+
+        let stats = call(
+            &listener,
+            &handler,
+            json!({ "jsonrpc": "2.0", "method": "v0.system.stats", "params": {}, "id": "1" }),
+        )
+        .await?;
+        assert_eq!(stats["result"]["queue_depth"], 0);
+
+        let queue = call(
+            &listener,
+            &handler,
+            json!({ "jsonrpc": "2.0", "method": "v0.queue.status", "params": {}, "id": "1" }),
+        )
+        .await?;
+        assert_eq!(queue["result"]["pending_requests"], json!([]));
+        assert_eq!(queue["result"]["exhausted_limits"], json!([]));
+
+        let snippets = call(
+            &listener,
+            &handler,
+            json!({ "jsonrpc": "2.0", "method": "v0.filter.snippets", "params": {}, "id": "2" }),
+        )
+        .await?;
+        assert_eq!(snippets["id"], "2");
+        assert!(snippets["result"]["snippets"].is_array());
+
+        let profile = call(
+            &listener,
+            &handler,
+            json!({ "jsonrpc": "2.0", "method": "v0.filter.profile", "params": { "profile": "work" }, "id": "3" }),
+        )
+        .await?;
+        assert_eq!(profile["result"]["profile"], "work");
+        assert_eq!(
+            handler.active_filter_profile.lock().unwrap().as_deref(),
+            Some("work")
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn keep_mutes_across_restarts() -> Result<(), Box<dyn std::error::Error>> {
+        // the mutes are saved to the database, so this needs the one the queries are checked against
+        let url = match std::env::var("DATABASE_URL") {
+            Ok(url) => url,
+            Err(_) => return Ok(()),
+        };
+        let conn = PgPoolOptions::new()
+            .connect_lazy(&url)
+            .context("could not create the pool")?;
+        let keyword = format!("spoiler-{}", std::process::id());
+        let (listener, handler) = harness_with_pool("mutes", false, conn.clone())?;
+
+        call(
+            &listener,
+            &handler,
+            json!({ "jsonrpc": "2.0", "method": "v0.mutes.add", "params": { "keyword": "expired", "until": 1 }, "id": "1" }),
+        )
+        .await?;
+        let mutes = call(
+            &listener,
+            &handler,
+            json!({ "jsonrpc": "2.0", "method": "v0.mutes.add", "params": { "keyword": keyword }, "id": "2" }),
+        )
+        .await?;
+        assert_eq!(
+            mutes["result"]["mutes"],
+            json!([{ "keyword": keyword, "until": null }])
+        );
+        drop((listener, handler));
+
+        // a new handler picks up the mutes as main does at startup
+        let (listener, handler) = harness_with_pool("mutes", false, conn)?;
+        let saved = handler.mute_store.load(clock::now()).await?;
+        *handler.mutes.lock().unwrap() = store::merge_mutes(&[], saved);
+        let mutes = call(
+            &listener,
+            &handler,
+            json!({ "jsonrpc": "2.0", "method": "v0.mutes.list", "params": {}, "id": "3" }),
+        )
+        .await?;
+        assert!(mutes["result"]["mutes"]
+            .as_array()
+            .unwrap()
+            .contains(&json!({ "keyword": keyword, "until": null })));
+        assert!(!mutes["result"]["mutes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|m| m["keyword"] == "expired"));
+
+        call(
+            &listener,
+            &handler,
+            json!({ "jsonrpc": "2.0", "method": "v0.mutes.remove", "params": { "keyword": keyword }, "id": "4" }),
+        )
+        .await?;
+        let saved = handler.mute_store.load(clock::now()).await?;
+        assert!(!saved.iter().any(|m| m.keyword == keyword));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn invalid_requests() -> Result<(), Box<dyn std::error::Error>> {
+        let (listener, handler) = harness("errors", false)?;
+
+        let version = call(
+            &listener,
+            &handler,
+            json!({ "jsonrpc": "1.0", "method": "v0.status", "params": {}, "id": "1" }),
+        )
+        .await?;
+        assert_eq!(version["id"], "1");
+        assert_eq!(version["error"]["code"], -32600);
+
+        let bogus = call(
+            &listener,
+            &handler,
+            json!({ "jsonrpc": "2.0", "method": "v0.bogus", "params": {}, "id": "0" }),
+        )
+        .await?;
+        assert_eq!(bogus["id"], "0");
+        assert_eq!(bogus["error"]["code"], -32601);
+        assert_eq!(bogus["error"]["data"]["method"], "v0.bogus");
+
+        // an unknown value in the params is not an unknown method
+        let format = call(
+            &listener,
+            &handler,
+            json!({ "jsonrpc": "2.0", "method": "v0.export.timeline", "params": { "session_key": "key", "format": "xml" }, "id": "0" }),
+        )
+        .await?;
+        assert_eq!(format["error"]["code"], -32602);
+        assert_eq!(format["error"]["data"]["method"], "v0.export.timeline");
+        for method in METHOD_NAMES {
+            let req = json!({ "jsonrpc": "2.0", "method": method, "params": 1, "id": "0" });
+            let Err(resp) = handler.parse_request(&req) else {
+                panic!("{} accepted invalid params", method);
+            };
+            assert_eq!(
+                serde_json::to_value(&resp)?["error"]["code"],
+                -32602,
+                "{}",
+                method
+            );
+        }
+
+        let empty = call(
+            &listener,
+            &handler,
+            json!({ "jsonrpc": "2.0", "method": "v0.tweet.create", "params": { "session_key": "key", "text": " " }, "id": "2" }),
+        )
+        .await?;
+        assert_eq!(empty["error"]["code"], -32602);
+
+        // a tweet cannot be scheduled in the past
+        let past = call(
+            &listener,
+            &handler,
+            json!({ "jsonrpc": "2.0", "method": "v0.tweet.create", "params": { "session_key": "key", "text": "hello", "scheduled_at": 1 }, "id": "2" }),
+        )
+        .await?;
+        assert_eq!(past["error"]["code"], -32602);
+        assert_eq!(past["error"]["data"]["method"], "v0.tweet.create");
+
+        let lookup = call(
+            &listener,
+            &handler,
+            json!({ "jsonrpc": "2.0", "method": "v0.user.lookup", "params": { "session_key": "key", "target_ids": [] }, "id": "3" }),
+        )
+        .await?;
+        assert_eq!(lookup["error"]["code"], -32602);
+
+        let search = call(
+            &listener,
+            &handler,
+            json!({ "jsonrpc": "2.0", "method": "v0.search", "params": { "session_key": "key", "query": "a".repeat(513) }, "id": "4" }),
+        )
+        .await?;
+        assert_eq!(search["error"]["code"], -32602);
+
+        let unknown_filter = call(
+            &listener,
+            &handler,
+            json!({ "jsonrpc": "2.0", "method": "v0.home_timeline", "params": { "session_key": "key", "use_filters": ["missing"] }, "id": "5" }),
+        )
+        .await?;
+        assert_eq!(unknown_filter["error"]["code"], -32602);
+
+        let since_id = call(
+            &listener,
+            &handler,
+            json!({ "jsonrpc": "2.0", "method": "v0.home_timeline", "params": { "session_key": "key", "api_params": { "since_id": "1 OR 1" } }, "id": "6" }),
+        )
+        .await?;
+        assert_eq!(since_id["error"]["code"], -32602);
+
+        let delete = call(
+            &listener,
+            &handler,
+            json!({ "jsonrpc": "2.0", "method": "v0.tweet.delete", "params": { "session_key": "key", "tweet_id": "" }, "id": "7" }),
+        )
+        .await?;
+        assert_eq!(delete["error"]["code"], -32602);
+
+        let like = call(
+            &listener,
+            &handler,
+            json!({ "jsonrpc": "2.0", "method": "v0.like", "params": { "session_key": "key", "tweet_id": "x" }, "id": "8" }),
+        )
+        .await?;
+        assert_eq!(like["error"]["code"], -32602);
+        assert_eq!(like["error"]["data"]["method"], "v0.like");
+
+        let history = call(
+            &listener,
+            &handler,
+            json!({ "jsonrpc": "2.0", "method": "v0.history", "params": { "session_key": "key", "limit": 0 }, "id": "10" }),
+        )
+        .await?;
+        assert_eq!(history["error"]["code"], -32602);
+
+        let retweet = call(
+            &listener,
+            &handler,
+            json!({ "jsonrpc": "2.0", "method": "v0.unretweet", "params": { "session_key": "key", "tweet_id": "x" }, "id": "9" }),
+        )
+        .await?;
+        assert_eq!(retweet["error"]["code"], -32602);
+        assert_eq!(retweet["error"]["data"]["method"], "v0.unretweet");
+
+        let show = call(
+            &listener,
+            &handler,
+            json!({ "jsonrpc": "2.0", "method": "v0.tweet.show", "params": { "session_key": "key", "tweet_id": "https://x.com/a/status/1" }, "id": "11" }),
+        )
+        .await?;
+        assert_eq!(show["error"]["code"], -32602);
+        assert_eq!(show["error"]["data"]["method"], "v0.tweet.show");
+
+        let mentions = call(
+            &listener,
+            &handler,
+            json!({ "jsonrpc": "2.0", "method": "v0.mentions", "params": { "session_key": "key", "max_results": 1 }, "id": "12" }),
+        )
+        .await?;
+        assert_eq!(mentions["error"]["code"], -32602);
+        assert_eq!(mentions["error"]["data"]["method"], "v0.mentions");
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn reject_invalid_subscriptions() -> Result<(), Box<dyn std::error::Error>> {
+        let (listener, handler) = harness("subscribe", false)?;
+
+        let too_often = call(
+            &listener,
+            &handler,
+            json!({ "jsonrpc": "2.0", "method": "v0.subscribe", "params": { "session_key": "key", "poll_interval_secs": 1 }, "id": "1" }),
+        )
+        .await?;
+        assert_eq!(too_often["error"]["code"], -32602);
+        assert_eq!(too_often["error"]["data"]["method"], "v0.subscribe");
+
+        let unknown = call(
+            &listener,
+            &handler,
+            json!({ "jsonrpc": "2.0", "method": "v0.unsubscribe", "params": { "subscription": "1" }, "id": "2" }),
+        )
+        .await?;
+        assert_eq!(unknown["error"]["code"], -32602);
+
+        // a batch is answered at once, so it has no connection to push to
+        let batch = call(
+            &listener,
+            &handler,
+            json!([{ "jsonrpc": "2.0", "method": "v0.subscribe", "params": { "session_key": "key" }, "id": "3" }]),
+        )
+        .await?;
+        assert_eq!(batch[0]["error"]["code"], -32600);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn cap_subscriptions_per_connection() -> Result<(), Box<dyn std::error::Error>> {
+        let (_listener, handler) = harness("subscribe-cap", false)?;
+        let mut subscriptions = Subscriptions::default();
+        for _ in 0..MAX_SUBSCRIPTIONS {
+            subscriptions.add(
+                "key".to_owned(),
+                FilterContext::default(),
+                None,
+                MIN_POLL_INTERVAL,
+            );
+        }
+
+        let value = json!({ "jsonrpc": "2.0", "method": "v0.subscribe", "params": { "session_key": "key" }, "id": "1" });
+        let Ok(req) = handler.parse_request(&value) else {
+            panic!("the request should be valid");
+        };
+        let resp = handler
+            .handle_streaming(req, &mut Vec::new(), &mut subscriptions)
+            .await;
+        let resp: Value = serde_json::from_str(&handler.render(&value, &resp))?;
+        assert_eq!(resp["error"]["code"], -32099);
+        assert_eq!(
+            resp["error"]["data"]["max_subscriptions"],
+            MAX_SUBSCRIPTIONS
+        );
+        assert_eq!(subscriptions.len(), MAX_SUBSCRIPTIONS);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn heartbeat_once_idle() -> Result<(), Box<dyn std::error::Error>> {
+        let mut heartbeat = Heartbeat::new(Some(Duration::from_millis(50)));
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        // a write postpones the heartbeat
+        heartbeat.wrote();
+        let started = std::time::Instant::now();
+        heartbeat.due().await;
+        assert!(started.elapsed() >= Duration::from_millis(40));
+
+        let mut out = Vec::new();
+        heartbeat.send(&mut out).await?;
+        assert_eq!(
+            String::from_utf8(out)?,
+            "{\"jsonrpc\":\"2.0\",\"method\":\"v0.heartbeat\"}\n"
+        );
+
+        let disabled = Heartbeat::new(None);
+        let due = tokio::time::timeout(Duration::from_millis(50), disabled.due()).await;
+        assert!(due.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn batch_keeps_order_and_isolates_errors() -> Result<(), Box<dyn std::error::Error>> {
+        let (listener, handler) = harness("batch", false)?;
+        let resp = call(
+            &listener,
+            &handler,
+            json!([
+                { "jsonrpc": "2.0", "method": "v0.status", "params": {}, "id": "a" },
+                { "jsonrpc": "2.0", "method": "v0.unknown", "params": {}, "id": "b" },
+                { "jsonrpc": "2.0", "method": "v0.system.stats", "params": {}, "id": "c" },
+                { "jsonrpc": "2.0", "method": "v0.status", "params": {}, "id": "d" }
+            ]),
+        )
+        .await?;
+
+        let ids: Vec<&str> = resp
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|r| r["id"].as_str().unwrap())
+            .collect();
+        assert_eq!(ids, vec!["a", "b", "c", "d"]);
+        assert_eq!(resp[0]["result"]["version"], VERSION);
+        assert_eq!(resp[1]["error"]["code"], -32601);
+        assert_eq!(resp[2]["result"]["queue_depth"], 0);
+
+        let empty = call(&listener, &handler, json!([])).await?;
+        assert_eq!(empty["error"]["code"], -32600);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn batch_runs_concurrently_in_order() -> Result<(), Box<dyn std::error::Error>> {
+        // the database accepts connections but never answers, so v0.status waits for the acquire timeout
+        let database = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let conn = PgPoolOptions::new()
+            .acquire_timeout(Duration::from_millis(500))
+            .connect_lazy(&format!(
+                "postgres://postgres@{}/binchotan",
+                database.local_addr()?
+            ))
+            .context("could not create the pool")?;
+        let (listener, handler) = harness_with_pool("batch-order", false, conn)?;
+
+        let started = std::time::Instant::now();
+        let resp = call(
+            &listener,
+            &handler,
+            json!([
+                { "jsonrpc": "2.0", "method": "v0.status", "params": {}, "id": "slow" },
+                { "jsonrpc": "2.0", "method": "v0.system.stats", "params": {}, "id": "fast" },
+                { "jsonrpc": "2.0", "method": "v0.status", "params": {}, "id": "slow too" }
+            ]),
+        )
+        .await?;
+        let elapsed = started.elapsed();
+
+        let ids: Vec<&str> = resp
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|r| r["id"].as_str().unwrap())
+            .collect();
+        assert_eq!(ids, vec!["slow", "fast", "slow too"]);
+        assert_eq!(resp[0]["result"]["account_count"], Value::Null);
+        assert_eq!(resp[1]["result"]["queue_depth"], 0);
+        // the slow requests waited at the same time
+        assert!(elapsed >= Duration::from_millis(500), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(1000), "{:?}", elapsed);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn strict_mode_rejects_unknown_members() -> Result<(), Box<dyn std::error::Error>> {
+        let (listener, handler) = harness("strict", true)?;
+        let resp = call(
+            &listener,
+            &handler,
+            json!({ "jsonrpc": "2.0", "method": "v0.status", "params": {}, "id": "1", "extra": true }),
+        )
+        .await?;
+
+        assert_eq!(resp["id"], "1");
+        assert!(resp["error"].is_object());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn malformed_input_keeps_the_connection() -> Result<(), Box<dyn std::error::Error>> {
+        let (listener, handler) = harness("malformed", false)?;
+        let payload = [
+            "not json",
+            r#"{ "jsonrpc": "2.0", "method": "v0.unknown", "params": {}, "id": "1" }"#,
+            r#"{ "jsonrpc": "2.0", "method": "v0.filter.profile", "params": { "profile": 1 }, "id": "2" }"#,
+            r#"{ "jsonrpc": "2.0", "method": "v0.status", "params": {}, "id": "3" }"#,
+        ]
+        .join("\n");
+        let (resp, result) = round_trip(&listener, &handler, &payload).await?;
+        result?;
+
+        let resps = resp
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<Vec<Value>, _>>()?;
+        assert_eq!(resps.len(), 4);
+        assert_eq!(resps[0]["error"]["code"], -32700);
+        assert_eq!(resps[1]["id"], "1");
+        assert_eq!(resps[1]["error"]["code"], -32601);
+        assert_eq!(resps[2]["id"], "2");
+        assert_eq!(resps[2]["error"]["code"], -32602);
+        assert_eq!(resps[2]["error"]["data"]["method"], "v0.filter.profile");
+        assert_eq!(resps[3]["result"]["version"], VERSION);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn refuse_oversized_requests() -> Result<(), Box<dyn std::error::Error>> {
+        let (listener, handler) = harness("too-large", false)?;
+        let listener = listener.with_max_request_bytes(100);
+        let status = r#"{ "jsonrpc": "2.0", "method": "v0.status", "params": {}, "id": "1" }"#;
+        let payload = [&"x".repeat(100_000), status].join("\n");
+        let (resp, result) = round_trip(&listener, &handler, &payload).await?;
+        result?;
+
+        let resps = resp
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<Vec<Value>, _>>()?;
+        assert_eq!(resps.len(), 2);
+        assert_eq!(resps[0]["error"]["code"], -32603);
+        assert_eq!(resps[0]["error"]["data"]["max_request_bytes"], 100);
+        assert_eq!(resps[1]["result"]["version"], VERSION);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn connections_are_served_concurrently() -> Result<(), Box<dyn std::error::Error>> {
+        let (mut listener, handler) = harness("concurrent", false)?;
+        let path = listener.path.clone().unwrap();
+        let server = tokio::spawn(async move {
+            listener
+                .listen(Arc::new(handler), std::future::pending())
+                .await
+        });
+
+        // the first request stays in flight until the rest of it is sent
+        let mut first = UnixStream::connect(&path).await?;
+        first
+            .write_all(br#"{ "jsonrpc": "2.0", "method": "v0.status", "#)
+            .await?;
+
+        let mut second = UnixStream::connect(&path).await?;
+        second
+            .write_all(b"{ \"jsonrpc\": \"2.0\", \"method\": \"v0.status\", \"params\": {}, \"id\": \"2\" }\n")
+            .await?;
+        let mut resp = String::new();
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            BufReader::new(&mut second).read_line(&mut resp),
+        )
+        .await??;
+        assert_eq!(serde_json::from_str::<Value>(&resp)?["id"], "2");
+
+        first
+            .write_all(b"\"params\": {}, \"id\": \"1\" }\n")
+            .await?;
+        let mut resp = String::new();
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            BufReader::new(&mut first).read_line(&mut resp),
+        )
+        .await??;
+        assert_eq!(serde_json::from_str::<Value>(&resp)?["id"], "1");
+
+        server.abort();
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn shutdown_closes_connections_and_unlinks_the_socket(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (listener, handler) = harness("shutdown", false)?;
+        let mut listener = listener.with_shutdown_grace(Duration::from_secs(5));
+        let path = listener.path.clone().unwrap();
+        let (signal, signaled) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(async move {
+            listener
+                .listen(Arc::new(handler), async {
+                    let _ = signaled.await;
+                })
+                .await?;
+            Ok::<_, AppError>(listener)
+        });
+
+        let mut idle = UnixStream::connect(&path).await?;
+        idle.write_all(
+            b"{ \"jsonrpc\": \"2.0\", \"method\": \"v0.status\", \"params\": {}, \"id\": \"1\" }\n",
+        )
+        .await?;
+        let mut reader = BufReader::new(&mut idle);
+        let mut resp = String::new();
+        reader.read_line(&mut resp).await?;
+        assert_eq!(serde_json::from_str::<Value>(&resp)?["id"], "1");
+
+        signal.send(()).unwrap();
+        // the idle connection is closed instead of holding the shutdown for the whole grace period
+        let listener = tokio::time::timeout(Duration::from_secs(2), server).await???;
+        let mut rest = String::new();
+        reader.read_line(&mut rest).await?;
+        assert!(rest.is_empty());
+
+        assert!(path.exists());
+        drop(listener);
+        assert!(!path.exists());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn serve_over_tcp() -> Result<(), Box<dyn std::error::Error>> {
+        let (_, handler) = harness("tcp", false)?;
+        assert!(matches!(
+            Listener::bind("tcp://0.0.0.0:0", false, None),
+            Err(ListenerError::RemoteAddress(_))
+        ));
+        assert!(matches!(
+            Listener::bind("tcp://127.0.0.1", false, None),
+            Err(ListenerError::InvalidAddress(_))
+        ));
+
+        let mut listener = Listener::bind("tcp://127.0.0.1:0", false, None)?;
+        let addr = match &listener.socket {
+            Socket::Tcp(socket) => socket.local_addr()?,
+            Socket::Unix(_) => unreachable!(),
+        };
+        let server = tokio::spawn(async move {
+            listener
+                .listen(Arc::new(handler), std::future::pending())
+                .await
+        });
+
+        let mut stream = TcpStream::connect(addr).await?;
+        stream
+            .write_all(b"{ \"jsonrpc\": \"2.0\", \"method\": \"v0.status\", \"params\": {}, \"id\": \"1\" }\n")
+            .await?;
+        let mut resp = String::new();
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            BufReader::new(&mut stream).read_line(&mut resp),
+        )
+        .await??;
+        assert_eq!(
+            serde_json::from_str::<Value>(&resp)?["result"]["version"],
+            VERSION
+        );
+
+        server.abort();
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn empty_timeline_is_well_formed() -> Result<(), Box<dyn std::error::Error>> {
+        let (_, handler) = harness("empty", false)?;
+        let filter = Filter::inline(
+            0,
+            InlineFilter {
+                name: None,
+                source: "return nil".to_owned(),
+            },
+        )?;
+
+        let timelines = [
+            // what the API returns when there are no tweets, e.g. for a since_id with nothing newer
+            r#"{ "meta": { "result_count": 0 } }"#,
+            // and tweets the filter drops all of
+            r#"{ "data": [{ "id": "1", "text": "hello" }], "meta": { "result_count": 1 } }"#,
+        ];
+        for timeline in timelines {
+            let fetched = CachedTimeline {
+                body: serde_json::from_str(timeline)?,
+                remaining: 10,
+                reset: 0,
+            };
+            let content = handler.home_timeline_content(
+                fetched,
+                std::slice::from_ref(&filter),
+                &FilterContext::default(),
+                true,
+                false,
+            );
+            let result = serde_json::to_value(&content)?["result"].clone();
+            assert_eq!(result["body"]["data"], json!([]));
+            assert_eq!(result["meta"]["api_calls_remaining"], 10);
+            assert_eq!(result["meta"]["next_token"], Value::Null);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn report_filter_in_dry_run() -> Result<(), Box<dyn std::error::Error>> {
+        let (_, handler) = harness("dry_run", false)?;
+        let filters = [
+            Filter::inline(
+                0,
+                InlineFilter {
+                    name: Some("no_ads".to_owned()),
+                    source: "if post.text:find('ad') then return nil end return post".to_owned(),
+                },
+            )?,
+            Filter::inline(
+                1,
+                InlineFilter {
+                    name: Some("shout".to_owned()),
+                    source: "post.text = post.text:upper() return post".to_owned(),
+                },
+            )?,
+        ];
+        let fetched = CachedTimeline {
+            body: serde_json::from_str(
+                r#"{ "data": [{ "id": "1", "text": "an ad" }, { "id": "2", "text": "hello" }], "meta": { "result_count": 2 } }"#,
+            )?,
+            remaining: 10,
+            reset: 0,
+        };
+
+        let content = handler.dry_run_content(fetched, &filters, &FilterContext::default(), false);
+        let result = serde_json::to_value(&content)?["result"].clone();
+        assert!(result.get("body").is_none());
+        assert_eq!(
+            result["dry_run"],
+            json!([
+                { "dropped_by": "no_ads", "tweet": { "id": "1", "text": "an ad" } },
+                { "dropped_by": null, "tweet": { "id": "2", "text": "hello" } },
+            ])
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn tell_which_filter_dropped_the_tweet() -> Result<(), Box<dyn std::error::Error>> {
+        let (_, handler) = harness("show", false)?;
+        let filters = [Filter::inline(
+            0,
+            InlineFilter {
+                name: Some("no_ads".to_owned()),
+                source: "if post.text:find('ad') then return nil end return post".to_owned(),
+            },
+        )?];
+        let show = |text: &str| -> Result<Value, Box<dyn std::error::Error>> {
+            let tweet = serde_json::from_value(json!({ "id": "1", "text": text }))?;
+            let meta = ResponsePlainMeta {
+                api_calls_remaining: 10,
+                api_calls_reset: 0,
+            };
+            let content =
+                handler.tweet_show_content(tweet, &filters, &FilterContext::default(), meta);
+            Ok(serde_json::to_value(&content)?["result"].clone())
+        };
+
+        let dropped = show("an ad")?;
+        assert_eq!(dropped["tweet"], Value::Null);
+        assert_eq!(dropped["dropped_by"], "no_ads");
+        let kept = show("hello")?;
+        assert_eq!(kept["tweet"]["text"], "hello");
+        assert_eq!(kept["dropped_by"], Value::Null);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn apply_the_filter_error_policy() -> Result<(), Box<dyn std::error::Error>> {
+        let (_, mut handler) = harness("error-policy", false)?;
+        let inline = |name: &str, source: &str| {
+            Filter::inline(
+                0,
+                InlineFilter {
+                    name: Some(name.to_owned()),
+                    source: source.to_owned(),
+                },
+            )
+        };
+        let filters = [
+            inline("broken", "error('boom')")?,
+            inline("shout", "post.text = post.text .. '!' return post")?,
+        ];
+        let mut show = |policy| -> Result<Value, Box<dyn std::error::Error>> {
+            handler.filter_error_policy = policy;
+            let tweet = serde_json::from_value(json!({ "id": "1", "text": "hello" }))?;
+            let meta = ResponsePlainMeta {
+                api_calls_remaining: 10,
+                api_calls_reset: 0,
+            };
+            let content =
+                handler.tweet_show_content(tweet, &filters, &FilterContext::default(), meta);
+            Ok(serde_json::to_value(&content)?["result"].clone())
+        };
+
+        // the tweet goes on to the next filter as if the broken one returned it unchanged
+        let skipped = show(FilterErrorPolicy::SkipFilter)?;
+        assert_eq!(skipped["tweet"]["text"], "hello!");
+        assert_eq!(skipped["dropped_by"], Value::Null);
+        let errors = skipped["filter_errors"].as_array().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0]["filter"], "broken");
+        assert_eq!(errors[0]["count"], 1);
+        assert!(errors[0]["error"].as_str().unwrap().contains("boom"));
+
+        let dropped = show(FilterErrorPolicy::DropTweet)?;
+        assert_eq!(dropped["tweet"], Value::Null);
+        assert_eq!(dropped["dropped_by"], "broken");
+        assert_eq!(dropped["filter_errors"], skipped["filter_errors"]);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn accepted_tweets_skip_later_filters() -> Result<(), Box<dyn std::error::Error>> {
+        let (_, handler) = harness("accept", false)?;
+        let filters = [
+            Filter::inline(
+                0,
+                InlineFilter {
+                    name: Some("friends".to_owned()),
+                    source: "if post.author_id == '42' then return { tweet = post, final = true } end return post".to_owned(),
+                },
+            )?,
+            Filter::inline(
+                1,
+                InlineFilter {
+                    name: Some("no_links".to_owned()),
+                    source: "if post.text:find('http') then return nil end return post".to_owned(),
+                },
+            )?,
+        ];
+        let fetched = CachedTimeline {
+            body: serde_json::from_str(
+                r#"{ "data": [{ "id": "1", "text": "http://a", "author_id": "42" }, { "id": "2", "text": "http://b", "author_id": "7" }], "meta": { "result_count": 2 } }"#,
+            )?,
+            remaining: 10,
+            reset: 0,
+        };
+
+        let content = handler.dry_run_content(fetched, &filters, &FilterContext::default(), false);
+        let result = serde_json::to_value(&content)?["result"].clone();
+        assert_eq!(result["dry_run"][0]["dropped_by"], Value::Null);
+        assert_eq!(result["dry_run"][1]["dropped_by"], "no_links");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replace_stale_socket() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir().join(format!("binchotan-{}-stale", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("binchotan.sock");
+        let _ = std::fs::remove_file(&path);
+
+        // the socket file outlives a listener which was not cleaned up
+        drop(net::UnixListener::bind(&path)?);
+        assert!(path.exists());
+        let listener = Listener::new(&path, None)?;
+
+        // but a live socket is not taken over
+        assert!(matches!(
+            Listener::new(&path, None),
+            Err(ListenerError::AlreadyRunning(p)) if p == path
+        ));
+
+        drop(listener);
+        assert!(!path.exists());
+
+        // nor is a file which is not a socket
+        std::fs::write(&path, "data")?;
+        assert!(matches!(
+            Listener::new(&path, None),
+            Err(ListenerError::NotSocket(p)) if p == path
+        ));
+        assert_eq!(std::fs::read_to_string(&path)?, "data");
+        std::fs::remove_file(&path)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,122 @@
+use mlua::prelude::*;
+use serde::Serialize;
+
+/// A blessed Lua helper module. Filters can load it with `require("bincho.text")` and so on.
+pub struct Snippet {
+    pub name: &'static str,
+    src: &'static str,
+}
+
+pub const SNIPPETS: [Snippet; 3] = [
+    Snippet {
+        name: "bincho.text",
+        src: include_str!("../resources/snippets/bincho/text.lua"),
+    },
+    Snippet {
+        name: "bincho.tweet",
+        src: include_str!("../resources/snippets/bincho/tweet.lua"),
+    },
+    Snippet {
+        name: "bincho.time",
+        src: include_str!("../resources/snippets/bincho/time.lua"),
+    },
+];
+
+const LOADED_KEY: &str = "bincho.loaded";
+
+#[derive(Debug, Serialize)]
+pub struct SnippetInfo {
+    pub name: String,
+    pub functions: Vec<String>,
+}
+
+/// Lists the helper modules along with the functions they export.
+pub fn list() -> LuaResult<Vec<SnippetInfo>> {
+    let lua = Lua::new();
+    SNIPPETS
+        .iter()
+        .map(|snippet| {
+            let module: LuaTable = lua.load(snippet.src).set_name(snippet.name)?.eval()?;
+            let mut functions = module
+                .pairs::<String, LuaValue>()
+                .filter_map(|pair| match pair {
+                    Ok((name, LuaValue::Function(_))) => Some(Ok(name)),
+                    Ok(_) => None,
+                    Err(err) => Some(Err(err)),
+                })
+                .collect::<LuaResult<Vec<_>>>()?;
+            functions.sort();
+            Ok(SnippetInfo {
+                name: snippet.name.to_owned(),
+                functions,
+            })
+        })
+        .collect()
+}
+
+/// Replaces `require` with one which only loads the blessed modules, and removes `package` so that filters cannot load arbitrary files.
+pub fn install_require(lua: &Lua) -> LuaResult<()> {
+    lua.set_named_registry_value(LOADED_KEY, lua.create_table()?)?;
+    let require = lua.create_function(|lua, name: String| {
+        let loaded: LuaTable = lua.named_registry_value(LOADED_KEY)?;
+        if let Some(module) = loaded.get::<_, Option<LuaValue>>(name.as_str())? {
+            return Ok(module);
+        }
+
+        let snippet = SNIPPETS.iter().find(|s| s.name == name).ok_or_else(|| {
+            LuaError::RuntimeError(format!(
+                "module `{}` is not available. only the blessed modules ({}) can be required",
+                name,
+                SNIPPETS.map(|s| s.name).join(", ")
+            ))
+        })?;
+        let module: LuaValue = lua.load(snippet.src).set_name(snippet.name)?.eval()?;
+        loaded.set(name, module.clone())?;
+        Ok(module)
+    })?;
+
+    lua.globals().set("require", require)?;
+    lua.globals().set("package", LuaValue::Nil)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn require_blessed_module() -> Result<(), Box<dyn std::error::Error>> {
+        let lua = Lua::new();
+        install_require(&lua)?;
+        let found: bool = lua
+            .load(r#"return require("bincho.text").contains_any("hello world", { "world" })"#)
+            .eval()?;
+        assert!(found);
+
+        let created_at: i64 = lua
+            .load(r#"return require("bincho.time").parse_created_at("2022-10-27T15:41:35.000Z")"#)
+            .eval()?;
+        assert_eq!(created_at, 1666885295);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reject_other_modules() -> Result<(), Box<dyn std::error::Error>> {
+        let lua = Lua::new();
+        install_require(&lua)?;
+        assert!(lua.load(r#"return require("os")"#).exec().is_err());
+        assert!(lua.load(r#"return package == nil"#).eval::<bool>()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn list_functions() -> Result<(), Box<dyn std::error::Error>> {
+        let snippets = list()?;
+        let tweet = snippets.iter().find(|s| s.name == "bincho.tweet").unwrap();
+        assert_eq!(tweet.functions, vec!["is_quote", "is_reply", "is_retweet"]);
+
+        Ok(())
+    }
+}
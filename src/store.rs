@@ -0,0 +1,380 @@
+use crate::{api::ReplySettings, clock, mute::Mute, tweet::Tweet};
+use futures_util::future::BoxFuture;
+use serde::Serialize;
+use sqlx::{Executor, PgPool};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum TweetStoreError {
+    #[error("database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("could not parse a stored tweet: {0}")]
+    Parse(serde_json::Error),
+}
+
+/// Keeps the tweets fetched for each account, so that the history can be browsed without calling the API. The methods return boxed futures so that the Handler can hold any implementation as a trait object.
+pub trait TweetStore: Send + Sync {
+    /// Saves the tweets fetched for the account. A tweet saved before is replaced with the fetched one. Tweets without an id are skipped.
+    fn upsert<'a>(
+        &'a self,
+        twitter_id: &'a str,
+        tweets: &'a [Tweet],
+    ) -> BoxFuture<'a, Result<(), TweetStoreError>>;
+
+    /// Returns up to `limit` tweets saved for the account, newest first. Only tweets older than `before_id` are returned if it is given.
+    fn history<'a>(
+        &'a self,
+        twitter_id: &'a str,
+        before_id: Option<&'a str>,
+        limit: usize,
+    ) -> BoxFuture<'a, Result<Vec<Tweet>, TweetStoreError>>;
+
+    /// Deletes the tweets of every account which were last fetched before `older_than` (in epoch sec). A tweet fetched again is kept even if it was first fetched long ago. Returns the number of deleted tweets.
+    fn prune(&self, older_than: i64) -> BoxFuture<'_, Result<u64, TweetStoreError>>;
+
+    /// Reclaims the space left by deleted tweets.
+    fn compact(&self) -> BoxFuture<'_, Result<(), TweetStoreError>>;
+}
+
+/// Stores the tweets in the `timeline_tweets` table of the Postgres database. This is the only backend for now, as sqlx is built without SQLite support.
+pub struct PgTweetStore {
+    conn: PgPool,
+}
+
+impl PgTweetStore {
+    pub fn new(conn: PgPool) -> Self {
+        Self { conn }
+    }
+
+    async fn upsert_tweets(
+        &self,
+        twitter_id: &str,
+        tweets: &[Tweet],
+    ) -> Result<(), TweetStoreError> {
+        let now = clock::now();
+
+        let mut tx = self.conn.begin().await?;
+        for tweet in tweets {
+            let tweet_id = match tweet.field("id").as_str() {
+                Some(id) => id,
+                None => continue,
+            };
+            let body = serde_json::to_value(tweet).map_err(TweetStoreError::Parse)?;
+            sqlx::query!(
+                r#"
+                insert into timeline_tweets (twitter_id, tweet_id, body, fetched_at)
+                    values ($1, $2, $3, $4)
+                    on conflict (twitter_id, tweet_id) do update set body = excluded.body, fetched_at = excluded.fetched_at
+                "#,
+                twitter_id,
+                tweet_id,
+                body,
+                now
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn saved_history(
+        &self,
+        twitter_id: &str,
+        before_id: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<Tweet>, TweetStoreError> {
+        // tweet ids grow over time, but they are strings of varying length
+        let rows = sqlx::query!(
+            r#"
+            select body from timeline_tweets
+                where twitter_id = $1 and ($2::text is null or (length(tweet_id), tweet_id) < (length($2), $2))
+                order by length(tweet_id) desc, tweet_id desc
+                limit $3
+            "#,
+            twitter_id,
+            before_id,
+            limit as i64
+        )
+        .fetch_all(&self.conn)
+        .await?;
+
+        rows.into_iter()
+            .map(|row| serde_json::from_value(row.body).map_err(TweetStoreError::Parse))
+            .collect()
+    }
+
+    async fn prune_before(&self, older_than: i64) -> Result<u64, TweetStoreError> {
+        let result = sqlx::query!(
+            "delete from timeline_tweets where fetched_at < $1",
+            older_than
+        )
+        .execute(&self.conn)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    async fn vacuum(&self) -> Result<(), TweetStoreError> {
+        // VACUUM cannot be prepared, so it is sent as a simple query
+        (&self.conn).execute("vacuum timeline_tweets").await?;
+
+        Ok(())
+    }
+}
+
+impl TweetStore for PgTweetStore {
+    fn upsert<'a>(
+        &'a self,
+        twitter_id: &'a str,
+        tweets: &'a [Tweet],
+    ) -> BoxFuture<'a, Result<(), TweetStoreError>> {
+        Box::pin(self.upsert_tweets(twitter_id, tweets))
+    }
+
+    fn history<'a>(
+        &'a self,
+        twitter_id: &'a str,
+        before_id: Option<&'a str>,
+        limit: usize,
+    ) -> BoxFuture<'a, Result<Vec<Tweet>, TweetStoreError>> {
+        Box::pin(self.saved_history(twitter_id, before_id, limit))
+    }
+
+    fn prune(&self, older_than: i64) -> BoxFuture<'_, Result<u64, TweetStoreError>> {
+        Box::pin(self.prune_before(older_than))
+    }
+
+    fn compact(&self) -> BoxFuture<'_, Result<(), TweetStoreError>> {
+        Box::pin(self.vacuum())
+    }
+}
+
+/// A tweet waiting in the `scheduled_tweets` table to be posted.
+#[derive(Debug, Clone, Serialize)]
+pub struct ScheduledTweet {
+    pub scheduled_id: i32,
+    pub text: String,
+    pub reply_to_tweet_id: Option<String>,
+    pub quote_tweet_id: Option<String>,
+    pub reply_settings: Option<ReplySettings>,
+    // Epoch sec to post the tweet at
+    pub scheduled_at: i64,
+    // Why posting the tweet failed. A failed tweet is not posted again.
+    pub error: Option<String>,
+}
+
+/// Keeps the tweets scheduled with v0.tweet.create until they are posted. Tweets of a removed account are removed along with it.
+pub struct ScheduledTweetStore {
+    conn: PgPool,
+}
+
+impl ScheduledTweetStore {
+    pub fn new(conn: PgPool) -> Self {
+        Self { conn }
+    }
+
+    /// Schedules the tweet for the account. Returns its id, or None if there is no such account.
+    pub async fn add(
+        &self,
+        session_key: &str,
+        tweet: &ScheduledTweet,
+    ) -> Result<Option<i32>, TweetStoreError> {
+        let rec = sqlx::query!(
+            r#"
+            insert into scheduled_tweets (account_id, text, reply_to_tweet_id, quote_tweet_id, reply_settings, scheduled_at)
+                select id, $2, $3, $4, $5, $6 from accounts where session_key = $1
+                returning id
+            "#,
+            session_key,
+            tweet.text,
+            tweet.reply_to_tweet_id,
+            tweet.quote_tweet_id,
+            tweet.reply_settings.map(reply_settings_column),
+            tweet.scheduled_at
+        )
+        .fetch_optional(&self.conn)
+        .await?;
+
+        Ok(rec.map(|rec| rec.id))
+    }
+
+    /// Returns the tweets scheduled by the account, the earliest first.
+    pub async fn list(&self, session_key: &str) -> Result<Vec<ScheduledTweet>, TweetStoreError> {
+        let rows = sqlx::query!(
+            r#"
+            select s.id, s.text, s.reply_to_tweet_id, s.quote_tweet_id, s.reply_settings, s.scheduled_at, s.error
+                from scheduled_tweets s join accounts a on a.id = s.account_id
+                where a.session_key = $1
+                order by s.scheduled_at, s.id
+            "#,
+            session_key
+        )
+        .fetch_all(&self.conn)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| ScheduledTweet {
+                scheduled_id: row.id,
+                text: row.text,
+                reply_to_tweet_id: row.reply_to_tweet_id,
+                quote_tweet_id: row.quote_tweet_id,
+                reply_settings: row.reply_settings.as_deref().and_then(parse_reply_settings),
+                scheduled_at: row.scheduled_at,
+                error: row.error,
+            })
+            .collect())
+    }
+
+    /// Removes the tweet scheduled by the account. Returns false if there is no such tweet.
+    pub async fn cancel(
+        &self,
+        session_key: &str,
+        scheduled_id: i32,
+    ) -> Result<bool, TweetStoreError> {
+        let result = sqlx::query!(
+            "delete from scheduled_tweets where id = $1 and account_id = (select id from accounts where session_key = $2)",
+            scheduled_id,
+            session_key
+        )
+        .execute(&self.conn)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Returns the tweets due by `now` (in epoch sec) which have not failed, along with the session keys of their accounts. The earliest come first.
+    pub async fn due(&self, now: i64) -> Result<Vec<(String, ScheduledTweet)>, TweetStoreError> {
+        let rows = sqlx::query!(
+            r#"
+            select a.session_key, s.id, s.text, s.reply_to_tweet_id, s.quote_tweet_id, s.reply_settings, s.scheduled_at
+                from scheduled_tweets s join accounts a on a.id = s.account_id
+                where s.error is null and s.scheduled_at <= $1
+                order by s.scheduled_at, s.id
+            "#,
+            now
+        )
+        .fetch_all(&self.conn)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .filter_map(|row| {
+                let tweet = ScheduledTweet {
+                    scheduled_id: row.id,
+                    text: row.text,
+                    reply_to_tweet_id: row.reply_to_tweet_id,
+                    quote_tweet_id: row.quote_tweet_id,
+                    reply_settings: row.reply_settings.as_deref().and_then(parse_reply_settings),
+                    scheduled_at: row.scheduled_at,
+                    error: None,
+                };
+                Some((row.session_key?, tweet))
+            })
+            .collect())
+    }
+
+    /// Removes the tweet once it is posted.
+    pub async fn posted(&self, scheduled_id: i32) -> Result<(), TweetStoreError> {
+        sqlx::query!("delete from scheduled_tweets where id = $1", scheduled_id)
+            .execute(&self.conn)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Keeps the tweet from being posted again, recording why it failed.
+    pub async fn failed(&self, scheduled_id: i32, error: &str) -> Result<(), TweetStoreError> {
+        sqlx::query!(
+            "update scheduled_tweets set error = $1 where id = $2",
+            error,
+            scheduled_id
+        )
+        .execute(&self.conn)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Returns the number of tweets waiting to be posted, of every account.
+    pub async fn pending_count(&self) -> Result<i64, TweetStoreError> {
+        let rec =
+            sqlx::query!("select count(*) as count from scheduled_tweets where error is null")
+                .fetch_one(&self.conn)
+                .await?;
+
+        Ok(rec.count.unwrap_or_default())
+    }
+}
+
+/// Keeps the keywords muted with v0.mutes.add in the `mutes` table, so that they survive a restart. Mutes from `mute_keywords` in the config are not stored here.
+pub struct MuteStore {
+    conn: PgPool,
+}
+
+impl MuteStore {
+    pub fn new(conn: PgPool) -> Self {
+        Self { conn }
+    }
+
+    /// Returns the mutes saved before, after deleting the ones expired by `now` (in epoch sec).
+    pub async fn load(&self, now: i64) -> Result<Vec<Mute>, TweetStoreError> {
+        sqlx::query!("delete from mutes where until <= $1", now)
+            .execute(&self.conn)
+            .await?;
+        let rows = sqlx::query!("select keyword, until from mutes order by keyword")
+            .fetch_all(&self.conn)
+            .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| Mute {
+                keyword: row.keyword,
+                until: row.until,
+            })
+            .collect())
+    }
+
+    /// Saves the mute. Saving the same keyword again updates its expiry.
+    pub async fn save(&self, mute: &Mute) -> Result<(), TweetStoreError> {
+        sqlx::query!(
+            "insert into mutes (keyword, until) values ($1, $2) on conflict (keyword) do update set until = excluded.until",
+            mute.keyword,
+            mute.until
+        )
+        .execute(&self.conn)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn remove(&self, keyword: &str) -> Result<(), TweetStoreError> {
+        sqlx::query!("delete from mutes where keyword = $1", keyword)
+            .execute(&self.conn)
+            .await?;
+
+        Ok(())
+    }
+}
+
+/// Merges the saved mutes over the ones in the config. A saved mute replaces the configured one with the same keyword.
+pub fn merge_mutes(configured: &[Mute], saved: Vec<Mute>) -> Vec<Mute> {
+    let mut mutes: Vec<Mute> = configured
+        .iter()
+        .filter(|m| saved.iter().all(|s| s.keyword != m.keyword))
+        .cloned()
+        .collect();
+    mutes.extend(saved);
+    mutes
+}
+
+fn reply_settings_column(settings: ReplySettings) -> String {
+    // SAFETY: ReplySettings serializes to a string
+    serde_json::json!(settings).as_str().unwrap().to_owned()
+}
+
+fn parse_reply_settings(column: &str) -> Option<ReplySettings> {
+    serde_json::from_value(serde_json::Value::String(column.to_owned())).ok()
+}
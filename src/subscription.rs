@@ -0,0 +1,164 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::Duration,
+};
+use tokio::time::Instant;
+
+use crate::filter::FilterContext;
+
+// Subscriptions may not poll the timeline more often than this, which keeps them well within its rate limit
+pub const MIN_POLL_INTERVAL: Duration = Duration::from_secs(15);
+// Most subscriptions a single connection can have at once, as each of them polls on its own
+pub const MAX_SUBSCRIPTIONS: usize = 10;
+// Number of tweet ids remembered by each subscription to push a tweet at most once
+const SEEN_CAPACITY: usize = 1000;
+// Heartbeat interval of connections with subscriptions when heartbeat_interval_secs is not set. Polls finding nothing new write nothing, so a client gone away would otherwise keep being polled for.
+pub const SUBSCRIPTION_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A home timeline polled for new tweets on behalf of a connection.
+pub struct Subscription {
+    pub session_key: String,
+    pub context: FilterContext,
+    // The newest tweet fetched so far. Only tweets newer than it are fetched by the next poll.
+    pub since_id: Option<String>,
+    interval: Duration,
+    next_poll: Instant,
+    seen: VecDeque<String>,
+    seen_ids: HashSet<String>,
+}
+
+impl Subscription {
+    /// Returns true if the tweet has not been seen before, remembering it.
+    pub fn first_seen(&mut self, tweet_id: &str) -> bool {
+        if !self.seen_ids.insert(tweet_id.to_owned()) {
+            return false;
+        }
+        self.seen.push_back(tweet_id.to_owned());
+        if self.seen.len() > SEEN_CAPACITY {
+            // SAFETY: the queue is longer than the capacity, so it is not empty
+            let oldest = self.seen.pop_front().unwrap();
+            self.seen_ids.remove(&oldest);
+        }
+        true
+    }
+
+    /// Schedules the next poll an interval from now.
+    pub fn polled(&mut self) {
+        self.next_poll = Instant::now() + self.interval;
+    }
+}
+
+/// The subscriptions made on a connection. They end when the connection is closed.
+#[derive(Default)]
+pub struct Subscriptions {
+    next_id: u64,
+    active: HashMap<String, Subscription>,
+}
+
+impl Subscriptions {
+    /// Starts a subscription, which is polled right away. Returns its id.
+    pub fn add(
+        &mut self,
+        session_key: String,
+        context: FilterContext,
+        since_id: Option<String>,
+        interval: Duration,
+    ) -> String {
+        self.next_id += 1;
+        let id = self.next_id.to_string();
+        let subscription = Subscription {
+            session_key,
+            context,
+            since_id,
+            interval,
+            next_poll: Instant::now(),
+            seen: VecDeque::new(),
+            seen_ids: HashSet::new(),
+        };
+        self.active.insert(id.clone(), subscription);
+        id
+    }
+
+    /// Ends the subscription. Returns false if there is no such subscription.
+    pub fn remove(&mut self, id: &str) -> bool {
+        self.active.remove(id).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.active.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.active.is_empty()
+    }
+
+    pub fn get_mut(&mut self, id: &str) -> Option<&mut Subscription> {
+        self.active.get_mut(id)
+    }
+
+    /// Waits until a subscription is due to be polled and returns its id. Never returns if there are no subscriptions.
+    pub async fn next_due(&self) -> String {
+        match self.active.iter().min_by_key(|(_, s)| s.next_poll) {
+            Some((id, subscription)) => {
+                tokio::time::sleep_until(subscription.next_poll).await;
+                id.clone()
+            }
+            None => std::future::pending().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn poll_the_earliest_subscription() {
+        let mut subscriptions = Subscriptions::default();
+        let first = subscriptions.add(
+            "a".to_owned(),
+            FilterContext::default(),
+            None,
+            Duration::from_millis(50),
+        );
+        let second = subscriptions.add(
+            "b".to_owned(),
+            FilterContext::default(),
+            None,
+            Duration::from_secs(60),
+        );
+
+        subscriptions.get_mut(&second).unwrap().polled();
+        assert_eq!(subscriptions.next_due().await, first);
+        subscriptions.get_mut(&first).unwrap().polled();
+        let start = Instant::now();
+        assert_eq!(subscriptions.next_due().await, first);
+        assert!(start.elapsed() >= Duration::from_millis(40));
+
+        assert!(subscriptions.remove(&first));
+        assert!(!subscriptions.remove(&first));
+        let due = tokio::time::timeout(Duration::from_millis(50), subscriptions.next_due()).await;
+        assert!(due.is_err());
+    }
+
+    #[test]
+    fn push_each_tweet_once() {
+        let mut subscriptions = Subscriptions::default();
+        let id = subscriptions.add(
+            "a".to_owned(),
+            FilterContext::default(),
+            None,
+            MIN_POLL_INTERVAL,
+        );
+        let subscription = subscriptions.get_mut(&id).unwrap();
+
+        assert!(subscription.first_seen("1"));
+        assert!(!subscription.first_seen("1"));
+        for i in 2..=SEEN_CAPACITY + 1 {
+            assert!(subscription.first_seen(&i.to_string()));
+        }
+        // only the oldest id has been forgotten
+        assert!(subscription.first_seen("1"));
+        assert!(!subscription.first_seen("3"));
+    }
+}
@@ -0,0 +1,117 @@
+use crate::api::HomeTimelineResponseBody;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// A timeline as the API returned it, along with the rate limit reported with it.
+#[derive(Debug, Clone)]
+pub struct CachedTimeline {
+    pub body: HomeTimelineResponseBody,
+    pub remaining: usize,
+    pub reset: usize,
+}
+
+/// Keeps the timelines fetched from the API for a short while, so that frontends polling the same account do not spend the rate limit on the same tweets. The timelines are kept before filtering. Caching is disabled if the TTL is zero.
+pub struct TimelineCache {
+    ttl: Duration,
+    // key -> when the timeline was fetched, and the timeline
+    entries: Mutex<HashMap<String, (Instant, CachedTimeline)>>,
+}
+
+impl TimelineCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.ttl.is_zero()
+    }
+
+    /// Builds the key of a request. The parameters are sorted, so their order does not matter.
+    pub fn key(
+        user_id: &str,
+        endpoint: &str,
+        params: &HashMap<String, serde_json::Value>,
+    ) -> String {
+        let params: BTreeMap<_, _> = params.iter().collect();
+        format!(
+            "{}\n{}\n{}",
+            user_id,
+            endpoint,
+            serde_json::to_string(&params).unwrap_or_default()
+        )
+    }
+
+    /// Returns the timeline cached for the key if it is fresh.
+    pub fn get(&self, key: &str) -> Option<CachedTimeline> {
+        if !self.is_enabled() {
+            return None;
+        }
+
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(key)
+            .filter(|(fetched_at, _)| fetched_at.elapsed() < self.ttl)
+            .map(|(_, timeline)| timeline.clone())
+    }
+
+    pub fn insert(&self, key: String, timeline: CachedTimeline) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        // drop the stale ones here so that the cache does not grow with keys never requested again
+        entries.retain(|_, (fetched_at, _)| fetched_at.elapsed() < self.ttl);
+        entries.insert(key, (Instant::now(), timeline));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timeline() -> CachedTimeline {
+        CachedTimeline {
+            body: serde_json::from_str(
+                r#"{ "data": [{ "id": "1", "text": "hello" }], "meta": {} }"#,
+            )
+            .unwrap(),
+            remaining: 10,
+            reset: 0,
+        }
+    }
+
+    #[test]
+    fn expire_after_ttl() {
+        let cache = TimelineCache::new(Duration::from_millis(50));
+        let params = HashMap::from([
+            ("max_results".to_owned(), serde_json::json!(10)),
+            ("exclude".to_owned(), serde_json::json!("replies")),
+        ]);
+        let key = TimelineCache::key("1", "timeline", &params);
+        assert!(cache.get(&key).is_none());
+
+        cache.insert(key.clone(), timeline());
+        assert_eq!(cache.get(&key).unwrap().body.data.len(), 1);
+        assert!(cache
+            .get(&TimelineCache::key("2", "timeline", &params))
+            .is_none());
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(cache.get(&key).is_none());
+    }
+
+    #[test]
+    fn disabled_with_zero_ttl() {
+        let cache = TimelineCache::new(Duration::ZERO);
+        let key = TimelineCache::key("1", "timeline", &HashMap::new());
+        cache.insert(key.clone(), timeline());
+        assert!(cache.get(&key).is_none());
+    }
+}
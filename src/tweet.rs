@@ -1,5 +1,205 @@
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug)]
+/// A tweet as the API returned it. Every field is kept, including ones requested with `tweet.fields` such as `created_at`, `lang` and `public_metrics`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(transparent)]
 pub struct Tweet(serde_json::Value);
+
+/// URL and mention entities of a tweet, which frontends need to render links and @-mentions. Other entities such as hashtags are left in the tweet as they are.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Entities {
+    #[serde(default)]
+    pub urls: Vec<UrlEntity>,
+    #[serde(default)]
+    pub mentions: Vec<MentionEntity>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UrlEntity {
+    pub start: usize,
+    pub end: usize,
+    // The t.co link as it appears in the text
+    pub url: String,
+    pub expanded_url: Option<String>,
+    pub display_url: Option<String>,
+    // Other fields of the entity (e.g. `unwound_url`, `images`) are kept as they are
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MentionEntity {
+    pub start: usize,
+    pub end: usize,
+    pub username: String,
+    pub id: Option<String>,
+    // Other fields of the entity are kept as they are
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Tweet {
+    /// Returns the value of the given field, or `Value::Null` if the tweet does not have it.
+    pub fn field(&self, key: &str) -> &serde_json::Value {
+        &self.0[key]
+    }
+
+    /// Sets the field, overwriting an existing one. Does nothing unless the tweet is a JSON object.
+    pub fn set_field(&mut self, key: &str, value: serde_json::Value) {
+        if let serde_json::Value::Object(map) = &mut self.0 {
+            map.insert(key.to_owned(), value);
+        }
+    }
+
+    /// Returns the annotations filters attached to the tweet (e.g. a spam score). Empty if there are none.
+    pub fn annotations(&self) -> serde_json::Map<String, serde_json::Value> {
+        match self.field("annotations") {
+            serde_json::Value::Object(map) => map.clone(),
+            _ => serde_json::Map::new(),
+        }
+    }
+
+    /// Sets the annotations. The field is removed if they are empty, so that tweets without annotations look as they were fetched.
+    pub fn set_annotations(&mut self, annotations: serde_json::Map<String, serde_json::Value>) {
+        if let serde_json::Value::Object(map) = &mut self.0 {
+            if annotations.is_empty() {
+                map.remove("annotations");
+            } else {
+                map.insert("annotations".to_owned(), annotations.into());
+            }
+        }
+    }
+
+    /// Splits an array of tweets (which a filter returns to expand a tweet into several) into the tweets. Anything else is a single tweet. Nulls and empty tables are no tweets, so `{}` (which Lua cannot tell from an empty array) yields none.
+    pub fn into_tweets(self) -> Vec<Tweet> {
+        let blank = |t: &serde_json::Value| match t {
+            serde_json::Value::Object(map) => map.is_empty(),
+            other => other.is_null(),
+        };
+        match self.0 {
+            serde_json::Value::Array(tweets) => tweets
+                .into_iter()
+                .filter(|t| !blank(t))
+                .map(Tweet)
+                .collect(),
+            other if blank(&other) => vec![],
+            other => vec![Tweet(other)],
+        }
+    }
+
+    /// Returns the URL and mention entities. They are empty if the tweet has none (or no `entities` at all). A malformed entity is left out, keeping the others.
+    pub fn entities(&self) -> Entities {
+        let entities = self.field("entities");
+        Entities {
+            urls: parse_each(&entities["urls"]),
+            mentions: parse_each(&entities["mentions"]),
+        }
+    }
+
+    /// Makes sure the tweet has `entities.urls` and `entities.mentions`, so that filters can read them without checking whether they exist.
+    pub fn fill_entities(&mut self) {
+        let mut entities = match self.field("entities") {
+            serde_json::Value::Object(map) => map.clone(),
+            _ => serde_json::Map::new(),
+        };
+        let Entities { urls, mentions } = self.entities();
+        // SAFETY: the entities have just been deserialized, so they can be serialized back
+        entities.insert("urls".to_owned(), serde_json::to_value(urls).unwrap());
+        entities.insert(
+            "mentions".to_owned(),
+            serde_json::to_value(mentions).unwrap(),
+        );
+        self.set_field("entities", entities.into());
+    }
+}
+
+// Deserializes each element of the array on its own, skipping the ones which do not parse
+fn parse_each<T: DeserializeOwned>(value: &serde_json::Value) -> Vec<T> {
+    match value {
+        serde_json::Value::Array(items) => items
+            .iter()
+            .filter_map(|item| T::deserialize(item).ok())
+            .collect(),
+        _ => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn fill_missing_entities() -> Result<(), Box<dyn std::error::Error>> {
+        let mut tweet: Tweet = serde_json::from_value(json!({ "id": "1", "text": "hello" }))?;
+        tweet.fill_entities();
+        assert_eq!(
+            tweet.field("entities"),
+            &json!({ "urls": [], "mentions": [] })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn keep_other_entities() -> Result<(), Box<dyn std::error::Error>> {
+        let mut tweet: Tweet = serde_json::from_value(json!({
+            "id": "1",
+            "text": "see https://t.co/abc #rust",
+            "entities": {
+                "urls": [{
+                    "start": 4,
+                    "end": 20,
+                    "url": "https://t.co/abc",
+                    "expanded_url": "https://example.com/post",
+                    "display_url": "example.com/post",
+                    "unwound_url": "https://example.com/post?ref=1"
+                }],
+                "hashtags": [{ "start": 21, "end": 26, "tag": "rust" }]
+            }
+        }))?;
+        tweet.fill_entities();
+
+        let entities = tweet.entities();
+        assert_eq!(
+            entities.urls[0].expanded_url.as_deref(),
+            Some("https://example.com/post")
+        );
+        assert!(entities.mentions.is_empty());
+        assert_eq!(
+            tweet.field("entities")["urls"][0]["unwound_url"],
+            "https://example.com/post?ref=1"
+        );
+        assert_eq!(tweet.field("entities")["hashtags"][0]["tag"], "rust");
+
+        Ok(())
+    }
+
+    #[test]
+    fn skip_malformed_entities() -> Result<(), Box<dyn std::error::Error>> {
+        let mut tweet: Tweet = serde_json::from_value(json!({
+            "id": "1",
+            "text": "@jack @ hi",
+            "entities": {
+                "mentions": [
+                    { "start": 0, "end": 5, "username": "jack", "id": "12", "verified": true },
+                    { "start": 6, "end": 7 }
+                ],
+                "urls": "not an array"
+            }
+        }))?;
+
+        let entities = tweet.entities();
+        assert_eq!(entities.mentions.len(), 1);
+        assert_eq!(entities.mentions[0].username, "jack");
+        assert!(entities.urls.is_empty());
+
+        tweet.fill_entities();
+        assert_eq!(
+            tweet.field("entities")["mentions"],
+            json!([{ "start": 0, "end": 5, "username": "jack", "id": "12", "verified": true }])
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,207 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::sync::watch;
+
+/// Keeps the users resolved by `users` lookups, so that ids looked up recently do not spend the rate limit again. The least recently used user is evicted once `capacity` is reached. Caching is disabled if the capacity or the TTL is zero.
+pub struct UserCache {
+    capacity: usize,
+    ttl: Duration,
+    // user id -> when the user was fetched, when it was last used, and the user
+    entries: Mutex<HashMap<String, (Instant, Instant, serde_json::Value)>>,
+    // user id -> closed when the lookup fetching the user finishes
+    in_flight: Mutex<HashMap<String, watch::Receiver<()>>>,
+}
+
+/// Ids a lookup is fetching. Lookups waiting for them are woken when it is dropped.
+pub struct Claim<'a> {
+    cache: &'a UserCache,
+    pub ids: Vec<String>,
+    _done: watch::Sender<()>,
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        let mut in_flight = self.cache.in_flight.lock().unwrap();
+        for id in &self.ids {
+            in_flight.remove(id);
+        }
+    }
+}
+
+impl UserCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0 && !self.ttl.is_zero()
+    }
+
+    /// Returns the users cached for the ids, and claims the rest for the caller to fetch. Ids another lookup is fetching are waited for rather than claimed, so that concurrent lookups do not request the same users twice.
+    pub async fn resolve(&self, ids: &[String]) -> (Vec<serde_json::Value>, Claim<'_>) {
+        let mut users = vec![];
+        let mut pending: Vec<&String> = ids.iter().collect();
+        loop {
+            let (waits, claimed) = {
+                let mut entries = self.entries.lock().unwrap();
+                let mut in_flight = self.in_flight.lock().unwrap();
+                let mut waits = vec![];
+                let mut claimed = vec![];
+                for id in pending.drain(..) {
+                    if let Some(user) = self.lookup(&mut entries, id) {
+                        users.push(user);
+                    } else if let Some(done) = in_flight.get(id) {
+                        waits.push((id, done.clone()));
+                    } else {
+                        claimed.push(id.clone());
+                    }
+                }
+
+                let (tx, rx) = watch::channel(());
+                if self.is_enabled() {
+                    for id in &claimed {
+                        in_flight.insert(id.clone(), rx.clone());
+                    }
+                }
+                (
+                    waits,
+                    Claim {
+                        cache: self,
+                        ids: claimed,
+                        _done: tx,
+                    },
+                )
+            };
+
+            if waits.is_empty() {
+                return (users, claimed);
+            }
+            // the claim is released before waiting, so that lookups never wait for each other in a cycle
+            let claimed_ids = claimed.ids.clone();
+            drop(claimed);
+            for (_, mut done) in waits.iter().cloned() {
+                // returns once the other lookup drops its claim
+                let _ = done.changed().await;
+            }
+            // look them up again. ones the other lookup could not resolve are claimed this time
+            pending = claimed_ids
+                .iter()
+                .filter_map(|id| ids.iter().find(|i| *i == id))
+                .chain(waits.into_iter().map(|(id, _)| id))
+                .collect();
+        }
+    }
+
+    // Returns the user if it is fresh, marking it as used
+    fn lookup(
+        &self,
+        entries: &mut HashMap<String, (Instant, Instant, serde_json::Value)>,
+        id: &str,
+    ) -> Option<serde_json::Value> {
+        if !self.is_enabled() {
+            return None;
+        }
+
+        let (fetched_at, used_at, user) = entries.get_mut(id)?;
+        if fetched_at.elapsed() >= self.ttl {
+            entries.remove(id);
+            return None;
+        }
+        *used_at = Instant::now();
+        Some(user.clone())
+    }
+
+    /// Saves the users returned by a lookup. Users without an id are ignored.
+    pub fn insert(&self, users: &[serde_json::Value]) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+        for user in users {
+            let Some(id) = user["id"].as_str() else {
+                continue;
+            };
+            if !entries.contains_key(id) && entries.len() >= self.capacity {
+                // SAFETY: the capacity is not zero, so the entries are not empty
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, (_, used_at, _))| *used_at)
+                    .map(|(id, _)| id.clone())
+                    .unwrap();
+                entries.remove(&oldest);
+            }
+            entries.insert(id.to_owned(), (now, now, user.clone()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn evict_least_recently_used() {
+        let cache = UserCache::new(2, Duration::from_secs(60));
+        cache.insert(&[json!({ "id": "1" }), json!({ "id": "2" })]);
+        // 1 is used more recently than 2
+        let (users, claim) = cache.resolve(&ids(&["1"])).await;
+        assert_eq!(users.len(), 1);
+        drop(claim);
+
+        cache.insert(&[json!({ "id": "3" })]);
+        let (users, claim) = cache.resolve(&ids(&["1", "2", "3"])).await;
+        assert_eq!(users.len(), 2);
+        assert_eq!(claim.ids, ids(&["2"]));
+    }
+
+    #[tokio::test]
+    async fn expire_after_ttl() {
+        let cache = UserCache::new(10, Duration::from_millis(50));
+        cache.insert(&[json!({ "id": "1" })]);
+        assert_eq!(cache.resolve(&ids(&["1"])).await.0.len(), 1);
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        let (users, claim) = cache.resolve(&ids(&["1"])).await;
+        assert!(users.is_empty());
+        assert_eq!(claim.ids, ids(&["1"]));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn coalesce_concurrent_lookups() {
+        let cache = std::sync::Arc::new(UserCache::new(10, Duration::from_secs(60)));
+        let (_, first) = cache.resolve(&ids(&["1", "2"])).await;
+        assert_eq!(first.ids, ids(&["1", "2"]));
+
+        let waiting = tokio::spawn({
+            let cache = cache.clone();
+            async move {
+                let (users, claim) = cache.resolve(&ids(&["1", "2", "3"])).await;
+                (users.len(), claim.ids.clone())
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+
+        // 2 could not be resolved, so the waiting lookup fetches it itself
+        cache.insert(&[json!({ "id": "1" })]);
+        drop(first);
+        let (found, claimed) = waiting.await.unwrap();
+        assert_eq!(found, 1);
+        assert_eq!(claimed, ids(&["3", "2"]));
+    }
+}